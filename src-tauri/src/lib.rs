@@ -1,4 +1,8 @@
-use crate::utils::system::PlayerControls;
+use crate::utils::song::{
+    DeviceSettings, LoudnessScanState, OutputDeviceState, PlaybackQueue, QueueState,
+    SleepTimerState,
+};
+use crate::utils::system::{PlayerControls, SleepInhibitState, SleepInhibitor};
 use std::sync::Mutex;
 use tauri::{AppHandle, Emitter, Manager, State};
 
@@ -39,7 +43,7 @@ fn get_start_args(state: State<StartArgs>) -> Vec<String> {
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
     // 1. Audio state initialization
-    let (_stream, audio_state) = utils::song::get_audio_state();
+    let audio_state = utils::song::get_audio_state();
 
     // 2. CRITICAL FIX: Safe Argument Handling
     // std::env::args() triggers readlink, which causes SIGABRT on some Android emulators
@@ -68,6 +72,20 @@ pub fn run() {
     builder
         .manage(StartArgs(Mutex::new(initial_args)))
         .manage(audio_state)
+        .manage(QueueState(Mutex::new(PlaybackQueue::default())))
+        .manage(LoudnessScanState(Mutex::new(None)))
+        .manage(SleepTimerState(Mutex::new(None)))
+        .manage(OutputDeviceState(Mutex::new(DeviceSettings::default())))
+        .manage(SleepInhibitState::new(SleepInhibitor::default()))
+        .register_asynchronous_uri_scheme_protocol(
+            utils::song::COVER_SCHEME,
+            |_ctx, request, responder| {
+                // Extracting and resizing takes a while, keep it off the webview thread.
+                std::thread::spawn(move || {
+                    responder.respond(utils::song::serve_cover(&request));
+                });
+            },
+        )
         .plugin(tauri_plugin_opener::init())
         .plugin(tauri_plugin_dialog::init())
         .plugin(tauri_plugin_fs::init())
@@ -81,6 +99,17 @@ pub fn run() {
 
             // Manage media controls state
             app.manage(PlayerControls(Mutex::new(None)));
+            utils::song::spawn_playback_supervisor(app.handle().clone());
+            utils::song::spawn_playback_clock(app.handle().clone());
+            utils::song::spawn_device_watcher(app.handle().clone());
+            utils::song::init_cover_cache(app.handle());
+            utils::system::load_sleep_inhibit_settings(app.handle());
+
+            if let Err(e) = utils::song::equalizer::apply_device_eq_profile(app.handle()) {
+                if cfg!(debug_assertions) {
+                    println!("[DEBUG] {}", e);
+                }
+            }
             Ok(())
         })
         .invoke_handler(tauri::generate_handler![
@@ -89,13 +118,51 @@ pub fn run() {
             utils::song::stop_song,
             utils::song::toggle_playback,
             utils::song::fetch_progress,
+            utils::song::fetch_device_error,
+            utils::song::set_progress_interval,
             utils::song::set_position,
+            utils::song::set_crossfade,
+            utils::song::set_declick_ramp,
+            utils::song::set_replay_gain,
+            utils::song::set_playback_rate,
+            utils::song::set_loop_region,
+            utils::song::clear_loop_region,
+            utils::song::set_sleep_timer,
+            utils::song::cancel_sleep_timer,
+            utils::song::fetch_sleep_timer,
+            utils::song::list_output_devices,
+            utils::song::set_output_device,
+            utils::song::set_resume_on_reconnect,
             utils::song::fetch_metadata,
+            utils::song::update_tags,
+            utils::song::update_tags_batch,
             utils::song::load_lyrics_from_lrc,
             utils::song::load_lyrics_from_song,
+            utils::song::fetch_queue,
+            utils::song::queue_add,
+            utils::song::queue_remove,
+            utils::song::queue_move,
+            utils::song::play_next,
+            utils::song::play_previous,
+            utils::song::set_playback_mode,
+            utils::song::scan_loudness,
+            utils::song::cancel_loudness_scan,
+            utils::song::fetch_eq,
+            utils::song::set_eq_enabled,
+            utils::song::set_eq_preamp,
+            utils::song::set_eq_bands,
+            utils::song::fetch_eq_presets,
+            utils::song::save_eq_preset,
+            utils::song::apply_eq_preset,
+            utils::song::delete_eq_preset,
+            utils::song::import_eq_profile,
+            utils::song::fetch_eq_device_profiles,
+            utils::song::set_eq_device_profile,
             utils::system::set_volume,
             utils::system::update_system_metadata,
             utils::system::update_system_status,
+            utils::system::fetch_sleep_inhibit,
+            utils::system::set_sleep_inhibit,
         ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
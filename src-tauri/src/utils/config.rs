@@ -0,0 +1,44 @@
+use crate::utils::song::player_error::PlayerError;
+use std::path::PathBuf;
+use tauri::{AppHandle, Manager};
+
+fn config_error(file: &str, error: impl std::fmt::Display) -> PlayerError {
+    PlayerError::ConfigFailed {
+        file: file.to_string(),
+        reason: error.to_string(),
+    }
+}
+
+fn config_path(app: &AppHandle, file: &str) -> Result<PathBuf, PlayerError> {
+    let dir = app
+        .path()
+        .app_config_dir()
+        .map_err(|e| config_error(file, e))?;
+    Ok(dir.join(file))
+}
+
+/// Read a JSON config file, or the default value when it does not exist yet.
+pub(crate) fn read_config<T: serde::de::DeserializeOwned + Default>(
+    app: &AppHandle,
+    file: &str,
+) -> Result<T, PlayerError> {
+    let path = config_path(app, file)?;
+    if !path.exists() {
+        return Ok(T::default());
+    }
+    let content = std::fs::read_to_string(&path).map_err(|e| config_error(file, e))?;
+    serde_json::from_str(&content).map_err(|e| config_error(file, e))
+}
+
+pub(crate) fn write_config<T: serde::Serialize + ?Sized>(
+    app: &AppHandle,
+    file: &str,
+    value: &T,
+) -> Result<(), PlayerError> {
+    let path = config_path(app, file)?;
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir).map_err(|e| config_error(file, e))?;
+    }
+    let content = serde_json::to_string_pretty(value).map_err(|e| config_error(file, e))?;
+    std::fs::write(&path, content).map_err(|e| config_error(file, e))
+}
@@ -0,0 +1,598 @@
+use crate::utils::song::gain_ramp::{GainRamp, RampControl};
+use crate::utils::song::player_error::PlayerError;
+use rodio::cpal::traits::{DeviceTrait, HostTrait};
+use rodio::mixer::{self, Mixer, MixerSource};
+use rodio::source::SeekError;
+use rodio::{
+    cpal, ChannelCount, OutputStream, OutputStreamBuilder, Sample, SampleRate, Sink, Source,
+};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+const DEFAULT_VOLUME: f32 = 0.8;
+/// Format of the null output, the most common one for music.
+const NULL_CHANNELS: ChannelCount = 2;
+const NULL_SAMPLE_RATE: SampleRate = 44100;
+/// The null output pulls the samples by chunks this long.
+const NULL_CHUNK: Duration = Duration::from_millis(10);
+/// Frames read from the shared source at once by the sink.
+const READ_FRAMES: usize = 256;
+/// How long a command waits for a ramp beyond its length, in case the output
+/// stopped pulling samples.
+const RAMP_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// Where the engine sends the samples.
+#[derive(Clone, Debug, PartialEq)]
+pub enum OutputBackend {
+    /// The default device of the system.
+    Default,
+    /// The output device with this name.
+    Named(String),
+    /// No device at all. The samples are consumed `speed` times faster than real time,
+    /// as fast as possible with `f32::INFINITY`.
+    Null { speed: f32 },
+}
+
+/// Consumes the samples on a thread of its own, at the pace of a device or faster,
+/// so that the playback goes on without a sound card.
+struct NullOutput {
+    mixer: Mixer,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl NullOutput {
+    fn open(speed: f32) -> Result<Self, PlayerError> {
+        let (mixer, source) = mixer::mixer(NULL_CHANNELS, NULL_SAMPLE_RATE);
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = std::thread::Builder::new()
+            .name("null-output".to_string())
+            .spawn({
+                let stop = stop.clone();
+                move || drain(source, speed, &stop)
+            })
+            .map_err(|e| PlayerError::AudioEngine {
+                reason: format!("Failed to start the null output: {}", e),
+            })?;
+        Ok(NullOutput {
+            mixer,
+            stop,
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for NullOutput {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Pull samples out of the mixer until `stop` is set, `speed` times faster than real time.
+fn drain(mut source: MixerSource, speed: f32, stop: &AtomicBool) {
+    let samples_per_second = NULL_SAMPLE_RATE as f64 * NULL_CHANNELS as f64;
+    let chunk = (NULL_CHUNK.as_secs_f64() * samples_per_second) as usize;
+    let start = Instant::now();
+    let mut pulled = 0u64;
+    while !stop.load(Ordering::Relaxed) {
+        source.by_ref().take(chunk).for_each(drop);
+        pulled += chunk as u64;
+        let due =
+            start + Duration::from_secs_f64(pulled as f64 / samples_per_second / speed as f64);
+        if let Some(wait) = due.checked_duration_since(Instant::now()) {
+            std::thread::sleep(wait);
+        }
+    }
+}
+
+/// The output owned by the audio thread.
+enum Output {
+    Device(OutputStream),
+    Null(NullOutput),
+}
+
+impl Output {
+    /// Open the output and tell the name of its device.
+    fn open(backend: &OutputBackend) -> Result<(Self, Option<String>), PlayerError> {
+        match backend {
+            OutputBackend::Default => {
+                let stream = OutputStreamBuilder::open_default_stream().map_err(|e| {
+                    PlayerError::NoOutputDevice {
+                        device: None,
+                        reason: e.to_string(),
+                    }
+                })?;
+                // The device picked by `open_default_stream`.
+                let name = cpal::default_host()
+                    .default_output_device()
+                    .and_then(|device| device.name().ok());
+                Ok((Output::Device(stream), name))
+            }
+            OutputBackend::Named(name) => {
+                let no_device = |reason: String| PlayerError::NoOutputDevice {
+                    device: Some(name.clone()),
+                    reason,
+                };
+                let device = cpal::default_host()
+                    .output_devices()
+                    .map_err(|e| no_device(e.to_string()))?
+                    .find(|device| device.name().ok().as_ref() == Some(name))
+                    .ok_or_else(|| no_device("No such device".to_string()))?;
+                let stream = OutputStreamBuilder::from_device(device)
+                    .and_then(|builder| builder.open_stream_or_fallback())
+                    .map_err(|e| no_device(e.to_string()))?;
+                Ok((Output::Device(stream), Some(name.clone())))
+            }
+            OutputBackend::Null { speed } => {
+                NullOutput::open(*speed).map(|null| (Output::Null(null), None))
+            }
+        }
+    }
+
+    fn mixer(&self) -> &Mixer {
+        match self {
+            Output::Device(stream) => stream.mixer(),
+            Output::Null(null) => &null.mixer,
+        }
+    }
+
+    fn info(&self, device: Option<String>) -> OutputInfo {
+        let (channels, sample_rate) = match self {
+            Output::Device(stream) => (
+                stream.config().channel_count(),
+                stream.config().sample_rate(),
+            ),
+            Output::Null(_) => (NULL_CHANNELS, NULL_SAMPLE_RATE),
+        };
+        OutputInfo {
+            channels,
+            sample_rate,
+            device,
+        }
+    }
+}
+
+/// What the sink is doing.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct EngineStatus {
+    pub paused: bool,
+    /// Everything appended was played (or nothing was).
+    pub empty: bool,
+    pub position: Duration,
+}
+
+/// Format of the output stream opened by the engine.
+#[derive(Clone, Debug)]
+pub struct OutputInfo {
+    pub channels: ChannelCount,
+    pub sample_rate: SampleRate,
+    /// Name of the device, `None` for the null output.
+    pub device: Option<String>,
+}
+
+/// The source being played, shared by the readers appended to the sinks,
+/// so that it can move to another output and go on from the same sample.
+#[derive(Clone, Default)]
+struct SharedSource(Arc<Mutex<Option<Box<dyn Source + Send>>>>);
+
+/// Plays the shared source in one sink, until it is detached from it.
+struct SourceReader {
+    shared: SharedSource,
+    detached: Arc<AtomicBool>,
+    channels: ChannelCount,
+    sample_rate: SampleRate,
+    buffer: Vec<Sample>,
+    next: usize,
+}
+
+impl SourceReader {
+    /// `None` if there is nothing to play.
+    fn new(shared: &SharedSource) -> Option<(Self, Arc<AtomicBool>)> {
+        let (channels, sample_rate) = {
+            let source = shared.0.lock().unwrap();
+            let source = source.as_ref()?;
+            (source.channels(), source.sample_rate())
+        };
+        let detached = Arc::new(AtomicBool::new(false));
+        let reader = SourceReader {
+            shared: shared.clone(),
+            detached: detached.clone(),
+            channels,
+            sample_rate,
+            buffer: Vec::with_capacity(READ_FRAMES * channels as usize),
+            next: 0,
+        };
+        Some((reader, detached))
+    }
+}
+
+impl Iterator for SourceReader {
+    type Item = Sample;
+
+    fn next(&mut self) -> Option<Sample> {
+        if self.next == self.buffer.len() {
+            if self.detached.load(Ordering::Relaxed) {
+                return None;
+            }
+            // Whole frames only, the next reader must start on the first channel.
+            self.buffer.clear();
+            self.next = 0;
+            let mut source = self.shared.0.lock().unwrap();
+            let source = source.as_mut()?;
+            self.buffer
+                .extend(source.take(READ_FRAMES * self.channels as usize));
+        }
+        let sample = self.buffer.get(self.next).copied();
+        self.next += 1;
+        sample
+    }
+}
+
+impl Source for SourceReader {
+    fn current_span_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> ChannelCount {
+        self.channels
+    }
+
+    fn sample_rate(&self) -> SampleRate {
+        self.sample_rate
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+
+    fn try_seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+        // The samples left in `buffer` are still played: the crossfade of
+        // the `GainRamp` starts right after them.
+        match self.shared.0.lock().unwrap().as_mut() {
+            Some(source) => source.try_seek(pos),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Requests handled by the audio thread. Each one carries where to send its reply.
+enum Message {
+    /// Replace whatever is playing with `source` and start it.
+    Play(Box<dyn Source + Send>, Sender<()>),
+    Stop(Sender<()>),
+    Pause(Sender<()>),
+    /// Resume if there is something to play.
+    Resume(Sender<()>),
+    /// Resume if there is something to play, pause otherwise. Replies whether paused.
+    Toggle(Sender<bool>),
+    SetVolume(f32, Sender<()>),
+    Volume(Sender<f32>),
+    Seek(Duration, Sender<Result<(), PlayerError>>),
+    Status(Sender<EngineStatus>),
+    /// Move the playback to another output, keeping the source.
+    Switch(OutputBackend, Sender<Result<OutputInfo, PlayerError>>),
+}
+
+/// Handle to the audio thread, which owns the output stream and the sink.
+/// Commands send it messages and wait for the reply, so nothing here is locked.
+pub struct AudioEngine {
+    messages: Sender<Message>,
+    ramp: Arc<RampControl>,
+}
+
+fn engine_stopped() -> PlayerError {
+    PlayerError::AudioEngine {
+        reason: "The audio thread stopped".to_string(),
+    }
+}
+
+impl AudioEngine {
+    /// Start the audio thread on the given output.
+    pub fn spawn(backend: OutputBackend) -> Result<(AudioEngine, OutputInfo), PlayerError> {
+        let (messages, inbox) = mpsc::channel();
+        let (ready_tx, ready_rx) = mpsc::channel();
+        let ramp = Arc::new(RampControl::default());
+        let player_ramp = ramp.clone();
+
+        std::thread::Builder::new()
+            .name("audio".to_string())
+            .spawn(move || {
+                // The stream is opened on this thread, it is not `Send` on every platform.
+                let (output, device) = match Output::open(&backend) {
+                    Ok(output) => output,
+                    Err(e) => {
+                        let _ = ready_tx.send(Err(e));
+                        return;
+                    }
+                };
+                let sink = Sink::connect_new(output.mixer());
+                sink.set_volume(DEFAULT_VOLUME);
+                let _ = ready_tx.send(Ok(output.info(device)));
+                Player {
+                    sink,
+                    output,
+                    shared: SharedSource::default(),
+                    detached: Arc::new(AtomicBool::new(false)),
+                    ramp: player_ramp,
+                }
+                .run(inbox);
+            })
+            .map_err(|e| PlayerError::AudioEngine {
+                reason: format!("Failed to start the audio thread: {}", e),
+            })?;
+
+        let info = ready_rx.recv().map_err(|_| engine_stopped())??;
+        Ok((AudioEngine { messages, ramp }, info))
+    }
+
+    /// Send a message built around a reply channel and wait for the answer.
+    fn request<T>(&self, message: impl FnOnce(Sender<T>) -> Message) -> Result<T, PlayerError> {
+        let (reply_tx, reply_rx) = mpsc::channel();
+        self.messages
+            .send(message(reply_tx))
+            .map_err(|_| engine_stopped())?;
+        reply_rx.recv().map_err(|_| engine_stopped())
+    }
+
+    pub fn play(&self, source: Box<dyn Source + Send>) -> Result<(), PlayerError> {
+        self.request(|reply| Message::Play(source, reply))
+    }
+
+    pub fn stop(&self) -> Result<(), PlayerError> {
+        self.request(Message::Stop)
+    }
+
+    pub fn pause(&self) -> Result<(), PlayerError> {
+        self.request(Message::Pause)
+    }
+
+    pub fn resume(&self) -> Result<(), PlayerError> {
+        self.request(Message::Resume)
+    }
+
+    /// Return whether the playback is now paused.
+    pub fn toggle(&self) -> Result<bool, PlayerError> {
+        self.request(Message::Toggle)
+    }
+
+    pub fn set_volume(&self, volume: f32) -> Result<(), PlayerError> {
+        self.request(|reply| Message::SetVolume(volume, reply))
+    }
+
+    pub fn volume(&self) -> Result<f32, PlayerError> {
+        self.request(Message::Volume)
+    }
+
+    pub fn seek(&self, position: Duration) -> Result<(), PlayerError> {
+        self.request(|reply| Message::Seek(position, reply))?
+    }
+
+    pub fn status(&self) -> Result<EngineStatus, PlayerError> {
+        self.request(Message::Status)
+    }
+
+    /// Carry on the playback on another output. On failure, the current one is kept.
+    pub fn switch(&self, backend: OutputBackend) -> Result<OutputInfo, PlayerError> {
+        self.request(|reply| Message::Switch(backend, reply))?
+    }
+
+    /// Set how long pausing, resuming and stopping fade, and seeking crossfades.
+    pub fn set_ramp_length(&self, length: Duration) {
+        self.ramp.set_length(length);
+    }
+}
+
+/// State of the audio thread.
+struct Player {
+    // Declared before `output`, so that it is dropped first.
+    sink: Sink,
+    output: Output,
+    shared: SharedSource,
+    /// Detaches the reader appended to `sink`.
+    detached: Arc<AtomicBool>,
+    ramp: Arc<RampControl>,
+}
+
+impl Player {
+    /// Append a reader of the shared source to the sink.
+    fn attach(&mut self) {
+        if let Some((reader, detached)) = SourceReader::new(&self.shared) {
+            self.sink.append(reader);
+            self.detached = detached;
+        }
+    }
+
+    /// Whether something is being heard.
+    fn playing(&self) -> bool {
+        !self.sink.is_paused() && !self.sink.empty()
+    }
+
+    /// Fade in or out, and wait until it is over.
+    fn ramp_to(&self, audible: bool) {
+        self.ramp.set_audible(audible);
+        self.wait_for_ramp();
+    }
+
+    fn wait_for_ramp(&self) {
+        if !self.ramp.wait(self.ramp.length() + RAMP_TIMEOUT) && cfg!(debug_assertions) {
+            println!("[DEBUG] Gave up waiting for the gain ramp");
+        }
+    }
+
+    fn pause(&self) {
+        if self.playing() {
+            self.ramp_to(false);
+        }
+        self.sink.pause();
+    }
+
+    /// Resume if there is something to play.
+    fn resume(&self) {
+        if self.sink.is_paused() && !self.sink.empty() {
+            self.sink.play();
+            self.ramp_to(true);
+        }
+    }
+
+    fn switch(&mut self, backend: OutputBackend) -> Result<OutputInfo, PlayerError> {
+        let (output, device) = Output::open(&backend)?;
+        let sink = Sink::connect_new(output.mixer());
+        sink.set_volume(self.sink.volume());
+        if self.sink.is_paused() {
+            sink.pause();
+        }
+
+        // The old sink stops reading before the new one starts.
+        self.detached.store(true, Ordering::Relaxed);
+        self.sink.stop();
+        let info = output.info(device);
+        self.sink = sink;
+        self.output = output;
+        self.attach();
+        Ok(info)
+    }
+
+    /// Handle the messages until every handle is dropped.
+    fn run(mut self, inbox: Receiver<Message>) {
+        for message in inbox {
+            match message {
+                Message::Play(source, reply) => {
+                    self.detached.store(true, Ordering::Relaxed);
+                    self.sink.stop();
+                    self.sink.clear();
+                    self.ramp.set_audible(true);
+                    let source: Box<dyn Source + Send> =
+                        Box::new(GainRamp::new(source, self.ramp.clone()));
+                    self.shared = SharedSource(Arc::new(Mutex::new(Some(source))));
+                    self.attach();
+                    self.sink.play();
+                    let _ = reply.send(());
+                }
+                Message::Stop(reply) => {
+                    if self.playing() {
+                        self.ramp_to(false);
+                    }
+                    self.detached.store(true, Ordering::Relaxed);
+                    self.sink.stop();
+                    self.sink.clear();
+                    self.shared = SharedSource::default();
+                    let _ = reply.send(());
+                }
+                Message::Pause(reply) => {
+                    self.pause();
+                    let _ = reply.send(());
+                }
+                Message::Resume(reply) => {
+                    self.resume();
+                    let _ = reply.send(());
+                }
+                Message::Toggle(reply) => {
+                    if self.sink.is_paused() && !self.sink.empty() {
+                        self.resume();
+                    } else {
+                        self.pause();
+                    }
+                    let _ = reply.send(self.sink.is_paused());
+                }
+                Message::SetVolume(volume, reply) => {
+                    self.sink.set_volume(volume);
+                    let _ = reply.send(());
+                }
+                Message::Volume(reply) => {
+                    let _ = reply.send(self.sink.volume());
+                }
+                Message::Seek(position, reply) => {
+                    let result =
+                        self.sink
+                            .try_seek(position)
+                            .map_err(|e| PlayerError::SeekUnsupported {
+                                position: position.as_secs_f64(),
+                                reason: e.to_string(),
+                            });
+                    // Paused, the ramp is silent and there is nothing to crossfade.
+                    if result.is_ok() && self.playing() {
+                        self.wait_for_ramp();
+                    }
+                    let _ = reply.send(result);
+                }
+                Message::Status(reply) => {
+                    let _ = reply.send(EngineStatus {
+                        paused: self.sink.is_paused(),
+                        empty: self.sink.empty(),
+                        position: self.sink.get_pos(),
+                    });
+                }
+                Message::Switch(backend, reply) => {
+                    let _ = reply.send(self.switch(backend));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AudioEngine, OutputBackend};
+    use rodio::source::{SineWave, Source};
+    use std::time::{Duration, Instant};
+
+    fn sine(seconds: u64) -> Box<dyn Source + Send> {
+        Box::new(SineWave::new(440.).take_duration(Duration::from_secs(seconds)))
+    }
+
+    #[test]
+    fn null_output_plays_faster_than_real_time() {
+        let (engine, info) = AudioEngine::spawn(OutputBackend::Null { speed: 20. }).unwrap();
+        assert_eq!(info.device, None);
+
+        engine.play(sine(2)).unwrap();
+        assert!(!engine.status().unwrap().empty);
+        let start = Instant::now();
+        while !engine.status().unwrap().empty {
+            assert!(start.elapsed() < Duration::from_secs(1));
+            std::thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    #[test]
+    fn null_output_pauses_and_seeks() {
+        let (engine, _) = AudioEngine::spawn(OutputBackend::Null { speed: 1. }).unwrap();
+        engine.play(sine(10)).unwrap();
+
+        assert!(engine.toggle().unwrap());
+        assert!(engine.status().unwrap().paused);
+        assert!(!engine.toggle().unwrap());
+
+        engine.seek(Duration::from_secs(5)).unwrap();
+        assert!(engine.status().unwrap().position >= Duration::from_secs(5));
+
+        engine.stop().unwrap();
+        assert!(engine.status().unwrap().empty);
+        // Nothing left to resume.
+        assert!(engine.toggle().unwrap());
+    }
+
+    #[test]
+    fn switching_outputs_keeps_the_source() {
+        let (engine, _) = AudioEngine::spawn(OutputBackend::Null { speed: 1. }).unwrap();
+        engine.play(sine(2)).unwrap();
+        assert!(engine.toggle().unwrap());
+
+        engine.switch(OutputBackend::Null { speed: 20. }).unwrap();
+        let status = engine.status().unwrap();
+        assert!(status.paused && !status.empty);
+
+        assert!(!engine.toggle().unwrap());
+        let start = Instant::now();
+        while !engine.status().unwrap().empty {
+            assert!(start.elapsed() < Duration::from_secs(1));
+            std::thread::sleep(Duration::from_millis(5));
+        }
+    }
+}
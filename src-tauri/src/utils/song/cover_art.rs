@@ -0,0 +1,375 @@
+use crate::utils::song::player_error::PlayerError;
+use audiotags::{MimeType, Tag};
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageFormat};
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tauri::http::{header, Request, Response, StatusCode, Uri};
+use tauri::{AppHandle, Manager};
+
+/// Covers are served as `cover://localhost/<key>?size=<pixels>`.
+pub const COVER_SCHEME: &str = "cover";
+const MIN_SIZE: u32 = 16;
+const MAX_SIZE: u32 = 2048;
+const JPEG_QUALITY: u8 = 90;
+/// Cached covers not used for this long are removed at startup.
+const CACHE_MAX_AGE: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+/// Files being written are left alone for this long, in case a request is writing them.
+const TEMP_MAX_AGE: Duration = Duration::from_secs(60 * 60);
+/// Extensions of the cached files and their content types.
+const FORMATS: [(&str, &str); 5] = [
+    ("jpg", "image/jpeg"),
+    ("png", "image/png"),
+    ("gif", "image/gif"),
+    ("bmp", "image/bmp"),
+    ("tiff", "image/tiff"),
+];
+
+/// Numbers the files being written, two requests may extract the same cover.
+static TEMP_FILES: AtomicUsize = AtomicUsize::new(0);
+
+lazy_static! {
+    /// The track behind every cover URL handed out.
+    static ref TRACKS: Mutex<CoverKeys> = Mutex::new(CoverKeys::default());
+}
+
+/// Where the covers are cached, set by `init_cover_cache`.
+static CACHE_DIR: OnceLock<PathBuf> = OnceLock::new();
+
+/// The keys handed out and their tracks. A track keeps only its latest key,
+/// so the map grows with the tracks, not with their changes.
+#[derive(Default)]
+struct CoverKeys {
+    tracks: HashMap<String, String>,
+    keys: HashMap<String, String>,
+}
+
+impl CoverKeys {
+    /// Record `key` for `path`. Return the key it replaces.
+    fn insert(&mut self, key: String, path: &str) -> Option<String> {
+        let replaced = self
+            .keys
+            .insert(path.to_string(), key.clone())
+            .filter(|old| *old != key);
+        if let Some(old) = &replaced {
+            self.tracks.remove(old);
+        }
+        self.tracks.insert(key, path.to_string());
+        replaced
+    }
+
+    fn path(&self, key: &str) -> Option<String> {
+        self.tracks.get(key).cloned()
+    }
+}
+
+/// FNV-1a of the path, size and modification time: a file whose tags change
+/// gets a new key, so the cover cached for the old one is never served for it.
+fn cover_key(path: &str) -> String {
+    let (length, modified) = fs::metadata(path)
+        .map(|metadata| {
+            let modified = metadata
+                .modified()
+                .ok()
+                .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                .map_or(0, |time| time.as_nanos());
+            (metadata.len(), modified)
+        })
+        .unwrap_or_default();
+    let bytes = path
+        .bytes()
+        .chain(length.to_le_bytes())
+        .chain(modified.to_le_bytes());
+    let hash = bytes.fold(0xcbf29ce484222325u64, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    });
+    format!("{:016x}", hash)
+}
+
+/// The URL of the embedded cover of `path`, full size; add `?size=` for a thumbnail.
+pub fn cover_url(path: &str) -> String {
+    let key = cover_key(path);
+    let replaced = TRACKS.lock().unwrap().insert(key.clone(), path);
+    // The file changed: its old covers are never asked for again.
+    if let (Some(old), Some(dir)) = (replaced, CACHE_DIR.get()) {
+        remove_cached(dir, &old);
+    }
+    // The webview serves custom schemes from http://<scheme>.localhost on Windows and Android.
+    if cfg!(any(target_os = "windows", target_os = "android")) {
+        format!("http://{}.localhost/{}", COVER_SCHEME, key)
+    } else {
+        format!("{}://localhost/{}", COVER_SCHEME, key)
+    }
+}
+
+/// Remove the cached covers of `key`, full size and thumbnails.
+fn remove_cached(dir: &Path, key: &str) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let name = entry.file_name();
+        let ours = name
+            .to_string_lossy()
+            .strip_prefix(key)
+            .is_some_and(|rest| rest.starts_with(['.', '-']));
+        if ours {
+            let _ = fs::remove_file(entry.path());
+        }
+    }
+}
+
+/// Remove the covers not used for `CACHE_MAX_AGE`, and the files left by interrupted writes.
+fn prune_cache(dir: &Path, now: SystemTime) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let max_age = if entry.file_name().to_string_lossy().ends_with(".tmp") {
+            TEMP_MAX_AGE
+        } else {
+            CACHE_MAX_AGE
+        };
+        let stale = entry
+            .metadata()
+            .and_then(|metadata| metadata.modified())
+            .is_ok_and(|modified| now.duration_since(modified).is_ok_and(|age| age > max_age));
+        if stale {
+            let _ = fs::remove_file(entry.path());
+        }
+    }
+}
+
+/// Remember where the covers are cached and prune the cache, off the startup thread.
+pub fn init_cover_cache(app: &AppHandle) {
+    let Ok(dir) = app.path().app_cache_dir() else {
+        return;
+    };
+    let dir = dir.join("covers");
+    let _ = CACHE_DIR.set(dir.clone());
+    std::thread::spawn(move || prune_cache(&dir, SystemTime::now()));
+}
+
+/// The key and size of a cover URL.
+fn parse_uri(uri: &Uri) -> Result<(String, Option<u32>), PlayerError> {
+    let key = uri.path().trim_start_matches('/').to_string();
+    let size = uri
+        .query()
+        .into_iter()
+        .flat_map(|query| query.split('&'))
+        .find_map(|pair| pair.strip_prefix("size="))
+        .map(|size| match size.parse() {
+            Ok(size) if (MIN_SIZE..=MAX_SIZE).contains(&size) => Ok(size),
+            _ => Err(PlayerError::invalid(format!(
+                "Invalid cover size: {}",
+                size
+            ))),
+        })
+        .transpose()?;
+    Ok((key, size))
+}
+
+fn extension(mime_type: MimeType) -> &'static str {
+    match mime_type {
+        MimeType::Jpeg => "jpg",
+        MimeType::Png => "png",
+        MimeType::Gif => "gif",
+        MimeType::Bmp => "bmp",
+        MimeType::Tiff => "tiff",
+    }
+}
+
+/// Shrink the cover to fit in `size` pixels. PNG stays PNG, the rest becomes JPEG;
+/// a PNG or JPEG small enough is kept as it is.
+fn resize(path: &str, data: Vec<u8>, size: u32) -> Result<(Vec<u8>, &'static str), PlayerError> {
+    let decode_error = |e: image::ImageError| PlayerError::DecodeFailed {
+        path: path.to_string(),
+        reason: format!("Invalid cover: {}", e),
+    };
+    let image = image::load_from_memory(&data).map_err(decode_error)?;
+    let format = image::guess_format(&data).ok();
+    let png = format == Some(ImageFormat::Png);
+    let jpeg = format == Some(ImageFormat::Jpeg);
+    if (png || jpeg) && image.width() <= size && image.height() <= size {
+        return Ok((data, if png { "png" } else { "jpg" }));
+    }
+
+    let image = image.resize(size, size, FilterType::Triangle);
+    let mut resized = Cursor::new(Vec::new());
+    let written = if png {
+        image.write_to(&mut resized, ImageFormat::Png)
+    } else {
+        let encoder = JpegEncoder::new_with_quality(&mut resized, JPEG_QUALITY);
+        DynamicImage::from(image.to_rgb8()).write_with_encoder(encoder)
+    };
+    written.map_err(decode_error)?;
+    Ok((resized.into_inner(), if png { "png" } else { "jpg" }))
+}
+
+/// The cover of `key` as a file of the cache, extracted and resized the first time
+/// it is asked for. `None` for a key never handed out or a track without a cover.
+pub fn cached_cover(key: &str, size: Option<u32>) -> Result<Option<PathBuf>, PlayerError> {
+    let Some(path) = TRACKS.lock().unwrap().path(key) else {
+        return Ok(None);
+    };
+    let dir = CACHE_DIR.get().ok_or_else(|| PlayerError::Io {
+        path: path.clone(),
+        reason: "The cover cache is not set up".to_string(),
+    })?;
+    let name = match size {
+        Some(size) => format!("{}-{}", key, size),
+        None => key.to_string(),
+    };
+    if let Some(cached) = FORMATS
+        .iter()
+        .map(|(extension, _)| dir.join(format!("{}.{}", name, extension)))
+        .find(|file| file.exists())
+    {
+        // Used again, so it is not pruned.
+        let _ = File::options()
+            .append(true)
+            .open(&cached)
+            .and_then(|file| file.set_modified(SystemTime::now()));
+        return Ok(Some(cached));
+    }
+
+    let Some((mime_type, data)) = Tag::new().read_from_path(&path).ok().and_then(|tag| {
+        tag.album_cover()
+            .map(|cover| (cover.mime_type, cover.data.to_vec()))
+    }) else {
+        return Ok(None);
+    };
+    let (data, extension) = match size {
+        Some(size) => resize(&path, data, size)?,
+        None => (data, extension(mime_type)),
+    };
+
+    // Written aside then renamed, so that a request at the same time never reads half a file.
+    let file = dir.join(format!("{}.{}", name, extension));
+    let temp = dir.join(format!(
+        ".{}.{}.tmp",
+        name,
+        TEMP_FILES.fetch_add(1, Ordering::Relaxed)
+    ));
+    let io_error = |e: std::io::Error| PlayerError::io(&file.to_string_lossy(), &e);
+    fs::create_dir_all(dir).map_err(io_error)?;
+    fs::write(&temp, data).map_err(io_error)?;
+    fs::rename(&temp, &file).map_err(io_error)?;
+    Ok(Some(file))
+}
+
+/// The file of a cover URL given by the frontend, for the system media controls.
+pub fn cover_file(url: &str) -> Option<PathBuf> {
+    let uri = url.parse::<Uri>().ok()?;
+    let (key, size) = parse_uri(&uri).ok()?;
+    cached_cover(&key, size).ok().flatten()
+}
+
+/// Answer a request of the `cover` scheme.
+pub fn serve_cover(request: &Request<Vec<u8>>) -> Response<Vec<u8>> {
+    let status = |status: StatusCode, reason: String| {
+        Response::builder()
+            .status(status)
+            .header(header::CONTENT_TYPE, "text/plain")
+            .body(reason.into_bytes())
+            .unwrap()
+    };
+    let result = parse_uri(request.uri()).and_then(|(key, size)| cached_cover(&key, size));
+    let file = match result {
+        Ok(Some(file)) => file,
+        Ok(None) => return status(StatusCode::NOT_FOUND, "No such cover".to_string()),
+        Err(e @ PlayerError::InvalidArgument { .. }) => {
+            return status(StatusCode::BAD_REQUEST, e.to_string())
+        }
+        Err(e) => return status(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+    };
+    let data = match fs::read(&file) {
+        Ok(data) => data,
+        Err(e) => return status(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+    };
+    let extension = file.extension().and_then(|ext| ext.to_str());
+    let content_type = FORMATS
+        .iter()
+        .find(|(known, _)| Some(*known) == extension)
+        .map_or("application/octet-stream", |(_, content_type)| content_type);
+
+    Response::builder()
+        .header(header::CONTENT_TYPE, content_type)
+        // The key changes with the file, so a cover never changes under its URL.
+        .header(header::CACHE_CONTROL, "public, max-age=31536000, immutable")
+        .body(data)
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{cover_key, parse_uri, prune_cache, remove_cached, CoverKeys, CACHE_MAX_AGE};
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn parses_urls_and_keys_follow_the_file() {
+        let uri = "cover://localhost/0123abcd?size=256".parse().unwrap();
+        assert_eq!(
+            parse_uri(&uri).unwrap(),
+            ("0123abcd".to_string(), Some(256))
+        );
+        let uri = "http://cover.localhost/0123abcd".parse().unwrap();
+        assert_eq!(parse_uri(&uri).unwrap(), ("0123abcd".to_string(), None));
+        let uri = "cover://localhost/0123abcd?size=100000".parse().unwrap();
+        assert!(parse_uri(&uri).is_err());
+
+        let path = std::env::temp_dir().join("youngl_cover_key.mp3");
+        std::fs::write(&path, b"one").unwrap();
+        let before = cover_key(path.to_str().unwrap());
+        std::fs::write(&path, b"three").unwrap();
+        let after = cover_key(path.to_str().unwrap());
+        let _ = std::fs::remove_file(&path);
+        assert_ne!(before, after);
+    }
+
+    #[test]
+    fn a_track_keeps_one_key() {
+        let mut keys = CoverKeys::default();
+        assert_eq!(keys.insert("1".to_string(), "a.mp3"), None);
+        assert_eq!(keys.insert("1".to_string(), "a.mp3"), None);
+        assert_eq!(keys.insert("2".to_string(), "b.mp3"), None);
+        assert_eq!(keys.insert("3".to_string(), "a.mp3"), Some("1".to_string()));
+        assert_eq!(keys.path("1"), None);
+        assert_eq!(keys.path("3").as_deref(), Some("a.mp3"));
+        assert_eq!(keys.tracks.len(), 2);
+    }
+
+    #[test]
+    fn stale_covers_are_removed() {
+        let dir = std::env::temp_dir().join("youngl_cover_cache");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let names = ["0a.jpg", "0a-256.png", "0ab.jpg", "1b.jpg", ".1b.0.tmp"];
+        for name in names {
+            std::fs::write(dir.join(name), b"").unwrap();
+        }
+        let left = || {
+            let mut names: Vec<String> = std::fs::read_dir(&dir)
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+                .collect();
+            names.sort();
+            names
+        };
+
+        remove_cached(&dir, "0a");
+        assert_eq!(left(), [".1b.0.tmp", "0ab.jpg", "1b.jpg"]);
+        prune_cache(&dir, SystemTime::now() + Duration::from_secs(2 * 60 * 60));
+        assert_eq!(left(), ["0ab.jpg", "1b.jpg"]);
+        prune_cache(&dir, SystemTime::now() + CACHE_MAX_AGE * 2);
+        assert!(left().is_empty());
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
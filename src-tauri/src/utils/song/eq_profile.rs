@@ -0,0 +1,190 @@
+use crate::utils::song::equalizer::{FilterKind, ParametricFilter};
+
+/// Q used by Equalizer APO when a shelf or pass filter does not give one.
+const DEFAULT_Q: f32 = 0.71;
+
+/// A headphone correction read from an Equalizer APO / AutoEq `ParametricEQ.txt`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ParametricProfile {
+    /// Gain applied before the filters (dB), usually negative to leave room for the boosts.
+    pub preamp: f32,
+    pub filters: Vec<ParametricFilter>,
+}
+
+/// Parse a profile in the Equalizer APO format:
+///
+/// ```text
+/// Preamp: -6.2 dB
+/// Filter 1: ON PK Fc 105 Hz Gain 3.4 dB Q 0.70
+/// ```
+///
+/// Filters turned `OFF` are skipped. The errors name the offending line.
+pub fn parse_parametric_eq(content: &str) -> Result<ParametricProfile, String> {
+    let mut profile = ParametricProfile::default();
+    for (index, line) in content.lines().enumerate() {
+        let line = line.trim().trim_start_matches('\u{feff}');
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let error = |reason: String| format!("Line {}: {} (`{}`)", index + 1, reason, line);
+        let Some((command, rest)) = line.split_once(':') else {
+            return Err(error("expected `Preamp:` or `Filter:`".to_string()));
+        };
+        let command = command.trim();
+
+        if command.eq_ignore_ascii_case("Preamp") {
+            profile.preamp = parse_value(rest.split_whitespace(), "dB").map_err(error)?;
+        } else if command
+            .get(..6)
+            .is_some_and(|word| word.eq_ignore_ascii_case("Filter"))
+        {
+            if let Some(filter) = parse_filter(rest).map_err(error)? {
+                profile.filters.push(filter);
+            }
+        } else {
+            return Err(error(format!("unsupported command `{}`", command)));
+        }
+    }
+    Ok(profile)
+}
+
+/// A number optionally followed by its unit.
+fn parse_value<'a>(mut tokens: impl Iterator<Item = &'a str>, unit: &str) -> Result<f32, String> {
+    let value = tokens.next().ok_or_else(|| "missing value".to_string())?;
+    let value = value.strip_suffix(unit).unwrap_or(value);
+    value
+        .parse()
+        .map_err(|_| format!("invalid number `{}`", value))
+}
+
+/// `ON PK Fc 105 Hz Gain 3.4 dB Q 0.70`. `None` when the filter is off.
+fn parse_filter(rest: &str) -> Result<Option<ParametricFilter>, String> {
+    let mut tokens = rest.split_whitespace();
+    match tokens.next() {
+        Some(state) if state.eq_ignore_ascii_case("ON") => {}
+        Some(state) if state.eq_ignore_ascii_case("OFF") => return Ok(None),
+        Some(state) => return Err(format!("expected ON or OFF, found `{}`", state)),
+        None => return Err("empty filter".to_string()),
+    }
+
+    let kind = match tokens.next().map(|kind| kind.to_ascii_uppercase()) {
+        Some(kind) => match kind.as_str() {
+            "PK" | "PEQ" => FilterKind::Peaking,
+            "LS" | "LSC" => FilterKind::LowShelf,
+            "HS" | "HSC" => FilterKind::HighShelf,
+            "LP" | "LPQ" => FilterKind::LowPass,
+            "HP" | "HPQ" => FilterKind::HighPass,
+            _ => return Err(format!("unsupported filter type `{}`", kind)),
+        },
+        None => return Err("missing filter type".to_string()),
+    };
+
+    let (mut frequency, mut gain, mut q) = (None, 0., DEFAULT_Q);
+    while let Some(key) = tokens.next() {
+        match key.to_ascii_uppercase().as_str() {
+            "FC" => frequency = Some(parse_value(&mut tokens, "Hz")?),
+            "GAIN" => gain = parse_value(&mut tokens, "dB")?,
+            "Q" => q = parse_value(&mut tokens, "")?,
+            // Units written apart from their value.
+            "HZ" | "DB" => {}
+            _ => return Err(format!("unsupported parameter `{}`", key)),
+        }
+    }
+
+    let frequency = frequency.ok_or_else(|| "missing `Fc`".to_string())?;
+    if frequency <= 0. || q <= 0. {
+        return Err("frequency and Q must be positive".to_string());
+    }
+    Ok(Some(ParametricFilter {
+        kind,
+        frequency,
+        gain,
+        q,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_parametric_eq, DEFAULT_Q};
+    use crate::utils::song::equalizer::{FilterKind, ParametricFilter};
+
+    #[test]
+    fn autoeq_profile() {
+        let content = "\u{feff}Preamp: -6.2 dB\n\
+            # Headphone correction\n\
+            Filter 1: ON LSC Fc 105 Hz Gain 5.5 dB Q 0.70\n\
+            Filter 2: ON PK Fc 2100Hz Gain -3.4dB Q 1.41\n\
+            \n\
+            Filter 3: OFF PK Fc 4000 Hz Gain 2.0 dB Q 2.00\n\
+            Filter 4: ON HP Fc 20 Hz\n";
+        let profile = parse_parametric_eq(content).unwrap();
+
+        assert_eq!(profile.preamp, -6.2);
+        assert_eq!(
+            profile.filters,
+            [
+                ParametricFilter {
+                    kind: FilterKind::LowShelf,
+                    frequency: 105.,
+                    gain: 5.5,
+                    q: 0.7,
+                },
+                ParametricFilter {
+                    kind: FilterKind::Peaking,
+                    frequency: 2100.,
+                    gain: -3.4,
+                    q: 1.41,
+                },
+                ParametricFilter {
+                    kind: FilterKind::HighPass,
+                    frequency: 20.,
+                    gain: 0.,
+                    q: DEFAULT_Q,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn unknown_filter_types() {
+        let error = parse_parametric_eq("Filter 1: ON BP Fc 1000 Hz Q 1").unwrap_err();
+        assert!(error.contains("unsupported filter type `BP`"), "{}", error);
+        let error = parse_parametric_eq("Filter 1: MAYBE PK Fc 1000 Hz").unwrap_err();
+        assert!(error.contains("expected ON or OFF"), "{}", error);
+        let error = parse_parametric_eq("Channel: L").unwrap_err();
+        assert!(error.contains("unsupported command `Channel`"), "{}", error);
+    }
+
+    #[test]
+    fn missing_and_extra_fields() {
+        for (line, reason) in [
+            ("Filter 1: ON PK Gain 3 dB Q 1", "missing `Fc`"),
+            ("Filter 1: ON PK Fc", "missing value"),
+            ("Filter 1: ON", "missing filter type"),
+            ("Filter 1:", "empty filter"),
+            (
+                "Filter 1: ON PK Fc 100 Hz Gain loud",
+                "invalid number `loud`",
+            ),
+            (
+                "Filter 1: ON PK Fc 100 Hz Width 2",
+                "unsupported parameter `Width`",
+            ),
+            ("Filter 1: ON PK Fc -100 Hz", "must be positive"),
+            ("Preamp -3 dB", "expected `Preamp:` or `Filter:`"),
+        ] {
+            let error = parse_parametric_eq(line).unwrap_err();
+            assert!(error.contains(reason), "{}: {}", line, error);
+        }
+    }
+
+    #[test]
+    fn errors_name_the_line() {
+        let content = "Preamp: -3 dB\n# Comment\n\nFilter 1: ON PK Fc 100 Hz Gain 2 dB Q 1\nFilter 2: ON PK Fc x Hz";
+        assert_eq!(
+            parse_parametric_eq(content).unwrap_err(),
+            "Line 5: invalid number `x` (`Filter 2: ON PK Fc x Hz`)"
+        );
+    }
+}
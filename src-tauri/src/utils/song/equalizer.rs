@@ -0,0 +1,568 @@
+use crate::utils::config::{read_config, write_config};
+use crate::utils::song::eq_profile;
+use crate::utils::song::player_error::PlayerError;
+use crate::utils::song::song_player::AudioState;
+use rodio::source::SeekError;
+use rodio::{ChannelCount, Sample, SampleRate, Source};
+use std::collections::HashMap;
+use std::f64::consts::PI;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tauri::{AppHandle, Manager, State};
+
+/// Center frequencies of the graphic bands, one octave apart.
+pub const EQ_FREQUENCIES: [f32; 10] = [
+    31., 62., 125., 250., 500., 1000., 2000., 4000., 8000., 16000.,
+];
+/// Q of the graphic bands, about one octave wide.
+const GRAPHIC_Q: f32 = 1.41;
+/// Largest boost or cut allowed (dB).
+const MAX_GAIN: f32 = 24.;
+/// How many frames are played between two checks for new settings.
+const UPDATE_FRAMES: usize = 256;
+
+const PRESETS_FILE: &str = "eq_presets.json";
+/// Maps output device names to the preset applied when playing on them.
+const DEVICE_PROFILES_FILE: &str = "eq_devices.json";
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FilterKind {
+    Peaking,
+    LowShelf,
+    HighShelf,
+    LowPass,
+    HighPass,
+}
+
+/// A biquad filter. `gain` (dB) is ignored by the pass filters.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ParametricFilter {
+    pub kind: FilterKind,
+    pub frequency: f32,
+    #[serde(default)]
+    pub gain: f32,
+    pub q: f32,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct EqSettings {
+    pub enabled: bool,
+    /// Gain applied before the bands (dB).
+    pub preamp: f32,
+    /// Gains of the graphic bands (dB), see `EQ_FREQUENCIES`.
+    pub bands: [f32; 10],
+    /// Applied after the graphic bands.
+    pub filters: Vec<ParametricFilter>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct EqPreset {
+    pub name: String,
+    #[serde(default)]
+    pub preamp: f32,
+    pub bands: [f32; 10],
+    pub filters: Vec<ParametricFilter>,
+}
+
+/// Settings shared between the commands and the audio thread.
+/// The version tells the audio thread that the settings changed.
+#[derive(Default)]
+pub struct EqControl {
+    settings: Mutex<EqSettings>,
+    version: AtomicU64,
+}
+
+impl EqControl {
+    pub fn settings(&self) -> EqSettings {
+        self.settings.lock().unwrap().clone()
+    }
+
+    pub fn update(&self, change: impl FnOnce(&mut EqSettings)) {
+        change(&mut self.settings.lock().unwrap());
+        self.version.fetch_add(1, Ordering::Release);
+    }
+}
+
+/// Normalized biquad coefficients (RBJ audio EQ cookbook).
+#[derive(Clone, Copy)]
+struct Coefficients {
+    b: [f32; 3],
+    a: [f32; 2],
+}
+
+impl Coefficients {
+    fn new(filter: &ParametricFilter, sample_rate: SampleRate) -> Self {
+        let rate = sample_rate as f64;
+        let frequency = (filter.frequency as f64).clamp(1., rate * 0.49);
+        let q = (filter.q as f64).max(0.01);
+        let w0 = 2. * PI * frequency / rate;
+        let (sin, cos) = w0.sin_cos();
+        let alpha = sin / (2. * q);
+        let a = 10f64.powf(filter.gain as f64 / 40.);
+
+        let (b, a) = match filter.kind {
+            FilterKind::Peaking => (
+                [1. + alpha * a, -2. * cos, 1. - alpha * a],
+                [1. + alpha / a, -2. * cos, 1. - alpha / a],
+            ),
+            FilterKind::LowShelf => {
+                let k = 2. * a.sqrt() * alpha;
+                (
+                    [
+                        a * ((a + 1.) - (a - 1.) * cos + k),
+                        2. * a * ((a - 1.) - (a + 1.) * cos),
+                        a * ((a + 1.) - (a - 1.) * cos - k),
+                    ],
+                    [
+                        (a + 1.) + (a - 1.) * cos + k,
+                        -2. * ((a - 1.) + (a + 1.) * cos),
+                        (a + 1.) + (a - 1.) * cos - k,
+                    ],
+                )
+            }
+            FilterKind::HighShelf => {
+                let k = 2. * a.sqrt() * alpha;
+                (
+                    [
+                        a * ((a + 1.) + (a - 1.) * cos + k),
+                        -2. * a * ((a - 1.) + (a + 1.) * cos),
+                        a * ((a + 1.) + (a - 1.) * cos - k),
+                    ],
+                    [
+                        (a + 1.) - (a - 1.) * cos + k,
+                        2. * ((a - 1.) - (a + 1.) * cos),
+                        (a + 1.) - (a - 1.) * cos - k,
+                    ],
+                )
+            }
+            FilterKind::LowPass => (
+                [(1. - cos) / 2., 1. - cos, (1. - cos) / 2.],
+                [1. + alpha, -2. * cos, 1. - alpha],
+            ),
+            FilterKind::HighPass => (
+                [(1. + cos) / 2., -(1. + cos), (1. + cos) / 2.],
+                [1. + alpha, -2. * cos, 1. - alpha],
+            ),
+        };
+
+        Coefficients {
+            b: [
+                (b[0] / a[0]) as f32,
+                (b[1] / a[0]) as f32,
+                (b[2] / a[0]) as f32,
+            ],
+            a: [(a[1] / a[0]) as f32, (a[2] / a[0]) as f32],
+        }
+    }
+}
+
+/// A source applying the equalizer to its input. New settings are picked up
+/// while playing, keeping the filter states so that there is no click.
+pub struct Equalizer<S> {
+    input: S,
+    control: Arc<EqControl>,
+    version: Option<u64>,
+    enabled: bool,
+    /// Linear gain applied before the filters.
+    preamp: f32,
+    stages: Vec<Coefficients>,
+    /// Filter memory, `stages.len()` per channel.
+    memory: Vec<[f32; 2]>,
+    channel: usize,
+    countdown: usize,
+}
+
+impl<S: Source> Equalizer<S> {
+    pub fn new(input: S, control: Arc<EqControl>) -> Self {
+        Equalizer {
+            input,
+            control,
+            version: None,
+            enabled: false,
+            preamp: 1.,
+            stages: Vec::new(),
+            memory: Vec::new(),
+            channel: 0,
+            countdown: 0,
+        }
+    }
+
+    fn update(&mut self) {
+        let version = self.control.version.load(Ordering::Acquire);
+        if self.version == Some(version) {
+            return;
+        }
+        // Never wait for the commands on the audio thread.
+        let Ok(settings) = self.control.settings.try_lock() else {
+            return;
+        };
+
+        let sample_rate = self.input.sample_rate();
+        self.enabled = settings.enabled;
+        self.preamp = 10f32.powf(settings.preamp / 20.);
+        self.stages = EQ_FREQUENCIES
+            .iter()
+            .zip(settings.bands.iter())
+            .map(|(&frequency, &gain)| ParametricFilter {
+                kind: FilterKind::Peaking,
+                frequency,
+                gain,
+                q: GRAPHIC_Q,
+            })
+            .chain(settings.filters.iter().copied())
+            .map(|filter| Coefficients::new(&filter, sample_rate))
+            .collect();
+        let size = self.stages.len() * self.input.channels() as usize;
+        if self.memory.len() != size {
+            self.memory = vec![[0.; 2]; size];
+        }
+        self.version = Some(version);
+    }
+}
+
+impl<S: Source> Iterator for Equalizer<S> {
+    type Item = Sample;
+
+    fn next(&mut self) -> Option<Sample> {
+        if self.countdown == 0 {
+            self.update();
+            self.countdown = UPDATE_FRAMES * self.input.channels() as usize;
+        }
+        self.countdown -= 1;
+
+        let sample = self.input.next()?;
+        let channel = self.channel;
+        self.channel = (self.channel + 1) % self.input.channels() as usize;
+        if !self.enabled {
+            return Some(sample);
+        }
+
+        let offset = channel * self.stages.len();
+        let memory = &mut self.memory[offset..offset + self.stages.len()];
+        let mut x = sample * self.preamp;
+        for (stage, z) in self.stages.iter().zip(memory.iter_mut()) {
+            let y = stage.b[0] * x + z[0];
+            z[0] = stage.b[1] * x - stage.a[0] * y + z[1];
+            z[1] = stage.b[2] * x - stage.a[1] * y;
+            x = y;
+        }
+        Some(x)
+    }
+}
+
+impl<S: Source> Source for Equalizer<S> {
+    fn current_span_len(&self) -> Option<usize> {
+        self.input.current_span_len()
+    }
+
+    fn channels(&self) -> ChannelCount {
+        self.input.channels()
+    }
+
+    fn sample_rate(&self) -> SampleRate {
+        self.input.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.input.total_duration()
+    }
+
+    fn try_seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+        self.input.try_seek(pos)
+    }
+}
+
+fn validate_gain(gain: f32) -> Result<(), PlayerError> {
+    if !(-MAX_GAIN..=MAX_GAIN).contains(&gain) {
+        return Err(PlayerError::invalid(format!(
+            "Invalid EQ gain: {} dB",
+            gain
+        )));
+    }
+    Ok(())
+}
+
+fn validate_filters(filters: &[ParametricFilter]) -> Result<(), PlayerError> {
+    for filter in filters {
+        validate_gain(filter.gain)?;
+        if !(filter.frequency > 0. && filter.q > 0.) {
+            return Err(PlayerError::invalid(format!(
+                "Invalid EQ filter: {:?}",
+                filter
+            )));
+        }
+    }
+    Ok(())
+}
+
+fn read_presets(app: &AppHandle) -> Result<Vec<EqPreset>, PlayerError> {
+    read_config(app, PRESETS_FILE)
+}
+
+fn write_presets(app: &AppHandle, presets: &[EqPreset]) -> Result<(), PlayerError> {
+    write_config(app, PRESETS_FILE, presets)
+}
+
+/// Save `preset`, replacing the one with the same name.
+fn store_preset(app: &AppHandle, preset: EqPreset) -> Result<Vec<EqPreset>, PlayerError> {
+    let mut presets = read_presets(app)?;
+    presets.retain(|p| p.name != preset.name);
+    presets.push(preset);
+    write_presets(app, &presets)?;
+    Ok(presets)
+}
+
+fn apply_preset(state: &AudioState, preset: EqPreset) {
+    state.equalizer.update(|settings| {
+        settings.enabled = true;
+        settings.preamp = preset.preamp;
+        settings.bands = preset.bands;
+        settings.filters = preset.filters;
+    });
+}
+
+/// Apply the preset assigned to the output device in use, if any.
+pub(crate) fn apply_device_eq_profile(app: &AppHandle) -> Result<(), PlayerError> {
+    let state = app.state::<AudioState>();
+    let Some(device) = state.output_device.lock().unwrap().clone() else {
+        return Ok(());
+    };
+    let assignments: HashMap<String, String> = read_config(app, DEVICE_PROFILES_FILE)?;
+    let Some(name) = assignments.get(&device) else {
+        return Ok(());
+    };
+
+    match read_presets(app)?
+        .into_iter()
+        .find(|preset| &preset.name == name)
+    {
+        Some(preset) => apply_preset(&state, preset),
+        None => {
+            if cfg!(debug_assertions) {
+                println!("[DEBUG] EQ profile {} of {} is missing", name, device);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Get the current equalizer settings.
+#[tauri::command]
+pub fn fetch_eq(state: State<'_, AudioState>) -> Result<EqSettings, PlayerError> {
+    Ok(state.equalizer.settings())
+}
+
+#[tauri::command]
+pub fn set_eq_enabled(enabled: bool, state: State<'_, AudioState>) -> Result<(), PlayerError> {
+    state
+        .equalizer
+        .update(|settings| settings.enabled = enabled);
+    Ok(())
+}
+
+#[tauri::command]
+pub fn set_eq_preamp(preamp: f32, state: State<'_, AudioState>) -> Result<(), PlayerError> {
+    validate_gain(preamp)?;
+    state.equalizer.update(|settings| settings.preamp = preamp);
+    Ok(())
+}
+
+/// Set the gains of the graphic bands, and the parametric filters if given.
+#[tauri::command]
+pub fn set_eq_bands(
+    bands: Vec<f32>,
+    filters: Option<Vec<ParametricFilter>>,
+    state: State<'_, AudioState>,
+) -> Result<(), PlayerError> {
+    let bands: [f32; 10] = bands
+        .try_into()
+        .map_err(|_| PlayerError::invalid(format!("Expected {} EQ bands", EQ_FREQUENCIES.len())))?;
+    for gain in bands {
+        validate_gain(gain)?;
+    }
+    if let Some(filters) = &filters {
+        validate_filters(filters)?;
+    }
+
+    state.equalizer.update(|settings| {
+        settings.bands = bands;
+        if let Some(filters) = filters {
+            settings.filters = filters;
+        }
+    });
+    Ok(())
+}
+
+#[tauri::command]
+pub fn fetch_eq_presets(app_handle: AppHandle) -> Result<Vec<EqPreset>, PlayerError> {
+    read_presets(&app_handle)
+}
+
+/// Save the current bands and filters under `name`, replacing a preset of the same name.
+#[tauri::command]
+pub fn save_eq_preset(
+    name: String,
+    app_handle: AppHandle,
+    state: State<'_, AudioState>,
+) -> Result<Vec<EqPreset>, PlayerError> {
+    if name.trim().is_empty() {
+        return Err(PlayerError::invalid("The preset needs a name"));
+    }
+    let settings = state.equalizer.settings();
+    store_preset(
+        &app_handle,
+        EqPreset {
+            name,
+            preamp: settings.preamp,
+            bands: settings.bands,
+            filters: settings.filters,
+        },
+    )
+}
+
+/// Turn an Equalizer APO / AutoEq `ParametricEQ.txt` into a preset.
+/// Without a name, the file name is used (e.g. `HD 600` for `HD 600 ParametricEQ.txt`).
+#[tauri::command]
+pub fn import_eq_profile(
+    path: String,
+    name: Option<String>,
+    app_handle: AppHandle,
+) -> Result<Vec<EqPreset>, PlayerError> {
+    let content = std::fs::read_to_string(&path).map_err(|e| PlayerError::io(&path, &e))?;
+    let profile = eq_profile::parse_parametric_eq(&content).map_err(PlayerError::invalid)?;
+    validate_gain(profile.preamp)?;
+    validate_filters(&profile.filters)?;
+
+    let name = name
+        .filter(|name| !name.trim().is_empty())
+        .or_else(|| {
+            let stem = Path::new(&path).file_stem()?.to_str()?;
+            let stem = stem.strip_suffix("ParametricEQ").unwrap_or(stem).trim();
+            (!stem.is_empty()).then(|| stem.to_string())
+        })
+        .ok_or_else(|| PlayerError::invalid("The profile needs a name"))?;
+
+    store_preset(
+        &app_handle,
+        EqPreset {
+            name,
+            preamp: profile.preamp,
+            bands: [0.; 10],
+            filters: profile.filters,
+        },
+    )
+}
+
+/// Apply a saved preset. The equalizer is enabled as well.
+#[tauri::command]
+pub fn apply_eq_preset(
+    name: String,
+    app_handle: AppHandle,
+    state: State<'_, AudioState>,
+) -> Result<EqSettings, PlayerError> {
+    let preset = read_presets(&app_handle)?
+        .into_iter()
+        .find(|preset| preset.name == name)
+        .ok_or(PlayerError::PresetNotFound { name })?;
+
+    apply_preset(&state, preset);
+    Ok(state.equalizer.settings())
+}
+
+#[tauri::command]
+pub fn delete_eq_preset(name: String, app_handle: AppHandle) -> Result<Vec<EqPreset>, PlayerError> {
+    let mut presets = read_presets(&app_handle)?;
+    presets.retain(|preset| preset.name != name);
+    write_presets(&app_handle, &presets)?;
+    Ok(presets)
+}
+
+/// Output device names and the preset assigned to each.
+#[tauri::command]
+pub fn fetch_eq_device_profiles(
+    app_handle: AppHandle,
+) -> Result<HashMap<String, String>, PlayerError> {
+    read_config(&app_handle, DEVICE_PROFILES_FILE)
+}
+
+/// Assign a preset to an output device, or remove the assignment with `None`.
+/// Applied right away when the device is the one in use.
+#[tauri::command]
+pub fn set_eq_device_profile(
+    device: String,
+    profile: Option<String>,
+    app_handle: AppHandle,
+    state: State<'_, AudioState>,
+) -> Result<(), PlayerError> {
+    let mut assignments: HashMap<String, String> = read_config(&app_handle, DEVICE_PROFILES_FILE)?;
+    match profile {
+        Some(profile) => {
+            if !read_presets(&app_handle)?.iter().any(|p| p.name == profile) {
+                return Err(PlayerError::PresetNotFound { name: profile });
+            }
+            assignments.insert(device.clone(), profile);
+        }
+        None => {
+            assignments.remove(&device);
+        }
+    }
+    write_config(&app_handle, DEVICE_PROFILES_FILE, &assignments)?;
+
+    if state.output_device.lock().unwrap().as_ref() == Some(&device) {
+        apply_device_eq_profile(&app_handle)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{EqControl, Equalizer};
+    use crate::utils::song::test_audio;
+    use rodio::buffer::SamplesBuffer;
+    use std::sync::Arc;
+
+    fn equalize(control: Arc<EqControl>, input: &[f32]) -> Vec<f32> {
+        Equalizer::new(SamplesBuffer::new(2, 44100, input.to_vec()), control).collect()
+    }
+
+    #[test]
+    fn flat_eq_passes_through() {
+        let input = test_audio::sine(440., -6., 2, 44100, 0.5);
+        let control = Arc::new(EqControl::default());
+        control.update(|settings| settings.enabled = true);
+        let output = equalize(control, &input);
+        assert_eq!(output.len(), input.len());
+        for (output, input) in output.iter().zip(&input) {
+            assert!((output - input).abs() < 1e-6, "{} != {}", output, input);
+        }
+    }
+
+    #[test]
+    fn disabled_eq_passes_through() {
+        let input = test_audio::sine(440., -6., 2, 44100, 0.5);
+        let control = Arc::new(EqControl::default());
+        control.update(|settings| {
+            settings.preamp = -6.;
+            settings.bands = [12.; 10];
+        });
+        assert_eq!(equalize(control, &input), input);
+    }
+
+    #[test]
+    fn preamp_scales_the_input() {
+        let input = test_audio::sine(440., -12., 2, 44100, 0.5);
+        let control = Arc::new(EqControl::default());
+        control.update(|settings| {
+            settings.enabled = true;
+            settings.preamp = 6.;
+        });
+        for (output, input) in equalize(control, &input).iter().zip(&input) {
+            assert!((output - input * 1.99526).abs() < 1e-5);
+        }
+    }
+}
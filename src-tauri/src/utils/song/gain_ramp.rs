@@ -0,0 +1,222 @@
+use rodio::source::SeekError;
+use rodio::{ChannelCount, Sample, SampleRate, Source};
+use std::f32::consts::{FRAC_PI_2, PI};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+pub const MIN_RAMP: Duration = Duration::from_millis(5);
+pub const MAX_RAMP: Duration = Duration::from_millis(50);
+const DEFAULT_RAMP: Duration = Duration::from_millis(15);
+
+/// Shared by the audio thread and the `GainRamp` of the source being played.
+pub struct RampControl {
+    /// False while pausing or stopping.
+    audible: AtomicBool,
+    length_micros: AtomicU64,
+    /// Someone waits for the ramps to end.
+    pending: AtomicBool,
+    settled: Mutex<bool>,
+    changed: Condvar,
+}
+
+impl Default for RampControl {
+    fn default() -> Self {
+        RampControl {
+            audible: AtomicBool::new(true),
+            length_micros: AtomicU64::new(DEFAULT_RAMP.as_micros() as u64),
+            pending: AtomicBool::new(false),
+            settled: Mutex::new(true),
+            changed: Condvar::new(),
+        }
+    }
+}
+
+impl RampControl {
+    pub fn set_length(&self, length: Duration) {
+        self.length_micros
+            .store(length.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn length(&self) -> Duration {
+        Duration::from_micros(self.length_micros.load(Ordering::Relaxed))
+    }
+
+    /// Fade in or out, from wherever the gain is.
+    pub fn set_audible(&self, audible: bool) {
+        self.audible.store(audible, Ordering::Relaxed);
+    }
+
+    pub fn audible(&self) -> bool {
+        self.audible.load(Ordering::Relaxed)
+    }
+
+    /// Wait until the fade or the seek crossfade just started is over, at most `timeout`.
+    /// Return false on timeout, e.g. when the output stopped pulling samples.
+    pub fn wait(&self, timeout: Duration) -> bool {
+        let mut settled = self.settled.lock().unwrap();
+        *settled = false;
+        self.pending.store(true, Ordering::Relaxed);
+        let (_settled, result) = self
+            .changed
+            .wait_timeout_while(settled, timeout, |settled| !*settled)
+            .unwrap();
+        !result.timed_out()
+    }
+
+    fn settle(&self) {
+        if self.pending.swap(false, Ordering::Relaxed) {
+            *self.settled.lock().unwrap() = true;
+            self.changed.notify_all();
+        }
+    }
+
+    fn length_frames(&self, sample_rate: SampleRate) -> usize {
+        (self.length().as_secs_f64() * sample_rate as f64) as usize
+    }
+}
+
+/// Fades the sound in and out instead of cutting it, so that pausing, resuming
+/// and stopping do not click, and crossfades over a seek.
+///
+/// Once faded out, it plays silence without reading its input, so that a pause
+/// does not skip anything.
+pub struct GainRamp<S> {
+    input: S,
+    control: Arc<RampControl>,
+    channels: ChannelCount,
+    channel: ChannelCount,
+    /// Where the ramp is, from 0 (silent) to 1.
+    level: f32,
+    gain: f32,
+    holding: bool,
+    /// Samples following the position before the last seek, faded out
+    /// while the ones after it fade in.
+    tail: Vec<Sample>,
+    tail_next: usize,
+}
+
+impl<S: Source> GainRamp<S> {
+    pub fn new(input: S, control: Arc<RampControl>) -> Self {
+        let level = if control.audible() { 1. } else { 0. };
+        GainRamp {
+            channels: input.channels().max(1),
+            input,
+            control,
+            channel: 0,
+            level,
+            gain: level,
+            holding: false,
+            tail: Vec::new(),
+            tail_next: 0,
+        }
+    }
+
+    /// Move the ramp one frame on.
+    fn next_frame(&mut self) {
+        let target = if self.control.audible() { 1. } else { 0. };
+        let step = 1. / self.control.length_frames(self.sample_rate()).max(1) as f32;
+        self.level = if self.level < target {
+            (self.level + step).min(target)
+        } else {
+            (self.level - step).max(target)
+        };
+        // Raised cosine, smoother at both ends than a straight line.
+        self.gain = (1. - (self.level * PI).cos()) / 2.;
+        self.holding = self.level == 0. && target == 0.;
+        if self.level == target && self.tail_next >= self.tail.len() {
+            self.control.settle();
+        }
+    }
+}
+
+impl<S: Source> Iterator for GainRamp<S> {
+    type Item = Sample;
+
+    fn next(&mut self) -> Option<Sample> {
+        if self.channel == 0 {
+            self.next_frame();
+        }
+        self.channel = (self.channel + 1) % self.channels;
+        if self.holding {
+            return Some(0.);
+        }
+
+        let sample = self.input.next();
+        let Some(&old) = self.tail.get(self.tail_next) else {
+            return sample.map(|sample| sample * self.gain);
+        };
+        let frames = self.tail.len() / self.channels as usize;
+        let angle = (self.tail_next / self.channels as usize) as f32 / frames as f32 * FRAC_PI_2;
+        self.tail_next += 1;
+        Some((sample.unwrap_or(0.) * angle.sin() + old * angle.cos()) * self.gain)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.input.size_hint()
+    }
+}
+
+impl<S: Source> Source for GainRamp<S> {
+    fn current_span_len(&self) -> Option<usize> {
+        self.input.current_span_len()
+    }
+
+    fn channels(&self) -> ChannelCount {
+        self.channels
+    }
+
+    fn sample_rate(&self) -> SampleRate {
+        self.input.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.input.total_duration()
+    }
+
+    fn try_seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+        // Nothing to crossfade from while silent.
+        let frames = if self.holding {
+            0
+        } else {
+            self.control.length_frames(self.sample_rate())
+        };
+        let tail = self
+            .input
+            .by_ref()
+            .take(frames * self.channels as usize)
+            .collect();
+        self.input.try_seek(pos)?;
+        self.tail = tail;
+        self.tail_next = 0;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{GainRamp, RampControl};
+    use rodio::buffer::SamplesBuffer;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn fades_out_then_holds() {
+        let control = Arc::new(RampControl::default());
+        control.set_length(Duration::from_millis(10));
+        // 48 kHz mono: 480 frames per ramp.
+        let input = SamplesBuffer::new(1, 48000, vec![1.; 4800]);
+        let mut ramp = GainRamp::new(input, control.clone());
+        assert!(ramp.by_ref().take(480).all(|sample| sample == 1.));
+
+        control.set_audible(false);
+        let fading: Vec<f32> = ramp.by_ref().take(480).collect();
+        assert!(fading.windows(2).all(|pair| pair[1] <= pair[0]));
+        assert!(fading[470..].iter().all(|sample| *sample < 0.01));
+        assert!(ramp.by_ref().take(480).all(|sample| sample == 0.));
+
+        // Nothing was read while held.
+        control.set_audible(true);
+        assert!(ramp.count() >= 4800 - 960);
+    }
+}
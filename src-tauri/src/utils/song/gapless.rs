@@ -0,0 +1,200 @@
+use mp4ameta::FreeformIdent;
+use rodio::source::SeekError;
+use rodio::{ChannelCount, Sample, SampleRate, Source};
+use std::path::Path;
+use std::time::Duration;
+
+/// Encoder delay and padding of a track, in frames.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct GaplessInfo {
+    /// Priming frames added by the encoder before the actual audio.
+    pub delay: u64,
+    /// Number of frames of actual audio, without delay and padding.
+    pub valid_frames: Option<u64>,
+}
+
+/// Parse the `iTunSMPB` comment written by iTunes/Apple encoders, e.g.
+/// ` 00000000 00000840 000001CA 00000000001CE4F6 ...`
+/// (reserved, delay, padding, original sample count, all in hex).
+pub fn parse_itunsmpb(value: &str) -> Option<GaplessInfo> {
+    let fields = value
+        .split_whitespace()
+        .take(4)
+        .map(|field| u64::from_str_radix(field, 16).ok())
+        .collect::<Option<Vec<u64>>>()?;
+    if fields.len() < 4 {
+        return None;
+    }
+
+    Some(GaplessInfo {
+        delay: fields[1],
+        valid_frames: (fields[3] > 0).then_some(fields[3]),
+    })
+}
+
+/// Read the gapless information that the decoder does not handle by itself.
+///
+/// MP3 files are not listed here: with gapless decoding enabled, symphonia already
+/// reads the LAME/Xing header and trims the encoder delay and padding.
+pub fn read_gapless_info(path: &str) -> Option<GaplessInfo> {
+    let ext = Path::new(path).extension()?.to_str()?.to_lowercase();
+
+    match ext.as_str() {
+        "m4a" | "mp4" => {
+            let tag = mp4ameta::Tag::read_from_path(path).ok()?;
+            let ident = FreeformIdent::new("com.apple.iTunes", "iTunSMPB");
+            let value = tag.strings_of(&ident).next()?;
+            parse_itunsmpb(value)
+        }
+        _ => None,
+    }
+}
+
+/// Drops the encoder delay at the start of a source and the padding at its end.
+pub struct GaplessTrim<S> {
+    input: S,
+    info: GaplessInfo,
+    channels: ChannelCount,
+    sample_rate: SampleRate,
+    /// Samples left before the padding starts.
+    remaining: Option<u64>,
+}
+
+impl<S: Source> GaplessTrim<S> {
+    pub fn new(mut input: S, info: GaplessInfo) -> Self {
+        let channels = input.channels();
+        let sample_rate = input.sample_rate();
+
+        for _ in 0..info.delay * channels as u64 {
+            if input.next().is_none() {
+                break;
+            }
+        }
+
+        GaplessTrim {
+            input,
+            info,
+            channels,
+            sample_rate,
+            remaining: info.valid_frames.map(|frames| frames * channels as u64),
+        }
+    }
+}
+
+impl<S: Source> Iterator for GaplessTrim<S> {
+    type Item = Sample;
+
+    #[inline]
+    fn next(&mut self) -> Option<Sample> {
+        if let Some(remaining) = self.remaining.as_mut() {
+            if *remaining == 0 {
+                return None;
+            }
+            *remaining -= 1;
+        }
+        self.input.next()
+    }
+}
+
+impl<S: Source> Source for GaplessTrim<S> {
+    fn current_span_len(&self) -> Option<usize> {
+        match (self.input.current_span_len(), self.remaining) {
+            (Some(len), Some(remaining)) => Some(len.min(remaining as usize)),
+            (len, _) => len,
+        }
+    }
+
+    fn channels(&self) -> ChannelCount {
+        self.channels
+    }
+
+    fn sample_rate(&self) -> SampleRate {
+        self.sample_rate
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        match self.info.valid_frames {
+            Some(frames) => Some(Duration::from_secs_f64(
+                frames as f64 / self.sample_rate as f64,
+            )),
+            None => self.input.total_duration().map(|total| {
+                total.saturating_sub(Duration::from_secs_f64(
+                    self.info.delay as f64 / self.sample_rate as f64,
+                ))
+            }),
+        }
+    }
+
+    fn try_seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+        // The decoder's timeline still contains the delay.
+        let delay = Duration::from_secs_f64(self.info.delay as f64 / self.sample_rate as f64);
+        self.input.try_seek(pos + delay)?;
+
+        if let Some(frames) = self.info.valid_frames {
+            let played = (pos.as_secs_f64() * self.sample_rate as f64) as u64;
+            self.remaining = Some(frames.saturating_sub(played) * self.channels as u64);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_itunsmpb, GaplessInfo, GaplessTrim};
+    use rodio::buffer::SamplesBuffer;
+    use rodio::Source;
+    use std::time::Duration;
+
+    #[test]
+    fn itunsmpb_values() {
+        let value = " 00000000 00000840 000001CA 00000000001CE4F6 00000000 00000000";
+        assert_eq!(
+            parse_itunsmpb(value),
+            Some(GaplessInfo {
+                delay: 2112,
+                valid_frames: Some(1893622),
+            })
+        );
+        assert_eq!(
+            parse_itunsmpb("00000000 00000840 00000000 0000000000000000"),
+            Some(GaplessInfo {
+                delay: 2112,
+                valid_frames: None,
+            })
+        );
+        assert_eq!(parse_itunsmpb("00000000 00000840 000001CA"), None);
+        assert_eq!(parse_itunsmpb("00000000 0000084G 000001CA 00"), None);
+        assert_eq!(parse_itunsmpb(""), None);
+    }
+
+    /// Stereo frames holding their index, at 4 Hz so that seeks land on exact frames.
+    fn frames(count: usize) -> SamplesBuffer {
+        let samples: Vec<f32> = (0..count).flat_map(|i| [i as f32; 2]).collect();
+        SamplesBuffer::new(2, 4, samples)
+    }
+
+    #[test]
+    fn trims_to_the_exact_frame() {
+        let info = GaplessInfo {
+            delay: 3,
+            valid_frames: Some(5),
+        };
+        let trim = GaplessTrim::new(frames(12), info);
+        assert_eq!(trim.total_duration(), Some(Duration::from_millis(1250)));
+        let samples: Vec<f32> = trim.collect();
+        assert_eq!(samples, [3., 3., 4., 4., 5., 5., 6., 6., 7., 7.]);
+
+        let mut trim = GaplessTrim::new(frames(12), info);
+        trim.try_seek(Duration::from_millis(500)).unwrap();
+        let samples: Vec<f32> = trim.collect();
+        assert_eq!(samples, [5., 5., 6., 6., 7., 7.]);
+
+        // Without a length, only the delay goes.
+        let info = GaplessInfo {
+            delay: 10,
+            valid_frames: None,
+        };
+        let samples: Vec<f32> = GaplessTrim::new(frames(12), info).collect();
+        assert_eq!(samples, [10., 10., 11., 11.]);
+    }
+}
@@ -0,0 +1,334 @@
+use crate::utils::song::player_error::PlayerError;
+use crate::utils::song::r128::{Loudness, LoudnessMeter};
+use crate::utils::song::{replay_gain, song_metadata, song_player};
+use audiotags::Tag;
+use std::collections::{HashMap, VecDeque};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tauri::{AppHandle, Emitter, Manager, State};
+
+/// Loudness targeted by ReplayGain 2.0 (LUFS).
+const REPLAYGAIN_REFERENCE: f64 = -18.;
+/// Loudness targeted by the R128_* tags (LUFS), as in RFC 7845.
+const R128_REFERENCE: f64 = -23.;
+/// How many samples are decoded between two checks of the cancel flag.
+const CANCEL_CHECK_SAMPLES: usize = 1 << 16;
+
+/// Cancel flag of the scan in progress, if any.
+pub struct LoudnessScanState(pub Mutex<Option<Arc<AtomicBool>>>);
+
+/// Sent once the tags of a track are written, or when it failed.
+#[derive(serde::Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct ScanProgress {
+    path: String,
+    done: usize,
+    total: usize,
+    integrated_loudness: Option<f64>,
+    loudness_range: Option<f64>,
+    true_peak: Option<f32>,
+    error: Option<PlayerError>,
+}
+
+#[derive(serde::Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct ScanFinished {
+    scanned: usize,
+    failed: usize,
+    total: usize,
+    cancelled: bool,
+}
+
+/// Tracks sharing an album tag and a folder. Their tags are written once all
+/// of them are measured, since the album values depend on every track.
+struct Album {
+    /// Tracks without an album tag get no album values.
+    tagged: bool,
+    remaining: usize,
+    measured: Vec<(String, Loudness)>,
+}
+
+struct Scan {
+    app: AppHandle,
+    jobs: Mutex<VecDeque<(usize, String)>>,
+    albums: Vec<Mutex<Album>>,
+    cancel: Arc<AtomicBool>,
+    total: usize,
+    done: AtomicUsize,
+    failed: AtomicUsize,
+}
+
+impl Scan {
+    fn new(app: AppHandle, paths: Vec<String>, cancel: Arc<AtomicBool>) -> Self {
+        let total = paths.len();
+        let mut keys: HashMap<(String, String), usize> = HashMap::new();
+        let mut albums = Vec::new();
+        let mut jobs = VecDeque::new();
+        for path in paths {
+            // The workers stop at once when the scan was cancelled while grouping.
+            if cancel.load(Ordering::Relaxed) {
+                break;
+            }
+            let album = album_tag(&path);
+            let folder = Path::new(&path)
+                .parent()
+                .map(|dir| dir.to_string_lossy().to_string())
+                .unwrap_or_default();
+
+            let index = match album {
+                Some(album) => *keys.entry((album, folder)).or_insert_with(|| {
+                    albums.push(Album {
+                        tagged: true,
+                        remaining: 0,
+                        measured: Vec::new(),
+                    });
+                    albums.len() - 1
+                }),
+                None => {
+                    albums.push(Album {
+                        tagged: false,
+                        remaining: 0,
+                        measured: Vec::new(),
+                    });
+                    albums.len() - 1
+                }
+            };
+            albums[index].remaining += 1;
+            jobs.push_back((index, path));
+        }
+
+        Scan {
+            app,
+            total,
+            jobs: Mutex::new(jobs),
+            albums: albums.into_iter().map(Mutex::new).collect(),
+            cancel,
+            done: AtomicUsize::new(0),
+            failed: AtomicUsize::new(0),
+        }
+    }
+
+    fn cancelled(&self) -> bool {
+        self.cancel.load(Ordering::Relaxed)
+    }
+
+    fn worker(&self) {
+        loop {
+            let Some((album, path)) = self.jobs.lock().unwrap().pop_front() else {
+                return;
+            };
+            if self.cancelled() {
+                return;
+            }
+
+            let measured = measure(&path, &self.cancel);
+            if self.cancelled() {
+                return;
+            }
+
+            let finished = {
+                let mut album = self.albums[album].lock().unwrap();
+                album.remaining -= 1;
+                match measured {
+                    Ok(loudness) => album.measured.push((path, loudness)),
+                    Err(e) => self.report(path, None, Err(e)),
+                }
+                (album.remaining == 0).then(|| (album.tagged, std::mem::take(&mut album.measured)))
+            };
+            if let Some((tagged, tracks)) = finished {
+                self.write_album(tagged, tracks);
+            }
+        }
+    }
+
+    fn write_album(&self, tagged: bool, tracks: Vec<(String, Loudness)>) {
+        let album = tagged.then(|| Loudness::combine(tracks.iter().map(|(_, track)| track)));
+        for (path, track) in tracks {
+            let result = gain_fields(&track, album.as_ref())
+                .ok_or_else(|| PlayerError::TagWriteFailed {
+                    path: path.clone(),
+                    reason: "The track is silent".to_string(),
+                })
+                .and_then(|fields| replay_gain::write_gain_tags(&path, &fields));
+            song_metadata::forget_metadata(&path);
+            self.report(path, Some(&track), result);
+        }
+    }
+
+    fn report(&self, path: String, track: Option<&Loudness>, result: Result<(), PlayerError>) {
+        if result.is_err() {
+            self.failed.fetch_add(1, Ordering::Relaxed);
+        }
+        let done = self.done.fetch_add(1, Ordering::Relaxed) + 1;
+        if cfg!(debug_assertions) {
+            if let Err(e) = &result {
+                println!("[DEBUG] Loudness scan of {} failed: {}", path, e);
+            }
+        }
+
+        let _ = self.app.emit(
+            "loudness-scan-progress",
+            ScanProgress {
+                path,
+                done,
+                total: self.total,
+                integrated_loudness: track.and_then(Loudness::integrated),
+                loudness_range: track.and_then(Loudness::range),
+                true_peak: track.map(|track| track.true_peak),
+                error: result.err(),
+            },
+        );
+    }
+}
+
+/// Only the album tag, as reading the full metadata of a library takes long.
+fn album_tag(path: &str) -> Option<String> {
+    let tag = Tag::new().read_from_path(path).ok()?;
+    tag.album().map(|album| album.title.to_string())
+}
+
+/// Decode the whole file through the meter.
+fn measure(path: &str, cancel: &AtomicBool) -> Result<Loudness, PlayerError> {
+    let source = song_player::open_decoder(path)?;
+    let mut meter = LoudnessMeter::new(source.channels() as usize, source.sample_rate());
+    for (i, sample) in source.enumerate() {
+        if i % CANCEL_CHECK_SAMPLES == 0 && cancel.load(Ordering::Relaxed) {
+            return Err(PlayerError::Cancelled);
+        }
+        meter.push(sample);
+    }
+    Ok(meter.finish())
+}
+
+/// The REPLAYGAIN_* and R128_* values of a track, `None` when it is silent.
+fn gain_fields(track: &Loudness, album: Option<&Loudness>) -> Option<Vec<(&'static str, String)>> {
+    let loudness = track.integrated()?;
+    // Q7.8 fixed point, in dB.
+    let r128_gain = |loudness: f64| {
+        (((R128_REFERENCE - loudness) * 256.).round() as i64)
+            .clamp(i16::MIN as i64, i16::MAX as i64)
+            .to_string()
+    };
+
+    let mut fields = vec![
+        (
+            "REPLAYGAIN_REFERENCE_LOUDNESS",
+            format!("{:.2} LUFS", REPLAYGAIN_REFERENCE),
+        ),
+        (
+            "REPLAYGAIN_TRACK_GAIN",
+            format!("{:.2} dB", REPLAYGAIN_REFERENCE - loudness),
+        ),
+        ("REPLAYGAIN_TRACK_PEAK", format!("{:.6}", track.true_peak)),
+        ("R128_TRACK_GAIN", r128_gain(loudness)),
+    ];
+    if let Some(range) = track.range() {
+        fields.push(("REPLAYGAIN_TRACK_RANGE", format!("{:.2} dB", range)));
+    }
+
+    if let Some(album) = album {
+        if let Some(loudness) = album.integrated() {
+            fields.push((
+                "REPLAYGAIN_ALBUM_GAIN",
+                format!("{:.2} dB", REPLAYGAIN_REFERENCE - loudness),
+            ));
+            fields.push(("REPLAYGAIN_ALBUM_PEAK", format!("{:.6}", album.true_peak)));
+            fields.push(("R128_ALBUM_GAIN", r128_gain(loudness)));
+        }
+        if let Some(range) = album.range() {
+            fields.push(("REPLAYGAIN_ALBUM_RANGE", format!("{:.2} dB", range)));
+        }
+    }
+    Some(fields)
+}
+
+fn run_scan(app: AppHandle, paths: Vec<String>, cancel: Arc<AtomicBool>) {
+    let scan = Scan::new(app.clone(), paths, cancel.clone());
+    let workers = std::thread::available_parallelism()
+        .map_or(2, |n| n.get())
+        .min(scan.total.max(1));
+    std::thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| scan.worker());
+        }
+    });
+
+    let _ = app.emit(
+        "loudness-scan-finished",
+        ScanFinished {
+            scanned: scan.done.load(Ordering::Relaxed) - scan.failed.load(Ordering::Relaxed),
+            failed: scan.failed.load(Ordering::Relaxed),
+            total: scan.total,
+            cancelled: scan.cancelled(),
+        },
+    );
+    *app.state::<LoudnessScanState>().0.lock().unwrap() = None;
+}
+
+/// Measure the loudness of the files (EBU R128) and write their ReplayGain tags.
+/// Tracks of the same album in the same folder also get album values.
+/// Progress is reported through `loudness-scan-progress` events.
+#[tauri::command]
+pub fn scan_loudness(
+    paths: Vec<String>,
+    app_handle: AppHandle,
+    state: State<'_, LoudnessScanState>,
+) -> Result<(), PlayerError> {
+    let mut running = state.0.lock().unwrap();
+    if running.is_some() {
+        return Err(PlayerError::Busy {
+            reason: "A loudness scan is already running".to_string(),
+        });
+    }
+    let cancel = Arc::new(AtomicBool::new(false));
+    *running = Some(cancel.clone());
+
+    std::thread::spawn(move || run_scan(app_handle, paths, cancel));
+    Ok(())
+}
+
+/// Stop the running scan. Albums not fully measured are left untouched.
+#[tauri::command]
+pub fn cancel_loudness_scan(state: State<'_, LoudnessScanState>) -> Result<(), PlayerError> {
+    if let Some(cancel) = state.0.lock().unwrap().as_ref() {
+        cancel.store(true, Ordering::Relaxed);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::gain_fields;
+    use crate::utils::song::r128::LoudnessMeter;
+    use crate::utils::song::test_audio;
+    use std::collections::HashMap;
+
+    #[test]
+    fn gain_fields_of_a_track() {
+        let mut meter = LoudnessMeter::new(2, 48000);
+        for sample in test_audio::sine(1000., -20., 2, 48000, 5.) {
+            meter.push(sample);
+        }
+        let track = meter.finish();
+        let fields: HashMap<_, _> = gain_fields(&track, None).unwrap().into_iter().collect();
+
+        assert_eq!(fields["REPLAYGAIN_REFERENCE_LOUDNESS"], "-18.00 LUFS");
+        // About -20 LUFS: 2 dB under the ReplayGain reference, 3 dB over the R128 one.
+        let gain: f64 = fields["REPLAYGAIN_TRACK_GAIN"]
+            .strip_suffix(" dB")
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!((gain - 2.).abs() < 0.1, "{} dB", gain);
+        let r128_gain: i16 = fields["R128_TRACK_GAIN"].parse().unwrap();
+        assert!((r128_gain as f64 / 256. + 3.).abs() < 0.1, "{}", r128_gain);
+        let peak: f64 = fields["REPLAYGAIN_TRACK_PEAK"].parse().unwrap();
+        assert!((peak - 0.1).abs() < 0.001, "{}", peak);
+        assert!(!fields.contains_key("REPLAYGAIN_ALBUM_GAIN"));
+
+        let album = gain_fields(&track, Some(&track)).unwrap();
+        assert!(album.iter().any(|(name, _)| *name == "R128_ALBUM_GAIN"));
+        assert!(gain_fields(&LoudnessMeter::new(2, 48000).finish(), None).is_none());
+    }
+}
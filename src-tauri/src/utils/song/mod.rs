@@ -1,7 +1,39 @@
+pub mod audio_engine;
+pub mod cover_art;
+pub mod eq_profile;
+pub mod equalizer;
+pub mod gain_ramp;
+pub mod gapless;
+pub mod loudness_scan;
 mod lyrics_handler;
+pub mod output_devices;
+pub mod playback_clock;
+pub mod playback_deck;
+pub mod playback_queue;
+pub mod player_error;
+pub mod r128;
+pub mod replay_gain;
+pub mod seek_fallback;
+pub mod sleep_timer;
 pub mod song_metadata;
 pub mod song_player;
+pub mod stream_info;
+pub mod tag_editor;
+#[cfg(test)]
+mod test_audio;
+pub mod time_stretch;
+pub mod track_duration;
+pub mod track_generations;
 
+pub use cover_art::*;
+pub use equalizer::*;
+pub use loudness_scan::*;
 pub use lyrics_handler::*;
+pub use output_devices::*;
+pub use playback_clock::*;
+pub use playback_queue::*;
+pub use player_error::*;
+pub use sleep_timer::*;
 pub use song_metadata::*;
 pub use song_player::*;
+pub use tag_editor::*;
@@ -0,0 +1,441 @@
+use crate::utils::config::{read_config, write_config};
+use crate::utils::song::audio_engine::OutputBackend;
+use crate::utils::song::equalizer;
+use crate::utils::song::player_error::PlayerError;
+use crate::utils::song::song_player::{self, AudioState};
+use crate::utils::system::{self, PlayerControls};
+use rodio::cpal;
+use rodio::cpal::traits::{DeviceTrait, HostTrait};
+use std::sync::Mutex;
+use std::time::Duration;
+use tauri::{AppHandle, Emitter, Manager, State};
+
+const PREFERENCE_FILE: &str = "output_device.json";
+/// How often the devices are listed to notice the ones coming and going.
+const WATCH_INTERVAL: Duration = Duration::from_secs(2);
+
+/// The device settings, saved in `output_device.json`.
+#[derive(serde::Serialize, serde::Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+struct OutputPreference {
+    device: Option<String>,
+    #[serde(default)]
+    resume_on_reconnect: bool,
+}
+
+/// The device in use when it disappeared.
+pub struct LostDevice {
+    name: String,
+    /// Whether the playback was paused because of it.
+    paused: bool,
+}
+
+#[derive(Default)]
+pub struct DeviceSettings {
+    /// The preferred output device, `None` to follow the default one.
+    preferred: Option<String>,
+    /// Resume the playback paused by a device loss once the device is back.
+    resume_on_reconnect: bool,
+    lost: Option<LostDevice>,
+}
+
+impl DeviceSettings {
+    /// Remember the device in use that disappeared. When the fallback goes away too,
+    /// the first device is still the one to go back to.
+    fn device_lost(&mut self, name: String, paused: bool) {
+        self.lost.get_or_insert(LostDevice { name, paused });
+    }
+
+    /// The lost device is in use again. Return whether to resume the playback,
+    /// only when the loss paused it and the user asked for it.
+    fn device_back(&mut self) -> bool {
+        let lost = self.lost.take();
+        self.resume_on_reconnect && lost.is_some_and(|lost| lost.paused)
+    }
+}
+
+pub struct OutputDeviceState(pub Mutex<DeviceSettings>);
+
+#[derive(serde::Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct OutputDevice {
+    name: String,
+    is_default: bool,
+    /// Whether the playback goes to this device.
+    active: bool,
+    preferred: bool,
+}
+
+#[derive(serde::Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct DeviceLost {
+    device: String,
+    /// Whether the playback was paused.
+    paused: bool,
+}
+
+#[derive(serde::Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct OutputChanged {
+    /// `None` for the null output.
+    device: Option<String>,
+}
+
+fn default_device_name() -> Option<String> {
+    cpal::default_host()
+        .default_output_device()
+        .and_then(|device| device.name().ok())
+}
+
+/// Names of the output devices, the default one included.
+fn device_names() -> Result<Vec<String>, PlayerError> {
+    let mut names: Vec<String> = cpal::default_host()
+        .output_devices()
+        .map_err(|e| PlayerError::NoOutputDevice {
+            device: None,
+            reason: format!("Failed to list the output devices: {}", e),
+        })?
+        .filter_map(|device| device.name().ok())
+        .collect();
+    // Some hosts do not list the default device among the others.
+    if let Some(default) = default_device_name().filter(|name| !names.contains(name)) {
+        names.push(default);
+    }
+    Ok(names)
+}
+
+/// Move the playback to `backend` and apply the EQ profile of the new device.
+fn switch_output(app: &AppHandle, backend: OutputBackend) -> Result<(), PlayerError> {
+    let state = app.state::<AudioState>();
+    let device = {
+        let mut current = state.output_device.lock().unwrap();
+        let info = state.engine.switch(backend)?;
+        *current = info.device.clone();
+        *state.output_sample_rate.lock().unwrap() = info.sample_rate;
+        info.device
+    };
+    if cfg!(debug_assertions) {
+        println!("[DEBUG] Playing on {:?}", device);
+    }
+
+    if let Err(e) = equalizer::apply_device_eq_profile(app) {
+        if cfg!(debug_assertions) {
+            println!("[DEBUG] {}", e);
+        }
+    }
+    let _ = app.emit("output-device-changed", OutputChanged { device });
+    Ok(())
+}
+
+/// Where the playback should go, if not where it is: the preferred device when it is
+/// there, else the current one while it stays, else the default one, else nowhere.
+fn wanted_output(
+    preferred: Option<&String>,
+    current: Option<&String>,
+    available: &[String],
+) -> Option<OutputBackend> {
+    if let Some(preferred) = preferred.filter(|name| available.contains(name)) {
+        return (current != Some(preferred)).then(|| OutputBackend::Named(preferred.clone()));
+    }
+    match current {
+        Some(current) if available.contains(current) => None,
+        _ if !available.is_empty() => Some(OutputBackend::Default),
+        Some(_) => Some(OutputBackend::Null { speed: 1. }),
+        None => None,
+    }
+}
+
+/// Where to move the playback, and whether that brings back the lost device.
+fn next_output(
+    settings: &DeviceSettings,
+    current: Option<&String>,
+    available: &[String],
+) -> Option<(OutputBackend, bool)> {
+    let lost = settings.lost.as_ref().map(|lost| &lost.name);
+    // Without a preference, the lost device is the one to go back to.
+    let preferred = settings.preferred.as_ref().or(lost);
+    let backend = wanted_output(preferred, current, available)?;
+    let back = matches!(&backend, OutputBackend::Named(name) if lost == Some(name));
+    Some((backend, back))
+}
+
+/// Pause rather than carry on through another device, e.g. the speakers
+/// once the headphones are unplugged.
+fn on_device_lost(app: &AppHandle, device: String) {
+    let state = app.state::<AudioState>();
+    let playing = state
+        .engine
+        .status()
+        .is_ok_and(|status| !status.paused && !status.empty);
+    if playing {
+        song_player::pause_playback(&state);
+        let progress = song_player::current_position(&state);
+        if let Err(e) = system::set_system_status(&app.state::<PlayerControls>(), true, progress) {
+            if cfg!(debug_assertions) {
+                println!("[DEBUG] Failed to update the media controls: {}", e);
+            }
+        }
+    }
+
+    let settings = app.state::<OutputDeviceState>();
+    let mut settings = settings.0.lock().unwrap();
+    settings.device_lost(device.clone(), playing);
+    drop(settings);
+    let _ = app.emit(
+        "device-lost",
+        DeviceLost {
+            device,
+            paused: playing,
+        },
+    );
+}
+
+/// The lost device is in use again: resume if the user asked for it.
+fn on_device_back(app: &AppHandle) {
+    let settings = app.state::<OutputDeviceState>();
+    let resume = settings.0.lock().unwrap().device_back();
+    if resume {
+        song_player::resume_playback(&app.state::<AudioState>());
+    }
+}
+
+/// Compare the devices with the one in use, and switch if needed.
+fn follow_devices(app: &AppHandle) {
+    let Ok(available) = device_names() else {
+        return;
+    };
+    let current = app
+        .state::<AudioState>()
+        .output_device
+        .lock()
+        .unwrap()
+        .clone();
+    if let Some(current) = current.clone().filter(|name| !available.contains(name)) {
+        on_device_lost(app, current);
+    }
+
+    let next = {
+        let settings = app.state::<OutputDeviceState>();
+        let settings = settings.0.lock().unwrap();
+        next_output(&settings, current.as_ref(), &available)
+    };
+    let Some((backend, back)) = next else {
+        return;
+    };
+
+    let no_device = matches!(backend, OutputBackend::Null { .. });
+    match switch_output(app, backend) {
+        Ok(()) if no_device => song_player::report_device_unavailable(
+            app,
+            PlayerError::NoOutputDevice {
+                device: None,
+                reason: "No output device left".to_string(),
+            },
+        ),
+        Ok(()) if back => on_device_back(app),
+        Ok(()) => {}
+        Err(e) => {
+            if cfg!(debug_assertions) {
+                println!("[DEBUG] {}", e);
+            }
+        }
+    }
+}
+
+/// Load the preferred device, then keep following the devices: fall back to the default
+/// one when the device in use disappears, and go back to the preferred one when it returns.
+pub fn spawn_device_watcher(app_handle: AppHandle) {
+    // The null output was asked for explicitly.
+    if matches!(song_player::requested_backend(), OutputBackend::Null { .. }) {
+        return;
+    }
+    match read_config::<OutputPreference>(&app_handle, PREFERENCE_FILE) {
+        Ok(preference) => {
+            let settings = app_handle.state::<OutputDeviceState>();
+            let mut settings = settings.0.lock().unwrap();
+            settings.preferred = preference.device;
+            settings.resume_on_reconnect = preference.resume_on_reconnect;
+        }
+        Err(e) => {
+            if cfg!(debug_assertions) {
+                println!("[DEBUG] {}", e);
+            }
+        }
+    }
+
+    std::thread::spawn(move || loop {
+        follow_devices(&app_handle);
+        std::thread::sleep(WATCH_INTERVAL);
+    });
+}
+
+#[tauri::command]
+pub fn list_output_devices(
+    state: State<'_, AudioState>,
+    preference: State<'_, OutputDeviceState>,
+) -> Result<Vec<OutputDevice>, PlayerError> {
+    let default = default_device_name();
+    let active = state.output_device.lock().unwrap().clone();
+    let preferred = preference.0.lock().unwrap().preferred.clone();
+    Ok(device_names()?
+        .into_iter()
+        .map(|name| OutputDevice {
+            is_default: default.as_ref() == Some(&name),
+            active: active.as_ref() == Some(&name),
+            preferred: preferred.as_ref() == Some(&name),
+            name,
+        })
+        .collect())
+}
+
+/// Play on the device named `device`, or on the default one with `None`.
+/// The choice is remembered, and restored whenever the device is plugged back.
+#[tauri::command]
+pub fn set_output_device(
+    device: Option<String>,
+    app_handle: AppHandle,
+    preference: State<'_, OutputDeviceState>,
+) -> Result<(), PlayerError> {
+    let backend = match &device {
+        Some(name) if !device_names()?.contains(name) => {
+            return Err(PlayerError::NoOutputDevice {
+                device: Some(name.clone()),
+                reason: "No such device".to_string(),
+            });
+        }
+        Some(name) => OutputBackend::Named(name.clone()),
+        None => OutputBackend::Default,
+    };
+    switch_output(&app_handle, backend)?;
+
+    let resume_on_reconnect = {
+        let mut settings = preference.0.lock().unwrap();
+        settings.preferred = device.clone();
+        // The user moved on from the lost device.
+        settings.lost = None;
+        settings.resume_on_reconnect
+    };
+    write_config(
+        &app_handle,
+        PREFERENCE_FILE,
+        &OutputPreference {
+            device,
+            resume_on_reconnect,
+        },
+    )
+}
+
+/// Whether to resume the playback paused by a device loss when the device is plugged back.
+#[tauri::command]
+pub fn set_resume_on_reconnect(
+    enabled: bool,
+    app_handle: AppHandle,
+    preference: State<'_, OutputDeviceState>,
+) -> Result<(), PlayerError> {
+    let device = {
+        let mut settings = preference.0.lock().unwrap();
+        settings.resume_on_reconnect = enabled;
+        settings.preferred.clone()
+    };
+    write_config(
+        &app_handle,
+        PREFERENCE_FILE,
+        &OutputPreference {
+            device,
+            resume_on_reconnect: enabled,
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{next_output, wanted_output, DeviceSettings};
+    use crate::utils::song::audio_engine::OutputBackend;
+
+    #[test]
+    fn wanted_output_falls_back_and_switches_back() {
+        let (speakers, usb) = ("Speakers".to_string(), "USB".to_string());
+        let named = |name: &String| Some(OutputBackend::Named(name.clone()));
+        let default = Some(OutputBackend::Default);
+        let null = Some(OutputBackend::Null { speed: 1. });
+        let both = [speakers.clone(), usb.clone()];
+        let cases = [
+            // The preferred device is back while playing on another one.
+            (Some(&usb), Some(&speakers), &both[..], named(&usb)),
+            (Some(&usb), Some(&usb), &both[..], None),
+            // The preferred device is missing: stay on the current one.
+            (Some(&usb), Some(&speakers), &both[..1], None),
+            (None, Some(&usb), &both[..], None),
+            // The current device is gone, others are there.
+            (None, Some(&usb), &both[..1], default.clone()),
+            (Some(&usb), Some(&usb), &both[..1], default.clone()),
+            // The last device is gone.
+            (None, Some(&usb), &[][..], null.clone()),
+            (Some(&usb), Some(&usb), &[][..], null),
+            // Started on the null output.
+            (None, None, &both[..], default),
+            (Some(&usb), None, &both[..], named(&usb)),
+            (None, None, &[][..], None),
+        ];
+        for (preferred, current, available, wanted) in cases {
+            assert_eq!(
+                wanted_output(preferred, current, available),
+                wanted,
+                "preferred {:?}, on {:?}, among {:?}",
+                preferred,
+                current,
+                available
+            );
+        }
+    }
+
+    #[test]
+    fn goes_back_to_the_lost_device_and_resumes() {
+        let (speakers, headphones) = ("Speakers".to_string(), "Headphones".to_string());
+        let plugged = [speakers.clone(), headphones.clone()];
+        let unplugged = [speakers.clone()];
+        let mut settings = DeviceSettings {
+            resume_on_reconnect: true,
+            ..Default::default()
+        };
+        assert_eq!(next_output(&settings, Some(&headphones), &plugged), None);
+
+        // Unplugged while playing: fall back to the default device.
+        settings.device_lost(headphones.clone(), true);
+        assert_eq!(
+            next_output(&settings, Some(&headphones), &unplugged),
+            Some((OutputBackend::Default, false))
+        );
+        assert_eq!(next_output(&settings, Some(&speakers), &unplugged), None);
+        // Still the headphones to go back to once the speakers are gone too.
+        settings.device_lost(speakers.clone(), false);
+        assert_eq!(
+            next_output(&settings, Some(&speakers), &[]),
+            Some((OutputBackend::Null { speed: 1. }, false))
+        );
+
+        // Plugged back: switch to them and resume, once.
+        assert_eq!(
+            next_output(&settings, None, &plugged),
+            Some((OutputBackend::Named(headphones.clone()), true))
+        );
+        assert!(settings.device_back());
+        assert!(!settings.device_back());
+        assert_eq!(next_output(&settings, Some(&headphones), &plugged), None);
+
+        // Not resumed when the loss did not pause the playback, or when not asked for.
+        settings.device_lost(headphones.clone(), false);
+        assert!(!settings.device_back());
+        settings.resume_on_reconnect = false;
+        settings.device_lost(headphones.clone(), true);
+        assert!(!settings.device_back());
+
+        // A preferred device other than the lost one is not a return.
+        settings.preferred = Some(speakers.clone());
+        settings.device_lost(headphones.clone(), true);
+        assert_eq!(
+            next_output(&settings, Some(&headphones), &unplugged),
+            Some((OutputBackend::Named(speakers), false))
+        );
+    }
+}
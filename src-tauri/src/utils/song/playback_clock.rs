@@ -0,0 +1,108 @@
+use crate::utils::song::player_error::PlayerError;
+use crate::utils::song::song_player::{self, AudioState};
+use crate::utils::system::{self, PlayerControls};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
+use tauri::{AppHandle, Emitter, Manager, State};
+
+const DEFAULT_INTERVAL: Duration = Duration::from_millis(250);
+const MIN_INTERVAL: Duration = Duration::from_millis(16);
+const MAX_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Sent by the clock while playing, and right after a seek, a pause or a track change.
+#[derive(serde::Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct PlaybackProgress {
+    pub track_id: u64,
+    /// Media time, in seconds.
+    pub position: f64,
+    pub duration: f64,
+    pub paused: bool,
+}
+
+/// Paces the `playback-progress` events.
+pub struct PlaybackClock {
+    interval_millis: AtomicU64,
+    /// Set when an event should be sent right away.
+    pending: Mutex<bool>,
+    wake: Condvar,
+}
+
+impl Default for PlaybackClock {
+    fn default() -> Self {
+        PlaybackClock {
+            interval_millis: AtomicU64::new(DEFAULT_INTERVAL.as_millis() as u64),
+            pending: Mutex::new(false),
+            wake: Condvar::new(),
+        }
+    }
+}
+
+impl PlaybackClock {
+    /// Send the progress now instead of at the next tick.
+    pub fn notify(&self) {
+        *self.pending.lock().unwrap() = true;
+        self.wake.notify_one();
+    }
+
+    /// Wait for the next tick. Return true when woken up by `notify`.
+    fn wait(&self) -> bool {
+        let interval = Duration::from_millis(self.interval_millis.load(Ordering::Relaxed));
+        let pending = self.pending.lock().unwrap();
+        let (mut pending, _) = self
+            .wake
+            .wait_timeout_while(pending, interval, |pending| !*pending)
+            .unwrap();
+        std::mem::take(&mut *pending)
+    }
+}
+
+/// Emit `playback-progress` and keep the position of the system media controls up to date,
+/// so that the frontend does not have to poll.
+/// Nothing is sent while paused, apart from the event telling so.
+pub fn spawn_playback_clock(app_handle: AppHandle) {
+    std::thread::spawn(move || loop {
+        let state = app_handle.state::<AudioState>();
+        let notified = state.clock.wait();
+        let progress = song_player::playback_progress(&state);
+        system::update_sleep_inhibitor(&app_handle, progress.as_ref().is_some_and(|p| !p.paused));
+        let Some(progress) = progress else {
+            continue;
+        };
+        if progress.paused && !notified {
+            continue;
+        }
+
+        if let Some(controls) = app_handle.try_state::<PlayerControls>() {
+            if let Err(e) = system::set_system_status(&controls, progress.paused, progress.position)
+            {
+                if cfg!(debug_assertions) {
+                    println!("[DEBUG] Failed to update the media controls: {}", e);
+                }
+            }
+        }
+        let _ = app_handle.emit("playback-progress", progress);
+    });
+}
+
+/// Set how often `playback-progress` is sent while playing, in milliseconds.
+#[tauri::command]
+pub fn set_progress_interval(
+    interval: u64,
+    state: State<'_, AudioState>,
+) -> Result<(), PlayerError> {
+    let range = MIN_INTERVAL.as_millis() as u64..=MAX_INTERVAL.as_millis() as u64;
+    if !range.contains(&interval) {
+        return Err(PlayerError::invalid(format!(
+            "Invalid progress interval: {} ms",
+            interval
+        )));
+    }
+    state
+        .clock
+        .interval_millis
+        .store(interval, Ordering::Relaxed);
+    state.clock.notify();
+    Ok(())
+}
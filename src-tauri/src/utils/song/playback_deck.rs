@@ -0,0 +1,696 @@
+use crate::utils::song::replay_gain::{ReplayGain, ReplayGainSettings};
+use rodio::source::{SeekError, UniformSourceIterator};
+use rodio::{ChannelCount, Sample, SampleRate, Source};
+use std::f32::consts::FRAC_PI_2;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How many frames are played between two checks of the command channel.
+/// About 5 ms at 48 kHz, like the `Sink` controls.
+const COMMAND_POLL_FRAMES: usize = 256;
+
+/// The longest crossfade allowed.
+pub const MAX_CROSSFADE: Duration = Duration::from_secs(12);
+
+/// Length of the fades around the jump from B back to A, so that it does not click.
+const LOOP_DECLICK_FRAMES: u64 = 96;
+
+/// A region of the current track played over and over (A-B loop).
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct LoopRegion {
+    /// Points A and B, in seconds.
+    pub start: f64,
+    pub end: f64,
+    /// Silence before every repetition, in seconds.
+    #[serde(default)]
+    pub count_in: f64,
+    /// Playback rate taken off after every repetition, e.g. 0.05. Negative speeds up.
+    #[serde(default)]
+    pub slowdown: f32,
+    /// Pitch shift while looping, in semitones.
+    #[serde(default)]
+    pub semitones: f32,
+}
+
+/// A decoded track, converted to the output format of the deck.
+pub struct DeckTrack {
+    pub id: u64,
+    pub path: String,
+    pub total_duration: Option<Duration>,
+    /// How long this track overlaps with the one before it. Zero means gapless.
+    crossfade: Duration,
+    replay_gain: Option<ReplayGain>,
+    /// Whether the track is played among tracks of its album.
+    album_sequence: bool,
+    /// Linear gain computed from `replay_gain` by the deck.
+    gain: f32,
+    source: UniformSourceIterator<Box<dyn Source + Send>>,
+}
+
+impl DeckTrack {
+    pub fn new(
+        id: u64,
+        path: String,
+        source: Box<dyn Source + Send>,
+        channels: ChannelCount,
+        sample_rate: SampleRate,
+    ) -> Self {
+        DeckTrack {
+            id,
+            path,
+            total_duration: source.total_duration(),
+            crossfade: Duration::ZERO,
+            replay_gain: None,
+            album_sequence: false,
+            gain: 1.,
+            source: UniformSourceIterator::new(source, channels, sample_rate),
+        }
+    }
+
+    pub fn with_replay_gain(
+        mut self,
+        replay_gain: Option<ReplayGain>,
+        album_sequence: bool,
+    ) -> Self {
+        self.replay_gain = replay_gain;
+        self.album_sequence = album_sequence;
+        self
+    }
+
+    fn apply_replay_gain(&mut self, settings: &ReplayGainSettings) {
+        self.gain = settings.gain_factor(self.replay_gain.as_ref(), self.album_sequence);
+    }
+
+    pub fn with_crossfade(mut self, crossfade: Duration) -> Self {
+        self.crossfade = crossfade.min(MAX_CROSSFADE);
+        self
+    }
+}
+
+pub enum DeckCommand {
+    /// Set the track to play right after the current one.
+    Enqueue(Box<DeckTrack>),
+    /// Forget the track set by `Enqueue`.
+    ClearNext,
+    /// Fade from the current track into this one right now.
+    CrossfadeTo(Box<DeckTrack>),
+    /// Recompute the gain of the tracks held by the deck.
+    SetReplayGain(ReplayGainSettings),
+    /// Loop a region of the current track, or stop looping.
+    SetLoop(Option<LoopRegion>),
+    /// Go on with `source`, opened again at `position`, if track `id` is still the current one.
+    Reopen {
+        id: u64,
+        source: Box<dyn Source + Send>,
+        position: Duration,
+    },
+    /// End track `id` now if it is the current one, as if it had played to the end.
+    Finish(u64),
+}
+
+/// Sent from the audio thread at the exact sample a track starts or ends.
+/// `id` is the one given to the track by `DeckTrack::new`.
+pub enum DeckEvent {
+    TrackStarted {
+        id: u64,
+        path: String,
+        total_duration: Option<Duration>,
+    },
+    TrackFinished {
+        id: u64,
+        path: String,
+    },
+    /// Playback jumped back to the start of the loop region.
+    LoopIteration {
+        id: u64,
+        path: String,
+        iteration: u32,
+    },
+}
+
+/// Values published by the audio thread.
+#[derive(Default)]
+struct DeckStatus {
+    position_micros: AtomicU64,
+}
+
+/// Used by the commands to talk to a deck playing inside the `Sink`.
+pub struct DeckHandle {
+    commands: Sender<DeckCommand>,
+    status: Arc<DeckStatus>,
+}
+
+impl DeckHandle {
+    pub fn enqueue(&self, track: DeckTrack) {
+        let _ = self.commands.send(DeckCommand::Enqueue(Box::new(track)));
+    }
+
+    pub fn clear_next(&self) {
+        let _ = self.commands.send(DeckCommand::ClearNext);
+    }
+
+    pub fn crossfade_to(&self, track: DeckTrack) {
+        let _ = self
+            .commands
+            .send(DeckCommand::CrossfadeTo(Box::new(track)));
+    }
+
+    pub fn set_replay_gain(&self, settings: ReplayGainSettings) {
+        let _ = self.commands.send(DeckCommand::SetReplayGain(settings));
+    }
+
+    pub fn set_loop(&self, region: Option<LoopRegion>) {
+        let _ = self.commands.send(DeckCommand::SetLoop(region));
+    }
+
+    pub fn reopen(&self, id: u64, source: Box<dyn Source + Send>, position: Duration) {
+        let _ = self.commands.send(DeckCommand::Reopen {
+            id,
+            source,
+            position,
+        });
+    }
+
+    pub fn finish(&self, id: u64) {
+        let _ = self.commands.send(DeckCommand::Finish(id));
+    }
+
+    /// Position in the track being played.
+    pub fn position(&self) -> Duration {
+        Duration::from_micros(self.status.position_micros.load(Ordering::Relaxed))
+    }
+}
+
+/// The track being faded out during a crossfade.
+struct FadeOut {
+    track: DeckTrack,
+    frames: u64,
+    frame: u64,
+    channel: ChannelCount,
+}
+
+/// The A-B loop of the current track.
+struct ActiveLoop {
+    region: LoopRegion,
+    iteration: u32,
+    /// Samples left before B.
+    remaining: u64,
+    /// Samples of the count-in left to play.
+    silence: u64,
+}
+
+/// A source playing tracks back to back without any gap, or overlapping them
+/// when the next track asks for a crossfade.
+///
+/// The next track is handed over before the current one ends, so the switch happens
+/// on the very next sample. The deck ends when it runs out of tracks.
+pub struct PlaybackDeck {
+    current: Option<DeckTrack>,
+    next: Option<DeckTrack>,
+    fading: Option<FadeOut>,
+    looping: Option<ActiveLoop>,
+    replay_gain: ReplayGainSettings,
+    commands: Receiver<DeckCommand>,
+    events: Sender<DeckEvent>,
+    status: Arc<DeckStatus>,
+    channels: ChannelCount,
+    sample_rate: SampleRate,
+    /// Position of the current track when `samples_played` was reset (i.e. after a seek).
+    offset: Duration,
+    samples_played: u64,
+    countdown: usize,
+}
+
+/// Create a deck starting with `first`, reporting to `event_tx`.
+pub fn new_deck(
+    mut first: DeckTrack,
+    channels: ChannelCount,
+    sample_rate: SampleRate,
+    replay_gain: ReplayGainSettings,
+    event_tx: Sender<DeckEvent>,
+) -> (PlaybackDeck, DeckHandle) {
+    let (command_tx, command_rx) = mpsc::channel();
+    let status = Arc::new(DeckStatus::default());
+
+    let _ = event_tx.send(DeckEvent::TrackStarted {
+        id: first.id,
+        path: first.path.clone(),
+        total_duration: first.total_duration,
+    });
+
+    first.apply_replay_gain(&replay_gain);
+    let deck = PlaybackDeck {
+        current: Some(first),
+        next: None,
+        fading: None,
+        looping: None,
+        replay_gain,
+        commands: command_rx,
+        events: event_tx,
+        status: status.clone(),
+        channels,
+        sample_rate,
+        offset: Duration::ZERO,
+        samples_played: 0,
+        countdown: 0,
+    };
+    let handle = DeckHandle {
+        commands: command_tx,
+        status,
+    };
+    (deck, handle)
+}
+
+impl PlaybackDeck {
+    fn poll_commands(&mut self) {
+        while let Ok(command) = self.commands.try_recv() {
+            match command {
+                DeckCommand::Enqueue(mut track) => {
+                    track.apply_replay_gain(&self.replay_gain);
+                    self.next = Some(*track);
+                }
+                DeckCommand::ClearNext => self.next = None,
+                DeckCommand::CrossfadeTo(mut track) => {
+                    track.apply_replay_gain(&self.replay_gain);
+                    let length = track
+                        .crossfade
+                        .min(self.remaining().unwrap_or(MAX_CROSSFADE));
+                    self.start_crossfade(*track, length);
+                }
+                DeckCommand::SetReplayGain(settings) => {
+                    self.replay_gain = settings;
+                    let fading = self.fading.as_mut().map(|fade| &mut fade.track);
+                    for track in [self.current.as_mut(), self.next.as_mut(), fading]
+                        .into_iter()
+                        .flatten()
+                    {
+                        track.apply_replay_gain(&settings);
+                    }
+                }
+                DeckCommand::SetLoop(region) => self.set_loop(region),
+                DeckCommand::Reopen {
+                    id,
+                    source,
+                    position,
+                } => {
+                    let (channels, sample_rate) = (self.channels, self.sample_rate);
+                    let Some(current) = self.current.as_mut().filter(|track| track.id == id) else {
+                        continue;
+                    };
+                    current.source = UniformSourceIterator::new(source, channels, sample_rate);
+                    self.moved_to(position);
+                }
+                DeckCommand::Finish(id) => {
+                    if self.current.as_ref().is_some_and(|track| track.id == id) {
+                        self.switch_track();
+                    }
+                }
+            }
+        }
+    }
+
+    /// Time left in the current track, if its duration is known.
+    fn remaining(&self) -> Option<Duration> {
+        let total = self.current.as_ref()?.total_duration?;
+        Some(total.saturating_sub(self.current_position()))
+    }
+
+    /// Start the crossfade once the current track is close enough to its end.
+    fn check_crossfade(&mut self) {
+        let Some(next) = self.next.as_ref() else {
+            return;
+        };
+        if next.crossfade.is_zero() || self.fading.is_some() {
+            return;
+        }
+        match self.remaining() {
+            Some(remaining) if remaining <= next.crossfade => {
+                let next = self.next.take().unwrap();
+                self.start_crossfade(next, remaining);
+            }
+            _ => {}
+        }
+    }
+
+    /// Number of samples played in `seconds`, rounded to the nearest frame.
+    fn samples_in(&self, seconds: f64) -> u64 {
+        (seconds.max(0.) * self.sample_rate as f64).round() as u64 * self.channels as u64
+    }
+
+    fn set_loop(&mut self, region: Option<LoopRegion>) {
+        self.looping = region.map(|region| ActiveLoop {
+            region,
+            iteration: 0,
+            remaining: 0,
+            silence: 0,
+        });
+        let Some(region) = region else {
+            return;
+        };
+
+        let position = self.current_position().as_secs_f64();
+        if position < region.start || position >= region.end {
+            self.loop_back(false);
+        } else {
+            let remaining = self.samples_in(region.end - position);
+            if let Some(active) = self.looping.as_mut() {
+                active.remaining = remaining;
+            }
+        }
+    }
+
+    /// Jump to A. `repeat` is false when entering the loop from outside of it.
+    fn loop_back(&mut self, repeat: bool) {
+        let Some(region) = self.looping.as_ref().map(|active| active.region) else {
+            return;
+        };
+        let remaining = self.samples_in(region.end - region.start);
+        let count_in = self.samples_in(region.count_in);
+        let (Some(current), Some(active)) = (self.current.as_mut(), self.looping.as_mut()) else {
+            return;
+        };
+
+        let start = Duration::from_secs_f64(region.start);
+        if let Err(e) = current.source.try_seek(start) {
+            if cfg!(debug_assertions) {
+                println!("[DEBUG] Stop looping, failed to seek: {}", e);
+            }
+            self.looping = None;
+            return;
+        }
+        active.remaining = remaining;
+        if repeat {
+            active.iteration += 1;
+            active.silence = count_in;
+            let _ = self.events.send(DeckEvent::LoopIteration {
+                id: current.id,
+                path: current.path.clone(),
+                iteration: active.iteration,
+            });
+        }
+        self.offset = start;
+        self.samples_played = 0;
+        self.publish_position();
+    }
+
+    /// Make `track` the current one, fading the previous one out over `length`.
+    fn start_crossfade(&mut self, track: DeckTrack, length: Duration) {
+        self.looping = None;
+        if let Some(fade) = self.fading.take() {
+            let _ = self.events.send(DeckEvent::TrackFinished {
+                id: fade.track.id,
+                path: fade.track.path,
+            });
+        }
+
+        if let Some(outgoing) = self.current.take() {
+            let frames = (length.as_secs_f64() * self.sample_rate as f64) as u64;
+            self.fading = Some(FadeOut {
+                track: outgoing,
+                frames: frames.max(1),
+                frame: 0,
+                channel: 0,
+            });
+        }
+
+        let _ = self.events.send(DeckEvent::TrackStarted {
+            id: track.id,
+            path: track.path.clone(),
+            total_duration: track.total_duration,
+        });
+        self.current = Some(track);
+        self.offset = Duration::ZERO;
+        self.samples_played = 0;
+        self.publish_position();
+    }
+
+    /// Next sample of the outgoing track with its gain applied, and the gain to apply
+    /// to the incoming one. Equal-power curves keep the loudness steady during the fade.
+    fn fade_step(&mut self) -> (Sample, f32) {
+        let channels = self.channels;
+        let Some(fade) = self.fading.as_mut() else {
+            return (0.0, 1.0);
+        };
+
+        let angle = (fade.frame as f32 / fade.frames as f32).min(1.0) * FRAC_PI_2;
+        let sample = fade.track.source.next();
+        fade.channel += 1;
+        if fade.channel == channels {
+            fade.channel = 0;
+            fade.frame += 1;
+        }
+
+        match sample {
+            Some(sample) if fade.frame < fade.frames => {
+                (sample * fade.track.gain * angle.cos(), angle.sin())
+            }
+            _ => {
+                if let Some(fade) = self.fading.take() {
+                    let _ = self.events.send(DeckEvent::TrackFinished {
+                        id: fade.track.id,
+                        path: fade.track.path,
+                    });
+                }
+                (0.0, 1.0)
+            }
+        }
+    }
+
+    fn current_position(&self) -> Duration {
+        let frames = self.samples_played / self.channels as u64;
+        self.offset + Duration::from_secs_f64(frames as f64 / self.sample_rate as f64)
+    }
+
+    fn publish_position(&self) {
+        self.status.position_micros.store(
+            self.current_position().as_micros() as u64,
+            Ordering::Relaxed,
+        );
+    }
+
+    /// The current track is exhausted: switch to the next one.
+    fn switch_track(&mut self) {
+        self.looping = None;
+        if let Some(finished) = self.current.take() {
+            let _ = self.events.send(DeckEvent::TrackFinished {
+                id: finished.id,
+                path: finished.path,
+            });
+        }
+
+        // The next track may have been sent during the last few samples.
+        self.poll_commands();
+        self.current = self.next.take();
+        self.offset = Duration::ZERO;
+        self.samples_played = 0;
+        self.publish_position();
+
+        if let Some(track) = &self.current {
+            let _ = self.events.send(DeckEvent::TrackStarted {
+                id: track.id,
+                path: track.path.clone(),
+                total_duration: track.total_duration,
+            });
+        }
+    }
+
+    /// The current track was moved to `pos`, by a seek or by opening it again.
+    fn moved_to(&mut self, pos: Duration) {
+        // Seeking ends the crossfade right away.
+        if let Some(fade) = self.fading.take() {
+            let _ = self.events.send(DeckEvent::TrackFinished {
+                id: fade.track.id,
+                path: fade.track.path,
+            });
+        }
+        self.offset = pos;
+        self.samples_played = 0;
+        let remaining = self
+            .looping
+            .as_ref()
+            .map(|active| self.samples_in(active.region.end - pos.as_secs_f64()));
+        if let (Some(active), Some(remaining)) = (self.looping.as_mut(), remaining) {
+            active.remaining = remaining;
+            active.silence = 0;
+        }
+        self.publish_position();
+    }
+}
+
+impl Iterator for PlaybackDeck {
+    type Item = Sample;
+
+    fn next(&mut self) -> Option<Sample> {
+        // Polling on frame boundaries, so that a crossfade starts on one.
+        if self.countdown == 0 {
+            self.poll_commands();
+            if self.looping.is_none() {
+                self.check_crossfade();
+            }
+            self.publish_position();
+            self.countdown = COMMAND_POLL_FRAMES * self.channels as usize;
+        }
+        self.countdown -= 1;
+
+        let (faded, gain) = self.fade_step();
+        loop {
+            if self
+                .looping
+                .as_ref()
+                .is_some_and(|active| active.remaining == 0)
+            {
+                self.loop_back(true);
+            }
+            if let Some(active) = self.looping.as_mut().filter(|active| active.silence > 0) {
+                active.silence -= 1;
+                return Some(faded);
+            }
+
+            let Some(current) = self.current.as_mut() else {
+                // Only the tail of the outgoing track is left.
+                return self.fading.is_some().then_some(faded);
+            };
+            if let Some(sample) = current.source.next() {
+                self.samples_played += 1;
+                let mut declick = 1.;
+                if let Some(active) = self.looping.as_mut() {
+                    active.remaining -= 1;
+                    let channels = self.channels as u64;
+                    let frames = (active.remaining / channels).min(self.samples_played / channels);
+                    declick = (frames as f32 / LOOP_DECLICK_FRAMES as f32).min(1.);
+                }
+                return Some(sample * current.gain * gain * declick + faded);
+            }
+
+            match self.looping.as_mut() {
+                // B is past the end of the track.
+                Some(active) if self.samples_played > 0 => active.remaining = 0,
+                _ => self.switch_track(),
+            }
+        }
+    }
+}
+
+impl Source for PlaybackDeck {
+    fn current_span_len(&self) -> Option<usize> {
+        // Every track is converted to the same format.
+        None
+    }
+
+    fn channels(&self) -> ChannelCount {
+        self.channels
+    }
+
+    fn sample_rate(&self) -> SampleRate {
+        self.sample_rate
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+
+    fn try_seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+        if let Some(current) = self.current.as_mut() {
+            current.source.try_seek(pos)?;
+        }
+        self.moved_to(pos);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{new_deck, DeckEvent, DeckTrack, LoopRegion, PlaybackDeck, LOOP_DECLICK_FRAMES};
+    use crate::utils::song::replay_gain::ReplayGainSettings;
+    use rodio::buffer::SamplesBuffer;
+    use std::sync::mpsc::{self, Receiver};
+    use std::time::Duration;
+
+    const RATE: u32 = 1000;
+
+    fn track(id: u64, samples: Vec<f32>) -> DeckTrack {
+        let source = SamplesBuffer::new(1, RATE, samples);
+        DeckTrack::new(id, id.to_string(), Box::new(source), 1, RATE)
+    }
+
+    fn deck(first: DeckTrack) -> (PlaybackDeck, super::DeckHandle, Receiver<DeckEvent>) {
+        let (events, receiver) = mpsc::channel();
+        let (deck, handle) = new_deck(first, 1, RATE, ReplayGainSettings::default(), events);
+        (deck, handle, receiver)
+    }
+
+    /// Gains of the outgoing and the incoming track, sample by sample.
+    fn crossfade_gains(outgoing: f32, incoming: f32) -> Vec<f32> {
+        let (deck, handle, _events) = deck(track(1, vec![outgoing; RATE as usize]));
+        handle.crossfade_to(
+            track(2, vec![incoming; RATE as usize]).with_crossfade(Duration::from_millis(100)),
+        );
+        deck.take(200).collect()
+    }
+
+    #[test]
+    fn crossfade_keeps_the_power() {
+        let outgoing = crossfade_gains(1., 0.);
+        let incoming = crossfade_gains(0., 1.);
+        assert_eq!((outgoing[0], incoming[0]), (1., 0.));
+        for (i, (a, b)) in outgoing.iter().zip(&incoming).enumerate() {
+            assert!((a * a + b * b - 1.).abs() < 1e-5, "{}: {} {}", i, a, b);
+        }
+        assert!(outgoing.windows(2).all(|pair| pair[1] <= pair[0]));
+        // Over after 100 ms.
+        assert_eq!((outgoing[100], incoming[100]), (0., 1.));
+    }
+
+    /// Loops over `region` of a track whose samples hold their index, for `samples`.
+    fn play_loop(region: (f64, f64), samples: usize) -> (Vec<f32>, Vec<u32>) {
+        let (deck, handle, events) = deck(track(1, (0..RATE).map(|i| i as f32).collect()));
+        handle.set_loop(Some(LoopRegion {
+            start: region.0,
+            end: region.1,
+            count_in: 0.,
+            slowdown: 0.,
+            semitones: 0.,
+        }));
+        let output = deck.take(samples).collect();
+        let iterations = events
+            .try_iter()
+            .filter_map(|event| match event {
+                DeckEvent::LoopIteration { iteration, .. } => Some(iteration),
+                _ => None,
+            })
+            .collect();
+        (output, iterations)
+    }
+
+    #[test]
+    fn loop_goes_from_a_to_b() {
+        let (output, iterations) = play_loop((0.5, 0.7), 600);
+        assert_eq!(iterations, [1, 2]);
+        for (i, sample) in output.iter().enumerate() {
+            // 200 samples from A, faded in after A and out before B.
+            let j = i % 200;
+            let declick = ((199 - j).min(j + 1) as f32 / LOOP_DECLICK_FRAMES as f32).min(1.);
+            let expected = (500 + j) as f32 * declick;
+            assert!(
+                (sample - expected).abs() < 1e-3,
+                "{}: {} != {}",
+                i,
+                sample,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn loop_past_the_end_of_the_track() {
+        let (output, iterations) = play_loop((0.9, 1.5), 300);
+        assert_eq!(iterations, [1, 2]);
+        // Back to A right after the last sample.
+        assert_eq!(output[..100], output[100..200]);
+        assert_eq!(output[..100], output[200..]);
+        assert_eq!(output[99], 999.);
+    }
+}
@@ -0,0 +1,592 @@
+use crate::utils::song::player_error::PlayerError;
+use crate::utils::song::sleep_timer;
+use crate::utils::song::song_metadata::{self, AudioMetadata};
+use crate::utils::song::song_player::{self, AudioState};
+use crate::utils::song::track_duration::ResolvedDuration;
+use std::collections::HashSet;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tauri::{AppHandle, Emitter, Manager, State};
+
+/// How the queue picks the track after the current one.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PlaybackMode {
+    /// Play through the queue once and stop at the end.
+    Sequential,
+    /// Play through the queue and wrap around.
+    #[default]
+    RepeatAll,
+    /// Keep playing the current track unless a skip is forced.
+    RepeatOne,
+    /// Pick a random track each time.
+    Shuffle,
+}
+
+/// The track list owned by the backend, so that anything (frontend, media keys, ...)
+/// can advance the playback.
+#[derive(serde::Serialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct PlaybackQueue {
+    tracks: Vec<String>,
+    current: Option<usize>,
+    /// Paths played before the current one, the latest last.
+    history: Vec<String>,
+    mode: PlaybackMode,
+    /// The shuffle pick handed to the deck in advance, so that `next` agrees with it.
+    #[serde(skip)]
+    shuffle_pick: Option<usize>,
+    #[serde(skip)]
+    rng_state: u64,
+}
+
+/// State managed by tauri.
+pub struct QueueState(pub Mutex<PlaybackQueue>);
+
+/// Returned by `queue_add`.
+#[derive(serde::Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct QueueAdded {
+    pub queue: PlaybackQueue,
+    /// Paths not added, being already in the queue or empty.
+    pub skipped: Vec<String>,
+}
+
+#[derive(serde::Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct TrackChanged {
+    /// `None` if the track is not in the queue.
+    pub index: Option<usize>,
+    pub path: String,
+    pub metadata: AudioMetadata,
+}
+
+impl PlaybackQueue {
+    pub fn set_mode(&mut self, mode: PlaybackMode) {
+        self.mode = mode;
+        self.shuffle_pick = None;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tracks.is_empty()
+    }
+
+    /// The index and path of the track being played.
+    pub fn current(&self) -> Option<(usize, &str)> {
+        self.current
+            .and_then(|i| self.tracks.get(i).map(|path| (i, path.as_str())))
+    }
+
+    /// Append tracks. Tracks are found by their path, so a file is in the queue only once:
+    /// the paths already there, or empty, are skipped and returned.
+    pub fn add(&mut self, paths: Vec<String>) -> Vec<String> {
+        let mut known: HashSet<String> = self.tracks.iter().cloned().collect();
+        let mut skipped = Vec::new();
+        for path in paths {
+            if !path.is_empty() && known.insert(path.clone()) {
+                self.tracks.push(path);
+            } else {
+                skipped.push(path);
+            }
+        }
+        skipped
+    }
+
+    /// Remove the tracks at the given indices.
+    /// If the current track is removed, the cursor moves back so that the next
+    /// track is the one that took its place.
+    pub fn remove(&mut self, indices: &[usize]) {
+        let mut sorted = indices.to_vec();
+        sorted.sort_unstable_by(|a, b| b.cmp(a));
+        sorted.dedup();
+
+        for index in sorted {
+            if index >= self.tracks.len() {
+                continue;
+            }
+            self.shuffle_pick = None;
+            let removed = self.tracks.remove(index);
+            self.history.retain(|path| *path != removed);
+            self.current = match self.current {
+                Some(cur) if index < cur => Some(cur - 1),
+                Some(cur) if index == cur => cur.checked_sub(1),
+                other => other,
+            };
+        }
+
+        if self.tracks.is_empty() {
+            self.current = None;
+        }
+    }
+
+    /// Move the track at `from` to `to`, keeping the cursor on the same track.
+    pub fn move_track(&mut self, from: usize, to: usize) -> Result<(), PlayerError> {
+        let len = self.tracks.len();
+        if from >= len || to >= len {
+            return Err(PlayerError::invalid(format!(
+                "Queue index out of range: {} -> {} (length {})",
+                from, to, len
+            )));
+        }
+
+        let track = self.tracks.remove(from);
+        self.tracks.insert(to, track);
+        self.shuffle_pick = None;
+
+        self.current = self.current.map(|cur| {
+            if cur == from {
+                to
+            } else if from < cur && cur <= to {
+                cur - 1
+            } else if to <= cur && cur < from {
+                cur + 1
+            } else {
+                cur
+            }
+        });
+        Ok(())
+    }
+
+    /// Point the cursor at `path` if it is in the queue.
+    pub fn focus(&mut self, path: &str) -> Option<usize> {
+        let index = self.tracks.iter().position(|p| p == path)?;
+        if self.current != Some(index) {
+            self.push_history();
+        }
+        self.current = Some(index);
+        self.shuffle_pick = None;
+        Some(index)
+    }
+
+    /// Move to the next track according to the playback mode.
+    /// `forced` skips even in `RepeatOne` mode (e.g. the "next" button).
+    pub fn next(&mut self, forced: bool) -> Option<(usize, String)> {
+        let index = self.next_index(forced)?;
+        if self.current != Some(index) {
+            self.push_history();
+        }
+        self.current = Some(index);
+        self.shuffle_pick = None;
+        Some((index, self.tracks[index].clone()))
+    }
+
+    /// The track that will follow the current one when it ends, without moving the cursor.
+    pub fn peek_next(&mut self) -> Option<(usize, String)> {
+        let index = self.next_index(false)?;
+        Some((index, self.tracks[index].clone()))
+    }
+
+    /// Go back to the last played track still in the queue.
+    /// Without any history, step back in queue order.
+    pub fn previous(&mut self) -> Option<(usize, String)> {
+        while let Some(path) = self.history.pop() {
+            if let Some(index) = self.tracks.iter().position(|p| *p == path) {
+                self.current = Some(index);
+                self.shuffle_pick = None;
+                return Some((index, path));
+            }
+        }
+
+        let len = self.tracks.len();
+        let index = match self.current {
+            None if len > 0 => 0,
+            Some(0) if self.mode == PlaybackMode::Sequential => return None,
+            Some(cur) => (cur + len - 1) % len,
+            None => return None,
+        };
+        self.current = Some(index);
+        self.shuffle_pick = None;
+        Some((index, self.tracks[index].clone()))
+    }
+
+    fn next_index(&mut self, forced: bool) -> Option<usize> {
+        let len = self.tracks.len();
+        if len == 0 {
+            return None;
+        }
+
+        match (self.mode, self.current) {
+            (_, None) => Some(0),
+            (PlaybackMode::RepeatOne, Some(cur)) if !forced => Some(cur),
+            (PlaybackMode::Shuffle, Some(cur)) => {
+                if len == 1 {
+                    return Some(cur);
+                }
+                if let Some(pick) = self.shuffle_pick.filter(|&p| p < len && p != cur) {
+                    return Some(pick);
+                }
+                // Never pick the same track twice in a row.
+                let pick = (self.next_random() % (len as u64 - 1)) as usize;
+                let pick = if pick >= cur { pick + 1 } else { pick };
+                self.shuffle_pick = Some(pick);
+                Some(pick)
+            }
+            (PlaybackMode::Sequential, Some(cur)) => (cur + 1 < len).then_some(cur + 1),
+            (_, Some(cur)) => Some((cur + 1) % len),
+        }
+    }
+
+    fn push_history(&mut self) {
+        if let Some((_, path)) = self.current() {
+            let path = path.to_string();
+            if self.history.last() != Some(&path) {
+                self.history.push(path);
+            }
+        }
+    }
+
+    /// xorshift64*, good enough to shuffle a playlist.
+    fn next_random(&mut self) -> u64 {
+        if self.rng_state == 0 {
+            self.rng_state = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_nanos() as u64)
+                .unwrap_or(0x9E37_79B9_7F4A_7C15)
+                | 1;
+        }
+        let mut x = self.rng_state;
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        self.rng_state = x;
+        x.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+}
+
+/// Play the track the queue stepped to. Unplayable files are dropped from the queue
+/// and the next candidate is tried instead. Other errors, such as a failing output,
+/// are returned as they would fail for every track.
+fn advance<F>(app: &AppHandle, step: F) -> Result<Option<AudioMetadata>, PlayerError>
+where
+    F: Fn(&mut PlaybackQueue) -> Option<(usize, String)>,
+{
+    let queue_state = app.state::<QueueState>();
+    let audio_state = app.state::<AudioState>();
+
+    let mut attempts = queue_state.0.lock().unwrap().tracks.len();
+    while attempts > 0 {
+        attempts -= 1;
+
+        let Some((index, path)) = step(&mut queue_state.0.lock().unwrap()) else {
+            return Ok(None);
+        };
+
+        match song_player::play_file(&path, &audio_state, app) {
+            Ok(metadata) => return Ok(Some(metadata)),
+            Err(e) if !e.is_unplayable() => return Err(e),
+            Err(e) => {
+                if cfg!(debug_assertions) {
+                    println!("[DEBUG] Dropping {} from the queue: {}", path, e);
+                }
+                queue_state.0.lock().unwrap().remove(&[index]);
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+/// Called when the deck starts playing a track, be it loaded or queued.
+/// Sync the cursor, notify the frontend and hand the following track to the deck.
+pub(crate) fn on_track_started(app: &AppHandle, path: String, duration: ResolvedDuration) {
+    let index = app.state::<QueueState>().0.lock().unwrap().focus(&path);
+    let metadata = song_player::with_output_format(
+        &app.state::<AudioState>(),
+        song_metadata::get_metadata(&path, 0.).with_duration(duration),
+    );
+    let _ = app.emit(
+        "track-changed",
+        TrackChanged {
+            index,
+            path,
+            metadata,
+        },
+    );
+
+    prepare_next_track(app);
+}
+
+/// Hand the track that follows the current one to the deck for gapless playback.
+/// Called again whenever the queue changes.
+pub(crate) fn prepare_next_track(app: &AppHandle) {
+    let queue_state = app.state::<QueueState>();
+    let audio_state = app.state::<AudioState>();
+    if sleep_timer::stops_after_current(app) {
+        song_player::clear_next(&audio_state);
+        return;
+    }
+
+    let mut attempts = queue_state.0.lock().unwrap().tracks.len();
+    while attempts > 0 {
+        attempts -= 1;
+
+        let (current, next) = {
+            let mut queue = queue_state.0.lock().unwrap();
+            (
+                queue.current().map(|(_, p)| p.to_string()),
+                queue.peek_next(),
+            )
+        };
+        let Some((index, path)) = next else {
+            break;
+        };
+        let album_sequence = in_album_sequence(app, &path);
+        match song_player::enqueue_next(&path, current.as_deref(), album_sequence, app) {
+            Ok(()) => return,
+            Err(e) if !e.is_unplayable() => {
+                if cfg!(debug_assertions) {
+                    println!("[DEBUG] {}", e);
+                }
+                break;
+            }
+            Err(e) => {
+                if cfg!(debug_assertions) {
+                    println!("[DEBUG] Dropping {} from the queue: {}", path, e);
+                }
+                queue_state.0.lock().unwrap().remove(&[index]);
+            }
+        }
+    }
+
+    song_player::clear_next(&audio_state);
+}
+
+/// Whether the track is next to another track of its album in the queue,
+/// in which case ReplayGain uses the album gain in `Auto` mode.
+pub(crate) fn in_album_sequence(app: &AppHandle, path: &str) -> bool {
+    let neighbours: Vec<String> = {
+        let queue = app.state::<QueueState>();
+        let queue = queue.0.lock().unwrap();
+        let Some(index) = queue.tracks.iter().position(|p| p == path) else {
+            return false;
+        };
+        [index.checked_sub(1), Some(index + 1)]
+            .into_iter()
+            .flatten()
+            .filter_map(|i| queue.tracks.get(i).cloned())
+            .collect()
+    };
+
+    let album_of = |path: &str| song_metadata::get_metadata(&path.to_string(), 0.).album;
+    let Some(album) = album_of(path) else {
+        return false;
+    };
+    neighbours
+        .iter()
+        .any(|neighbour| album_of(neighbour).as_ref() == Some(&album))
+}
+
+/// Skip to the next track. Used by the media keys as well.
+pub fn play_next_in_queue(app: &AppHandle) -> Result<Option<AudioMetadata>, PlayerError> {
+    advance(app, |queue| queue.next(true))
+}
+
+/// Go back to the previous track. Used by the media keys as well.
+pub fn play_previous_in_queue(app: &AppHandle) -> Result<Option<AudioMetadata>, PlayerError> {
+    advance(app, |queue| queue.previous())
+}
+
+/// Get a snapshot of the queue.
+#[tauri::command]
+pub fn fetch_queue(queue: State<'_, QueueState>) -> PlaybackQueue {
+    queue.0.lock().unwrap().clone()
+}
+
+/// Append files to the queue.
+#[tauri::command]
+pub fn queue_add(
+    paths: Vec<String>,
+    queue: State<'_, QueueState>,
+    app_handle: AppHandle,
+) -> QueueAdded {
+    let added = {
+        let mut queue = queue.0.lock().unwrap();
+        let skipped = queue.add(paths);
+        QueueAdded {
+            queue: queue.clone(),
+            skipped,
+        }
+    };
+    prepare_next_track(&app_handle);
+    added
+}
+
+/// Remove the tracks at the given indices.
+#[tauri::command]
+pub fn queue_remove(
+    indices: Vec<usize>,
+    queue: State<'_, QueueState>,
+    app_handle: AppHandle,
+) -> PlaybackQueue {
+    let snapshot = {
+        let mut queue = queue.0.lock().unwrap();
+        queue.remove(&indices);
+        queue.clone()
+    };
+    prepare_next_track(&app_handle);
+    snapshot
+}
+
+/// Move a track inside the queue.
+#[tauri::command]
+pub fn queue_move(
+    from: usize,
+    to: usize,
+    queue: State<'_, QueueState>,
+    app_handle: AppHandle,
+) -> Result<PlaybackQueue, PlayerError> {
+    let snapshot = {
+        let mut queue = queue.0.lock().unwrap();
+        queue.move_track(from, to)?;
+        queue.clone()
+    };
+    prepare_next_track(&app_handle);
+    Ok(snapshot)
+}
+
+/// Play the next track of the queue.
+/// Return `None` if there is nothing left to play.
+#[tauri::command]
+pub fn play_next(app_handle: AppHandle) -> Result<Option<AudioMetadata>, PlayerError> {
+    play_next_in_queue(&app_handle)
+}
+
+/// Play the previous track of the queue.
+#[tauri::command]
+pub fn play_previous(app_handle: AppHandle) -> Result<Option<AudioMetadata>, PlayerError> {
+    play_previous_in_queue(&app_handle)
+}
+
+/// Switch between sequential, repeat and shuffle playback.
+#[tauri::command]
+pub fn set_playback_mode(mode: PlaybackMode, queue: State<'_, QueueState>, app_handle: AppHandle) {
+    queue.0.lock().unwrap().set_mode(mode);
+    prepare_next_track(&app_handle);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PlaybackMode, PlaybackQueue};
+
+    fn new_queue(mode: PlaybackMode) -> PlaybackQueue {
+        let mut queue = PlaybackQueue::default();
+        queue.set_mode(mode);
+        queue.add(["a", "b", "c", "d"].map(String::from).to_vec());
+        queue
+    }
+
+    fn current(queue: &PlaybackQueue) -> Option<&str> {
+        queue.current().map(|(_, path)| path)
+    }
+
+    fn path(step: Option<(usize, String)>) -> Option<String> {
+        step.map(|(_, path)| path)
+    }
+
+    #[test]
+    fn add_reports_the_skipped_paths() {
+        let mut queue = new_queue(PlaybackMode::RepeatAll);
+        let skipped = queue.add(["b", "e", "", "e"].map(String::from).to_vec());
+        assert_eq!(skipped, ["b", "", "e"]);
+        assert_eq!(queue.tracks, ["a", "b", "c", "d", "e"]);
+    }
+
+    #[test]
+    fn next_follows_the_mode() {
+        let mut queue = new_queue(PlaybackMode::RepeatAll);
+        let steps: Vec<_> = (0..5).map(|_| path(queue.next(false)).unwrap()).collect();
+        assert_eq!(steps, ["a", "b", "c", "d", "a"]);
+
+        let mut queue = new_queue(PlaybackMode::Sequential);
+        queue.focus("d");
+        assert_eq!(queue.next(false), None);
+        assert_eq!(current(&queue), Some("d"));
+
+        let mut queue = new_queue(PlaybackMode::RepeatOne);
+        queue.focus("b");
+        assert_eq!(queue.peek_next(), Some((1, "b".to_string())));
+        assert_eq!(queue.next(false), Some((1, "b".to_string())));
+        assert_eq!(queue.next(true), Some((2, "c".to_string())));
+    }
+
+    #[test]
+    fn previous_walks_back_the_history() {
+        let mut queue = new_queue(PlaybackMode::RepeatAll);
+        queue.focus("a");
+        queue.focus("c");
+        queue.next(true);
+        assert_eq!(path(queue.previous()).as_deref(), Some("c"));
+        assert_eq!(path(queue.previous()).as_deref(), Some("a"));
+        // Out of history, back in queue order, wrapping around.
+        assert_eq!(path(queue.previous()).as_deref(), Some("d"));
+
+        let mut queue = new_queue(PlaybackMode::Sequential);
+        queue.focus("a");
+        assert_eq!(queue.previous(), None);
+    }
+
+    #[test]
+    fn remove_keeps_the_cursor() {
+        let mut queue = new_queue(PlaybackMode::RepeatAll);
+        queue.focus("a");
+        queue.focus("c");
+        queue.remove(&[0, 0, 9]);
+        assert_eq!(current(&queue), Some("c"));
+        // The removed track is gone from the history too.
+        assert_eq!(path(queue.previous()).as_deref(), Some("b"));
+
+        // Removing the current track: the next one is the track that took its place.
+        queue.focus("c");
+        queue.remove(&[1]);
+        assert_eq!(current(&queue), Some("b"));
+        assert_eq!(path(queue.next(false)).as_deref(), Some("d"));
+
+        queue.remove(&[0, 1]);
+        assert!(queue.is_empty());
+        assert_eq!(queue.current(), None);
+        assert_eq!(queue.next(false), None);
+    }
+
+    #[test]
+    fn move_track_keeps_the_cursor() {
+        let mut queue = new_queue(PlaybackMode::RepeatAll);
+        queue.focus("a");
+        queue.move_track(0, 2).unwrap();
+        assert_eq!(queue.tracks, ["b", "c", "a", "d"]);
+        assert_eq!(queue.current(), Some((2, "a")));
+
+        queue.move_track(0, 3).unwrap();
+        assert_eq!(queue.current(), Some((1, "a")));
+        queue.move_track(3, 0).unwrap();
+        assert_eq!(queue.current(), Some((2, "a")));
+        assert!(queue.move_track(0, 4).is_err());
+        assert_eq!(queue.tracks, ["b", "c", "a", "d"]);
+    }
+
+    #[test]
+    fn focus_finds_the_path() {
+        let mut queue = new_queue(PlaybackMode::RepeatAll);
+        assert_eq!(queue.focus("c"), Some(2));
+        assert_eq!(queue.focus("z"), None);
+        assert_eq!(current(&queue), Some("c"));
+        assert_eq!(path(queue.next(false)).as_deref(), Some("d"));
+    }
+
+    #[test]
+    fn shuffle_never_repeats_and_agrees_with_the_deck() {
+        let mut queue = new_queue(PlaybackMode::Shuffle);
+        queue.focus("a");
+        for _ in 0..100 {
+            let before = queue.current().unwrap().0;
+            let peeked = queue.peek_next();
+            let next = queue.next(false);
+            assert_eq!(peeked, next);
+            assert_ne!(next.unwrap().0, before);
+        }
+
+        let mut queue = PlaybackQueue::default();
+        queue.set_mode(PlaybackMode::Shuffle);
+        queue.add(vec!["a".to_string()]);
+        queue.focus("a");
+        assert_eq!(path(queue.next(false)).as_deref(), Some("a"));
+    }
+}
@@ -0,0 +1,204 @@
+use rodio::decoder::DecoderError;
+use std::fmt;
+use std::io;
+
+/// Error returned by the commands and sent with the error events.
+/// Serialized as `{ "code": "FileNotFound", "path": "..." }`, so that the frontend
+/// can tell whether to skip the track, retry or ask the user.
+#[derive(serde::Serialize, Clone, Debug, PartialEq)]
+#[serde(tag = "code", rename_all_fields = "camelCase")]
+pub enum PlayerError {
+    FileNotFound {
+        path: String,
+    },
+    PermissionDenied {
+        path: String,
+    },
+    /// Not an audio file, or one no decoder is built for.
+    UnsupportedFormat {
+        path: String,
+        reason: String,
+    },
+    DecodeFailed {
+        path: String,
+        reason: String,
+    },
+    /// The track cannot go to `position`, in seconds.
+    SeekUnsupported {
+        position: f64,
+        reason: String,
+    },
+    /// `device` is `None` for the default device.
+    NoOutputDevice {
+        device: Option<String>,
+        reason: String,
+    },
+    TagWriteFailed {
+        path: String,
+        reason: String,
+    },
+    InvalidArgument {
+        reason: String,
+    },
+    NothingPlaying,
+    PresetNotFound {
+        name: String,
+    },
+    /// A settings file of the app could not be read or written.
+    ConfigFailed {
+        file: String,
+        reason: String,
+    },
+    /// Any other failure on a file given by the user.
+    Io {
+        path: String,
+        reason: String,
+    },
+    /// The same kind of task is already running.
+    Busy {
+        reason: String,
+    },
+    Cancelled,
+    /// The audio thread failed to start or stopped.
+    AudioEngine {
+        reason: String,
+    },
+    MediaControls {
+        reason: String,
+    },
+    /// The system could not be kept from sleeping.
+    InhibitFailed {
+        reason: String,
+    },
+}
+
+impl PlayerError {
+    /// Failure to open `path`.
+    pub fn io(path: &str, error: &io::Error) -> Self {
+        let path = path.to_string();
+        match error.kind() {
+            io::ErrorKind::NotFound => PlayerError::FileNotFound { path },
+            io::ErrorKind::PermissionDenied => PlayerError::PermissionDenied { path },
+            _ => PlayerError::Io {
+                path,
+                reason: error.to_string(),
+            },
+        }
+    }
+
+    /// Failure to decode `path`.
+    pub fn decoder(path: &str, error: &DecoderError) -> Self {
+        let path = path.to_string();
+        let reason = error.to_string();
+        match error {
+            DecoderError::UnrecognizedFormat | DecoderError::NoStreams => {
+                PlayerError::UnsupportedFormat { path, reason }
+            }
+            _ => PlayerError::DecodeFailed { path, reason },
+        }
+    }
+
+    pub fn invalid(reason: impl Into<String>) -> Self {
+        PlayerError::InvalidArgument {
+            reason: reason.into(),
+        }
+    }
+
+    /// Whether the file itself cannot be played, so that trying it again is pointless.
+    pub fn is_unplayable(&self) -> bool {
+        matches!(
+            self,
+            PlayerError::FileNotFound { .. }
+                | PlayerError::PermissionDenied { .. }
+                | PlayerError::UnsupportedFormat { .. }
+                | PlayerError::DecodeFailed { .. }
+        )
+    }
+}
+
+impl fmt::Display for PlayerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[ERROR] ")?;
+        match self {
+            PlayerError::FileNotFound { path } => write!(f, "File not found: {}", path),
+            PlayerError::PermissionDenied { path } => write!(f, "Permission denied: {}", path),
+            PlayerError::UnsupportedFormat { path, reason } => {
+                write!(f, "Unsupported format ({}): {}", reason, path)
+            }
+            PlayerError::DecodeFailed { path, reason } => {
+                write!(f, "Failed to decode {}: {}", path, reason)
+            }
+            PlayerError::SeekUnsupported { position, reason } => {
+                write!(f, "Failed to seek to {:.3}s: {}", position, reason)
+            }
+            PlayerError::NoOutputDevice {
+                device: Some(device),
+                reason,
+            } => write!(f, "Failed to open {}: {}", device, reason),
+            PlayerError::NoOutputDevice {
+                device: None,
+                reason,
+            } => write!(f, "Failed to open the default output device: {}", reason),
+            PlayerError::TagWriteFailed { path, reason } => {
+                write!(f, "Failed to write the tags of {}: {}", path, reason)
+            }
+            PlayerError::InvalidArgument { reason } => write!(f, "{}", reason),
+            PlayerError::NothingPlaying => write!(f, "Nothing is playing"),
+            PlayerError::PresetNotFound { name } => write!(f, "No EQ preset named {}", name),
+            PlayerError::ConfigFailed { file, reason } => write!(f, "{}: {}", file, reason),
+            PlayerError::Io { path, reason } => write!(f, "{}: {}", path, reason),
+            PlayerError::Busy { reason } => write!(f, "{}", reason),
+            PlayerError::Cancelled => write!(f, "Cancelled"),
+            PlayerError::AudioEngine { reason } => write!(f, "{}", reason),
+            PlayerError::MediaControls { reason } => {
+                write!(f, "Failed to update the media controls: {}", reason)
+            }
+            PlayerError::InhibitFailed { reason } => {
+                write!(f, "Failed to keep the system awake: {}", reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for PlayerError {}
+
+#[cfg(test)]
+mod tests {
+    use super::PlayerError;
+    use std::io;
+
+    #[test]
+    fn io_errors_keep_their_kind() {
+        let missing = io::Error::from(io::ErrorKind::NotFound);
+        assert_eq!(
+            PlayerError::io("a.mp3", &missing),
+            PlayerError::FileNotFound {
+                path: "a.mp3".to_string()
+            }
+        );
+        let denied = io::Error::from(io::ErrorKind::PermissionDenied);
+        assert!(PlayerError::io("a.mp3", &denied).is_unplayable());
+        let other = io::Error::from(io::ErrorKind::Interrupted);
+        assert!(!PlayerError::io("a.mp3", &other).is_unplayable());
+    }
+
+    #[test]
+    fn serialized_with_a_code() {
+        let error = PlayerError::NoOutputDevice {
+            device: Some("USB DAC".to_string()),
+            reason: "No such device".to_string(),
+        };
+        assert_eq!(
+            serde_json::to_value(&error).unwrap(),
+            serde_json::json!({
+                "code": "NoOutputDevice",
+                "device": "USB DAC",
+                "reason": "No such device",
+            })
+        );
+        assert_eq!(
+            serde_json::to_value(PlayerError::NothingPlaying).unwrap(),
+            serde_json::json!({ "code": "NothingPlaying" })
+        );
+    }
+}
@@ -0,0 +1,334 @@
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+
+/// Blocks below this loudness are ignored (LUFS).
+const ABSOLUTE_GATE: f64 = -70.;
+/// Integrated loudness ignores blocks this far below the ungated mean (LU).
+const INTEGRATED_RELATIVE_GATE: f64 = -10.;
+/// Loudness range ignores blocks this far below the ungated mean (LU).
+const RANGE_RELATIVE_GATE: f64 = -20.;
+
+/// Blocks are measured every 100 ms.
+const STEPS_PER_SECOND: u32 = 10;
+/// Momentary blocks are 400 ms long, short-term blocks 3 s long.
+const MOMENTARY_STEPS: usize = 4;
+const SHORT_TERM_STEPS: usize = 30;
+
+/// Oversampling factor and taps per phase of the true-peak interpolator.
+const OVERSAMPLING: usize = 4;
+const PHASE_TAPS: usize = 12;
+
+/// Second-order IIR filter, transposed direct form II.
+#[derive(Clone)]
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    z: [f64; 2],
+}
+
+impl Biquad {
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.z[0];
+        self.z[0] = self.b[1] * x - self.a[0] * y + self.z[1];
+        self.z[1] = self.b[2] * x - self.a[1] * y;
+        y
+    }
+}
+
+/// The two stages of the K-weighting curve of ITU-R BS.1770,
+/// computed for any sample rate instead of the tabulated 48 kHz values.
+fn k_weighting(sample_rate: u32) -> [Biquad; 2] {
+    let rate = sample_rate as f64;
+
+    // High shelf modelling the acoustic effect of the head.
+    let f0 = 1681.974450955533;
+    let gain = 3.999843853973347;
+    let q = 0.7071752369554196;
+    let k = (PI * f0 / rate).tan();
+    let vh = 10f64.powf(gain / 20.);
+    let vb = vh.powf(0.4996667741545416);
+    let a0 = 1. + k / q + k * k;
+    let shelf = Biquad {
+        b: [
+            (vh + vb * k / q + k * k) / a0,
+            2. * (k * k - vh) / a0,
+            (vh - vb * k / q + k * k) / a0,
+        ],
+        a: [2. * (k * k - 1.) / a0, (1. - k / q + k * k) / a0],
+        z: [0.; 2],
+    };
+
+    // RLB high-pass.
+    let f0 = 38.13547087602444;
+    let q = 0.5003270373238773;
+    let k = (PI * f0 / rate).tan();
+    let a0 = 1. + k / q + k * k;
+    let high_pass = Biquad {
+        b: [1., -2., 1.],
+        a: [2. * (k * k - 1.) / a0, (1. - k / q + k * k) / a0],
+        z: [0.; 2],
+    };
+
+    [shelf, high_pass]
+}
+
+/// Weight of a channel in the loudness sum. Only 5.1 layouts are known to rodio
+/// by their channel count; the surround channels count more and the LFE is ignored.
+fn channel_weight(channels: usize, channel: usize) -> f64 {
+    match (channels, channel) {
+        (6, 3) => 0.,
+        (6, 4) | (6, 5) => 1.41,
+        _ => 1.,
+    }
+}
+
+/// Windowed-sinc interpolation filter, one set of taps per oversampling phase.
+fn interpolation_filter() -> Vec<[f64; PHASE_TAPS]> {
+    let length = OVERSAMPLING * PHASE_TAPS;
+    let center = (length - 1) as f64 / 2.;
+    let mut phases = vec![[0.; PHASE_TAPS]; OVERSAMPLING];
+    for i in 0..length {
+        let x = (i as f64 - center) / OVERSAMPLING as f64;
+        let sinc = if x == 0. {
+            1.
+        } else {
+            (PI * x).sin() / (PI * x)
+        };
+        let window = 0.5 - 0.5 * (2. * PI * (i as f64 + 0.5) / length as f64).cos();
+        phases[i % OVERSAMPLING][i / OVERSAMPLING] = sinc * window;
+    }
+    // Unity gain at DC for every phase.
+    for phase in phases.iter_mut() {
+        let sum: f64 = phase.iter().sum();
+        phase.iter_mut().for_each(|tap| *tap /= sum);
+    }
+    phases
+}
+
+/// Result of the measurement of one track. Blocks are kept as mean energies
+/// so that tracks can be pooled together for album values.
+#[derive(Clone, Debug, Default)]
+pub struct Loudness {
+    momentary: Vec<f64>,
+    short_term: Vec<f64>,
+    /// Linear true peak (1.0 = full scale).
+    pub true_peak: f32,
+}
+
+impl Loudness {
+    /// Integrated loudness in LUFS, `None` for silence.
+    pub fn integrated(&self) -> Option<f64> {
+        gated_energies(&self.momentary, INTEGRATED_RELATIVE_GATE)
+            .map(|blocks| energy_to_loudness(mean(&blocks)))
+    }
+
+    /// Loudness range in LU (EBU Tech 3342), `None` when the track is too short or silent.
+    pub fn range(&self) -> Option<f64> {
+        let mut loudness: Vec<f64> = gated_energies(&self.short_term, RANGE_RELATIVE_GATE)?
+            .into_iter()
+            .map(energy_to_loudness)
+            .collect();
+        loudness.sort_by(f64::total_cmp);
+        let percentile = |p: f64| loudness[((loudness.len() - 1) as f64 * p).round() as usize];
+        Some(percentile(0.95) - percentile(0.10))
+    }
+
+    /// The tracks measured as one programme, i.e. an album.
+    pub fn combine<'a>(tracks: impl IntoIterator<Item = &'a Loudness>) -> Loudness {
+        let mut album = Loudness::default();
+        for track in tracks {
+            album.momentary.extend_from_slice(&track.momentary);
+            album.short_term.extend_from_slice(&track.short_term);
+            album.true_peak = album.true_peak.max(track.true_peak);
+        }
+        album
+    }
+}
+
+fn energy_to_loudness(energy: f64) -> f64 {
+    -0.691 + 10. * energy.log10()
+}
+
+fn mean(blocks: &[f64]) -> f64 {
+    blocks.iter().sum::<f64>() / blocks.len() as f64
+}
+
+/// Blocks passing the absolute gate, then the relative one.
+fn gated_energies(blocks: &[f64], relative_gate: f64) -> Option<Vec<f64>> {
+    let absolute: Vec<f64> = blocks
+        .iter()
+        .copied()
+        .filter(|&energy| energy > 0. && energy_to_loudness(energy) > ABSOLUTE_GATE)
+        .collect();
+    if absolute.is_empty() {
+        return None;
+    }
+
+    let threshold = energy_to_loudness(mean(&absolute)) + relative_gate;
+    let relative: Vec<f64> = absolute
+        .into_iter()
+        .filter(|&energy| energy_to_loudness(energy) > threshold)
+        .collect();
+    (!relative.is_empty()).then_some(relative)
+}
+
+/// Measures interleaved samples as per ITU-R BS.1770-4 / EBU R128.
+pub struct LoudnessMeter {
+    channels: usize,
+    filters: Vec<[Biquad; 2]>,
+    interpolation: Vec<[f64; PHASE_TAPS]>,
+    /// Last input samples of every channel, for the true-peak interpolation.
+    history: Vec<VecDeque<f64>>,
+    channel: usize,
+    /// Weighted energy of the 100 ms step being measured.
+    step_energy: f64,
+    step_frames: usize,
+    frames_per_step: usize,
+    /// Energies of the last steps, enough for a short-term block.
+    steps: VecDeque<f64>,
+    result: Loudness,
+}
+
+impl LoudnessMeter {
+    pub fn new(channels: usize, sample_rate: u32) -> Self {
+        let channels = channels.max(1);
+        LoudnessMeter {
+            channels,
+            filters: vec![k_weighting(sample_rate); channels],
+            interpolation: interpolation_filter(),
+            history: vec![VecDeque::from(vec![0.; PHASE_TAPS]); channels],
+            channel: 0,
+            step_energy: 0.,
+            step_frames: 0,
+            frames_per_step: (sample_rate / STEPS_PER_SECOND).max(1) as usize,
+            steps: VecDeque::with_capacity(SHORT_TERM_STEPS),
+            result: Loudness::default(),
+        }
+    }
+
+    pub fn push(&mut self, sample: f32) {
+        let channel = self.channel;
+        let x = sample as f64;
+
+        let [shelf, high_pass] = &mut self.filters[channel];
+        let weighted = high_pass.process(shelf.process(x));
+        self.step_energy += channel_weight(self.channels, channel) * weighted * weighted;
+
+        let history = &mut self.history[channel];
+        history.pop_back();
+        history.push_front(x);
+        let mut peak = x.abs();
+        for phase in &self.interpolation {
+            let y: f64 = phase.iter().zip(history.iter()).map(|(h, x)| h * x).sum();
+            peak = peak.max(y.abs());
+        }
+        self.result.true_peak = self.result.true_peak.max(peak as f32);
+
+        self.channel += 1;
+        if self.channel == self.channels {
+            self.channel = 0;
+            self.step_frames += 1;
+            if self.step_frames == self.frames_per_step {
+                self.end_step();
+            }
+        }
+    }
+
+    fn end_step(&mut self) {
+        if self.steps.len() == SHORT_TERM_STEPS {
+            self.steps.pop_front();
+        }
+        self.steps.push_back(self.step_energy);
+        self.step_energy = 0.;
+        self.step_frames = 0;
+
+        let block_energy = |steps: usize| {
+            self.steps.iter().rev().take(steps).sum::<f64>() / (steps * self.frames_per_step) as f64
+        };
+        if self.steps.len() >= MOMENTARY_STEPS {
+            self.result.momentary.push(block_energy(MOMENTARY_STEPS));
+        }
+        if self.steps.len() == SHORT_TERM_STEPS {
+            self.result.short_term.push(block_energy(SHORT_TERM_STEPS));
+        }
+    }
+
+    /// The trailing partial block is dropped, as the standard says.
+    pub fn finish(self) -> Loudness {
+        self.result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{k_weighting, Loudness, LoudnessMeter};
+    use crate::utils::song::test_audio;
+
+    /// 1 kHz stereo sines at 48 kHz, one after the other, as (dBFS, seconds).
+    fn measure(parts: &[(f64, f64)]) -> Loudness {
+        let mut meter = LoudnessMeter::new(2, 48000);
+        for &(dbfs, seconds) in parts {
+            for sample in test_audio::sine(1000., dbfs, 2, 48000, seconds) {
+                meter.push(sample);
+            }
+        }
+        meter.finish()
+    }
+
+    #[test]
+    fn sine_at_minus_20_dbfs_is_minus_20_lufs() {
+        let loudness = measure(&[(-20., 5.)]).integrated().unwrap();
+        assert!((loudness + 20.).abs() < 0.1, "{} LUFS", loudness);
+    }
+
+    #[test]
+    fn silence_has_no_loudness() {
+        let silence = measure(&[(f64::NEG_INFINITY, 5.)]);
+        assert_eq!(silence.integrated(), None);
+        assert_eq!(silence.range(), None);
+    }
+
+    /// EBU Tech 3341 case 3: the quiet parts are gated out.
+    #[test]
+    fn tech_3341_gating() {
+        let loudness = measure(&[(-36., 10.), (-23., 60.), (-36., 10.)])
+            .integrated()
+            .unwrap();
+        assert!((loudness + 23.).abs() < 0.1, "{} LUFS", loudness);
+    }
+
+    /// EBU Tech 3342 case 1: 20 s at -20 dBFS then 20 s at -30 dBFS.
+    #[test]
+    fn tech_3342_range() {
+        let range = measure(&[(-20., 20.), (-30., 20.)]).range().unwrap();
+        assert!((range - 10.).abs() < 1., "{} LU", range);
+    }
+
+    /// The values tabulated in ITU-R BS.1770 for 48 kHz.
+    #[test]
+    fn k_weighting_at_48_khz() {
+        let [shelf, high_pass] = k_weighting(48000);
+        let expected = [
+            (
+                shelf.b.to_vec(),
+                vec![1.53512485958697, -2.69169618940638, 1.19839281085285],
+            ),
+            (shelf.a.to_vec(), vec![-1.69065929318241, 0.73248077421585]),
+            (high_pass.b.to_vec(), vec![1., -2., 1.]),
+            (
+                high_pass.a.to_vec(),
+                vec![-1.99004745483398, 0.99007225036621],
+            ),
+        ];
+        for (actual, expected) in expected {
+            for (actual, expected) in actual.iter().zip(expected) {
+                assert!(
+                    (actual - expected).abs() < 1e-8,
+                    "{} != {}",
+                    actual,
+                    expected
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,338 @@
+use crate::utils::song::player_error::PlayerError;
+use crate::utils::song::tag_editor;
+use id3::frame::ExtendedText;
+use id3::{Tag as Id3Tag, TagLike};
+use metaflac::Tag as FlacTag;
+use mp4ameta::{Data, DataIdent, FreeformIdent};
+use std::path::Path;
+
+/// ReplayGain values of a file. Gains are in dB, peaks are linear (1.0 = full scale).
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ReplayGain {
+    pub track_gain: Option<f32>,
+    pub track_peak: Option<f32>,
+    pub album_gain: Option<f32>,
+    pub album_peak: Option<f32>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ReplayGainMode {
+    Off,
+    Track,
+    Album,
+    /// Album gain when the track is played among tracks of the same album, track gain otherwise.
+    #[default]
+    Auto,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ReplayGainSettings {
+    pub mode: ReplayGainMode,
+    /// Extra gain in dB added to the tagged one.
+    pub preamp: f32,
+    /// Lower the gain so that the peak does not go over full scale.
+    pub prevent_clipping: bool,
+}
+
+impl Default for ReplayGainSettings {
+    fn default() -> Self {
+        ReplayGainSettings {
+            mode: ReplayGainMode::default(),
+            preamp: 0.,
+            prevent_clipping: true,
+        }
+    }
+}
+
+impl ReplayGainSettings {
+    /// The linear factor to apply to a track.
+    /// `album_sequence` tells if the track is played as part of its album (for `Auto`).
+    pub fn gain_factor(&self, replay_gain: Option<&ReplayGain>, album_sequence: bool) -> f32 {
+        let Some(rg) = replay_gain else {
+            return 1.;
+        };
+
+        let album = match self.mode {
+            ReplayGainMode::Off => return 1.,
+            ReplayGainMode::Track => false,
+            ReplayGainMode::Album => true,
+            ReplayGainMode::Auto => album_sequence,
+        };
+        let (gain, peak) = if album {
+            (
+                rg.album_gain.or(rg.track_gain),
+                rg.album_peak.or(rg.track_peak),
+            )
+        } else {
+            (
+                rg.track_gain.or(rg.album_gain),
+                rg.track_peak.or(rg.album_peak),
+            )
+        };
+        let Some(gain) = gain else {
+            return 1.;
+        };
+
+        let factor = 10f32.powf((gain + self.preamp) / 20.);
+        match peak {
+            Some(peak) if self.prevent_clipping && peak > 0. => factor.min(1. / peak),
+            _ => factor,
+        }
+    }
+}
+
+/// Parse values like `-6.54 dB`.
+fn parse_gain(value: &str) -> Option<f32> {
+    let value = value.trim();
+    let value = value
+        .strip_suffix("dB")
+        .or_else(|| value.strip_suffix("db"))
+        .unwrap_or(value);
+    value.trim().parse().ok()
+}
+
+fn parse_peak(value: &str) -> Option<f32> {
+    value.trim().parse().ok()
+}
+
+impl ReplayGain {
+    /// Pick the REPLAYGAIN_* values out of the tag fields, whatever their case.
+    fn from_fields<I>(fields: I) -> Option<ReplayGain>
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        let mut rg = ReplayGain::default();
+        for (key, value) in fields {
+            match key.to_ascii_uppercase().as_str() {
+                "REPLAYGAIN_TRACK_GAIN" => rg.track_gain = parse_gain(&value),
+                "REPLAYGAIN_TRACK_PEAK" => rg.track_peak = parse_peak(&value),
+                "REPLAYGAIN_ALBUM_GAIN" => rg.album_gain = parse_gain(&value),
+                "REPLAYGAIN_ALBUM_PEAK" => rg.album_peak = parse_peak(&value),
+                _ => {}
+            }
+        }
+        (rg != ReplayGain::default()).then_some(rg)
+    }
+}
+
+/// Read the ReplayGain tags: TXXX frames (MP3), Vorbis comments (FLAC)
+/// or freeform atoms (M4A).
+pub fn read_replay_gain(path: &str) -> Option<ReplayGain> {
+    let ext = Path::new(path).extension()?.to_str()?.to_lowercase();
+
+    let fields: Vec<(String, String)> = match ext.as_str() {
+        "mp3" => {
+            let tag = Id3Tag::read_from_path(path).ok()?;
+            tag.extended_texts()
+                .map(|text| (text.description.clone(), text.value.clone()))
+                .collect()
+        }
+        "flac" => {
+            let tag = FlacTag::read_from_path(path).ok()?;
+            tag.vorbis_comments()?
+                .comments
+                .iter()
+                .filter_map(|(key, values)| Some((key.clone(), values.first()?.clone())))
+                .collect()
+        }
+        "m4a" | "mp4" => {
+            let tag = mp4ameta::Tag::read_from_path(path).ok()?;
+            tag.data()
+                .filter_map(|(ident, data)| match ident {
+                    DataIdent::Freeform { name, .. } => {
+                        Some((name.clone(), data.string()?.to_string()))
+                    }
+                    _ => None,
+                })
+                .collect()
+        }
+        _ => return None,
+    };
+
+    ReplayGain::from_fields(fields)
+}
+
+pub(crate) fn tag_error(path: &str, context: &str, error: impl std::fmt::Display) -> PlayerError {
+    PlayerError::TagWriteFailed {
+        path: path.to_string(),
+        reason: format!("{}: {}", context, error),
+    }
+}
+
+/// Write loudness fields such as `REPLAYGAIN_TRACK_GAIN`, replacing the existing ones
+/// whatever their case. Other tags are left untouched.
+pub fn write_gain_tags(path: &str, fields: &[(&str, String)]) -> Result<(), PlayerError> {
+    tag_editor::update_atomically(path, |target| write_fields(path, target, fields))
+}
+
+/// Write the fields to `target`, a copy of `path`.
+fn write_fields(path: &str, target: &Path, fields: &[(&str, String)]) -> Result<(), PlayerError> {
+    let ext = Path::new(path)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.to_lowercase())
+        .unwrap_or_default();
+
+    match ext.as_str() {
+        "mp3" => {
+            let (mut tag, version) = match Id3Tag::read_from_path(target) {
+                Ok(tag) => {
+                    let version = tag.version();
+                    (tag, version)
+                }
+                Err(e) if matches!(e.kind, id3::ErrorKind::NoTag) => {
+                    (Id3Tag::new(), id3::Version::Id3v24)
+                }
+                Err(e) => return Err(tag_error(path, "Failed to read the tags", e)),
+            };
+            for (key, value) in fields {
+                let existing: Vec<String> = tag
+                    .extended_texts()
+                    .filter(|text| text.description.eq_ignore_ascii_case(key))
+                    .map(|text| text.description.clone())
+                    .collect();
+                for description in existing {
+                    tag.remove_extended_text(Some(&description), None);
+                }
+                tag.add_frame(ExtendedText {
+                    description: key.to_string(),
+                    value: value.clone(),
+                });
+            }
+            tag.write_to_path(target, version)
+                .map_err(|e| tag_error(path, "Failed to write the tags", e))
+        }
+        "flac" => {
+            let mut tag = FlacTag::read_from_path(target)
+                .map_err(|e| tag_error(path, "Failed to read the tags", e))?;
+            for (key, value) in fields {
+                // metaflac upper-cases the keys when reading.
+                tag.remove_vorbis(key);
+                tag.set_vorbis(*key, vec![value.as_str()]);
+            }
+            tag.save()
+                .map_err(|e| tag_error(path, "Failed to write the tags", e))
+        }
+        "m4a" | "mp4" => {
+            let mut tag = mp4ameta::Tag::read_from_path(target)
+                .map_err(|e| tag_error(path, "Failed to read the tags", e))?;
+            for (key, value) in fields {
+                tag.retain_data(|ident, _| {
+                    !matches!(ident, DataIdent::Freeform { name, .. } if name.eq_ignore_ascii_case(key))
+                });
+                // Lower case, like the other taggers writing to MP4.
+                let name = key.to_lowercase();
+                tag.set_data(
+                    FreeformIdent::new("com.apple.iTunes", &name),
+                    Data::Utf8(value.clone()),
+                );
+            }
+            tag.write_to_path(target)
+                .map_err(|e| tag_error(path, "Failed to write the tags", e))
+        }
+        _ => Err(PlayerError::TagWriteFailed {
+            path: path.to_string(),
+            reason: "Unsupported format for tag writing".to_string(),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        read_replay_gain, write_gain_tags, ReplayGain, ReplayGainMode, ReplayGainSettings,
+    };
+
+    fn settings(mode: ReplayGainMode, preamp: f32, prevent_clipping: bool) -> ReplayGainSettings {
+        ReplayGainSettings {
+            mode,
+            preamp,
+            prevent_clipping,
+        }
+    }
+
+    fn close(actual: f32, expected: f32) {
+        assert!(
+            (actual - expected).abs() < 1e-4,
+            "{} != {}",
+            actual,
+            expected
+        );
+    }
+
+    const TAGS: ReplayGain = ReplayGain {
+        track_gain: Some(-6.),
+        track_peak: Some(0.5),
+        album_gain: Some(-3.),
+        album_peak: Some(0.8),
+    };
+
+    #[test]
+    fn decibels_to_factor() {
+        let track = settings(ReplayGainMode::Track, 0., true);
+        close(track.gain_factor(Some(&TAGS), true), 0.50119);
+        let album = settings(ReplayGainMode::Album, 0., true);
+        close(album.gain_factor(Some(&TAGS), false), 0.70795);
+
+        // Auto: the album gain among the album, the track gain otherwise.
+        let auto = settings(ReplayGainMode::Auto, 0., true);
+        close(auto.gain_factor(Some(&TAGS), true), 0.70795);
+        close(auto.gain_factor(Some(&TAGS), false), 0.50119);
+
+        // Without the album values, the track ones are used.
+        let track_only = ReplayGain {
+            album_gain: None,
+            album_peak: None,
+            ..TAGS
+        };
+        close(album.gain_factor(Some(&track_only), true), 0.50119);
+
+        assert_eq!(track.gain_factor(None, false), 1.);
+        let off = settings(ReplayGainMode::Off, 6., true);
+        assert_eq!(off.gain_factor(Some(&TAGS), false), 1.);
+    }
+
+    #[test]
+    fn preamp_is_added() {
+        let track = settings(ReplayGainMode::Track, 2., true);
+        close(track.gain_factor(Some(&TAGS), false), 0.63096);
+    }
+
+    #[test]
+    fn clipping_protection_caps_the_gain() {
+        let loud = ReplayGain {
+            track_gain: Some(6.),
+            track_peak: Some(0.9),
+            ..ReplayGain::default()
+        };
+        let protected = settings(ReplayGainMode::Track, 0., true);
+        close(protected.gain_factor(Some(&loud), false), 1. / 0.9);
+        let unprotected = settings(ReplayGainMode::Track, 0., false);
+        close(unprotected.gain_factor(Some(&loud), false), 1.99526);
+    }
+
+    #[test]
+    fn gain_tags_replace_the_old_ones() {
+        let dir = std::env::temp_dir().join("youngl_gain_tags");
+        let _ = std::fs::create_dir_all(&dir);
+        let path = dir.join("song.mp3");
+        std::fs::write(&path, b"\xff\xfb\x90\x44\x00\x00\x00\x00").unwrap();
+        let path = path.to_str().unwrap().to_string();
+
+        write_gain_tags(&path, &[("replaygain_track_gain", "-1.00 dB".to_string())]).unwrap();
+        let fields = [
+            ("REPLAYGAIN_TRACK_GAIN", "-6.00 dB".to_string()),
+            ("REPLAYGAIN_TRACK_PEAK", "0.500000".to_string()),
+        ];
+        write_gain_tags(&path, &fields).unwrap();
+
+        let replay_gain = read_replay_gain(&path).unwrap();
+        assert_eq!(replay_gain.track_gain, Some(-6.));
+        assert_eq!(replay_gain.track_peak, Some(0.5));
+        // Written on a copy renamed over the file: only the file is left.
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,370 @@
+use crate::utils::song::player_error::PlayerError;
+use crate::utils::song::song_player;
+use crate::utils::song::track_duration;
+use rodio::source::SeekError;
+use rodio::{ChannelCount, Decoder, Sample, SampleRate, Source};
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::time::Duration;
+
+/// How far after the ID3v2 tag the first MP3 frame is looked for.
+const FRAME_SEARCH_BYTES: usize = 64 * 1024;
+
+/// Positions of a VBR MP3 in time and in bytes, from its Xing or VBRI header.
+#[derive(Debug, PartialEq)]
+pub struct SeekTable {
+    points: Vec<(Duration, u64)>,
+}
+
+impl SeekTable {
+    /// The last point at or before `target`.
+    pub fn point_before(&self, target: Duration) -> Option<(Duration, u64)> {
+        self.points
+            .iter()
+            .take_while(|(time, _)| *time <= target)
+            .last()
+            .copied()
+    }
+}
+
+/// The fields of an MPEG audio layer III frame header needed to find the VBR header.
+struct FrameHeader {
+    mpeg1: bool,
+    mono: bool,
+    sample_rate: u32,
+}
+
+impl FrameHeader {
+    fn parse(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < 4 || bytes[0] != 0xff || bytes[1] & 0xe0 != 0xe0 {
+            return None;
+        }
+        let version = (bytes[1] >> 3) & 0b11;
+        let layer = (bytes[1] >> 1) & 0b11;
+        let rate_index = ((bytes[2] >> 2) & 0b11) as usize;
+        let bitrate_index = bytes[2] >> 4;
+        // Layer III only, no reserved or free format values.
+        if version == 1
+            || layer != 1
+            || rate_index == 3
+            || bitrate_index == 0
+            || bitrate_index == 15
+        {
+            return None;
+        }
+        let rates = match version {
+            3 => [44100, 48000, 32000],
+            2 => [22050, 24000, 16000],
+            _ => [11025, 12000, 8000],
+        };
+        Some(FrameHeader {
+            mpeg1: version == 3,
+            mono: bytes[3] >> 6 == 0b11,
+            sample_rate: rates[rate_index],
+        })
+    }
+
+    fn samples_per_frame(&self) -> u64 {
+        if self.mpeg1 {
+            1152
+        } else {
+            576
+        }
+    }
+
+    /// Where the Xing header starts, after the side information.
+    fn xing_offset(&self) -> usize {
+        4 + match (self.mpeg1, self.mono) {
+            (true, false) => 32,
+            (true, true) | (false, false) => 17,
+            (false, true) => 9,
+        }
+    }
+
+    fn frame_time(&self, frames: u64) -> Duration {
+        Duration::from_secs_f64(
+            (frames * self.samples_per_frame()) as f64 / self.sample_rate as f64,
+        )
+    }
+}
+
+fn be_u16(bytes: &[u8], at: usize) -> Option<u64> {
+    Some(u16::from_be_bytes(bytes.get(at..at + 2)?.try_into().ok()?) as u64)
+}
+
+fn be_u32(bytes: &[u8], at: usize) -> Option<u64> {
+    Some(u32::from_be_bytes(bytes.get(at..at + 4)?.try_into().ok()?) as u64)
+}
+
+/// The Xing TOC: 100 entries, the byte position of every percent of the track over 256.
+fn parse_xing(header: &FrameHeader, frame: &[u8], start: u64, length: u64) -> Option<SeekTable> {
+    let at = header.xing_offset();
+    let tag = frame.get(at..at + 4)?;
+    if tag != b"Xing" && tag != b"Info" {
+        return None;
+    }
+    let flags = be_u32(frame, at + 4)?;
+    let mut field = at + 8;
+    if flags & 0x1 == 0 || flags & 0x4 == 0 {
+        return None;
+    }
+    let frames = be_u32(frame, field)?;
+    field += 4;
+    let bytes = if flags & 0x2 != 0 {
+        field += 4;
+        be_u32(frame, field - 4)?
+    } else {
+        length.saturating_sub(start)
+    };
+    let toc = frame.get(field..field + 100)?;
+
+    let duration = header.frame_time(frames);
+    let points = toc
+        .iter()
+        .enumerate()
+        .map(|(percent, &entry)| {
+            (
+                duration.mul_f64(percent as f64 / 100.),
+                start + entry as u64 * bytes / 256,
+            )
+        })
+        .collect();
+    Some(SeekTable { points })
+}
+
+/// The VBRI TOC of the Fraunhofer encoder: sizes of runs of `frames_per_entry` frames.
+fn parse_vbri(header: &FrameHeader, frame: &[u8], start: u64) -> Option<SeekTable> {
+    let at = 4 + 32;
+    if frame.get(at..at + 4)? != b"VBRI" {
+        return None;
+    }
+    let entries = be_u16(frame, at + 18)?;
+    let scale = be_u16(frame, at + 20)?;
+    let entry_size = be_u16(frame, at + 22)? as usize;
+    let frames_per_entry = be_u16(frame, at + 24)?;
+    if !(1..=4).contains(&entry_size) {
+        return None;
+    }
+
+    let mut points = vec![(Duration::ZERO, start)];
+    let mut byte = start;
+    for index in 0..entries as usize {
+        let offset = at + 26 + index * entry_size;
+        let entry = frame
+            .get(offset..offset + entry_size)?
+            .iter()
+            .fold(0u64, |value, &b| (value << 8) | b as u64);
+        byte += entry * scale;
+        points.push((
+            header.frame_time((index as u64 + 1) * frames_per_entry),
+            byte,
+        ));
+    }
+    Some(SeekTable { points })
+}
+
+/// Read the seek table of a VBR MP3, if it has one.
+pub fn read_seek_table(path: &str) -> Option<SeekTable> {
+    let mut file = File::open(path).ok()?;
+    let length = file.metadata().ok()?.len();
+    let tag_size = track_duration::id3v2_size(&mut file);
+    file.seek(SeekFrom::Start(tag_size)).ok()?;
+    let mut buffer = Vec::new();
+    file.take(FRAME_SEARCH_BYTES as u64)
+        .read_to_end(&mut buffer)
+        .ok()?;
+
+    let position = (0..buffer.len()).find(|&i| FrameHeader::parse(&buffer[i..]).is_some())?;
+    let frame = &buffer[position..];
+    let header = FrameHeader::parse(frame)?;
+    let start = tag_size + position as u64;
+    parse_xing(&header, frame, start, length).or_else(|| parse_vbri(&header, frame, start))
+}
+
+/// A file read from `start` on, as if it began there.
+struct OffsetReader {
+    file: BufReader<File>,
+    start: u64,
+}
+
+impl Read for OffsetReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.file.read(buf)
+    }
+}
+
+impl Seek for OffsetReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => self.file.seek(SeekFrom::Start(self.start + offset))?,
+            other => self.file.seek(other)?,
+        };
+        position
+            .checked_sub(self.start)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Seeking before the start"))
+    }
+}
+
+/// Decode an MP3 from the frame at `byte`.
+fn open_mp3_at(path: &str, byte: u64) -> Result<Box<dyn Source + Send>, PlayerError> {
+    let mut file = File::open(path).map_err(|e| PlayerError::io(path, &e))?;
+    let length = file
+        .metadata()
+        .map_err(|e| PlayerError::io(path, &e))?
+        .len();
+    file.seek(SeekFrom::Start(byte))
+        .map_err(|e| PlayerError::io(path, &e))?;
+    let decoder = Decoder::builder()
+        .with_data(OffsetReader {
+            file: BufReader::new(file),
+            start: byte,
+        })
+        .with_byte_len(length.saturating_sub(byte))
+        .with_hint("mp3")
+        .with_seekable(true)
+        .build()
+        .map_err(|e| PlayerError::decoder(path, &e))?;
+    Ok(Box::new(decoder))
+}
+
+/// Decode and drop the samples up to `length`. Return how much was skipped,
+/// less than `length` when the source ends first.
+fn skip(source: &mut Box<dyn Source + Send>, length: Duration) -> Duration {
+    let channels = source.channels() as u64;
+    let sample_rate = source.sample_rate() as f64;
+    let samples = (length.as_secs_f64() * sample_rate) as u64 * channels;
+    let skipped = source.by_ref().take(samples as usize).count() as u64;
+    Duration::from_secs_f64((skipped / channels) as f64 / sample_rate)
+}
+
+/// A source opened in the middle of a file: seeks are shifted by `start`,
+/// and fail before it.
+pub struct StartingAt {
+    input: Box<dyn Source + Send>,
+    start: Duration,
+}
+
+impl Iterator for StartingAt {
+    type Item = Sample;
+
+    fn next(&mut self) -> Option<Sample> {
+        self.input.next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.input.size_hint()
+    }
+}
+
+impl Source for StartingAt {
+    fn current_span_len(&self) -> Option<usize> {
+        self.input.current_span_len()
+    }
+
+    fn channels(&self) -> ChannelCount {
+        self.input.channels()
+    }
+
+    fn sample_rate(&self) -> SampleRate {
+        self.input.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+
+    fn try_seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+        match pos.checked_sub(self.start) {
+            Some(pos) => self.input.try_seek(pos),
+            None => Err(SeekError::NotSupported {
+                underlying_source: "StartingAt",
+            }),
+        }
+    }
+}
+
+/// Open `path` again at `target`, for the decoders unable to seek. VBR MP3s jump to
+/// the closest point of their seek table, the rest is decoded and dropped.
+/// Return the source and the position reached, short of `target` if the file ends first.
+pub fn reopen_at(
+    path: &str,
+    target: Duration,
+) -> Result<(Box<dyn Source + Send>, Duration), PlayerError> {
+    let is_mp3 = Path::new(path)
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("mp3"));
+    let jump = is_mp3
+        .then(|| read_seek_table(path)?.point_before(target))
+        .flatten()
+        .filter(|(time, _)| !time.is_zero());
+
+    let (mut source, start) = match jump {
+        Some((time, byte)) => (open_mp3_at(path, byte)?, time),
+        None => (song_player::open_decoder(path)?, Duration::ZERO),
+    };
+    let reached = start + skip(&mut source, target - start);
+    if start.is_zero() {
+        return Ok((source, reached));
+    }
+    Ok((
+        Box::new(StartingAt {
+            input: source,
+            start,
+        }),
+        reached,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_vbri, parse_xing, FrameHeader};
+    use std::time::Duration;
+
+    /// MPEG1 layer III, 128 kbps, 44.1 kHz, joint stereo.
+    const HEADER: [u8; 4] = [0xff, 0xfb, 0x90, 0x44];
+
+    #[test]
+    fn xing_toc() {
+        let mut frame = HEADER.to_vec();
+        frame.resize(4 + 32, 0);
+        frame.extend(b"Xing");
+        frame.extend(0x7u32.to_be_bytes());
+        // 1000 frames of 1152 samples, about 26 s.
+        frame.extend(1000u32.to_be_bytes());
+        frame.extend(400_000u32.to_be_bytes());
+        frame.extend((0..100).map(|percent| (percent * 256 / 100) as u8));
+
+        let header = FrameHeader::parse(&frame).unwrap();
+        let table = parse_xing(&header, &frame, 100, 400_100).unwrap();
+        let duration = Duration::from_secs_f64(1000. * 1152. / 44100.);
+        let (time, byte) = table.point_before(duration / 2).unwrap();
+        assert_eq!(time, duration.mul_f64(0.5));
+        assert_eq!(byte, 100 + 128 * 400_000 / 256);
+        assert_eq!(
+            table.point_before(Duration::ZERO),
+            Some((Duration::ZERO, 100))
+        );
+    }
+
+    #[test]
+    fn vbri_toc() {
+        let mut frame = HEADER.to_vec();
+        frame.resize(4 + 32, 0);
+        frame.extend(b"VBRI");
+        frame.extend([0; 14]);
+        // 3 entries of 2 bytes, scale 1, 10 frames each.
+        for value in [3u16, 1, 2, 10, 1000, 2000, 3000] {
+            frame.extend(value.to_be_bytes());
+        }
+
+        let header = FrameHeader::parse(&frame).unwrap();
+        let table = parse_vbri(&header, &frame, 0).unwrap();
+        let ten_frames = Duration::from_secs_f64(10. * 1152. / 44100.);
+        assert_eq!(
+            table.point_before(ten_frames * 2 + ten_frames / 2),
+            Some((ten_frames * 2, 3000))
+        );
+        assert!(parse_xing(&header, &frame, 0, 6000).is_none());
+    }
+}
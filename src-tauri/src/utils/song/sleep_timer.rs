@@ -0,0 +1,349 @@
+use crate::utils::song::playback_queue;
+use crate::utils::song::player_error::PlayerError;
+use crate::utils::song::song_player::{self, AudioState};
+use crate::utils::system::{self, PlayerControls};
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tauri::{AppHandle, Emitter, Manager, State};
+
+/// How often the countdown checks whether it was cancelled.
+const TICK: Duration = Duration::from_millis(250);
+/// Interval between two volume changes of the fade-out.
+const FADE_STEP: Duration = Duration::from_millis(50);
+const MAX_MINUTES: f64 = 24. * 60.;
+const MAX_FADE: f64 = 60.;
+
+/// When the playback should stop.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(tag = "mode", rename_all = "camelCase")]
+pub enum SleepTimerMode {
+    /// After `minutes`, fading the volume out over the last `fade` seconds.
+    Minutes {
+        minutes: f64,
+        #[serde(default)]
+        fade: f64,
+    },
+    /// Once the current track is over.
+    EndOfTrack,
+    /// Once `count` tracks are over, the current one included.
+    Tracks { count: u32 },
+}
+
+pub struct SleepTimer {
+    mode: SleepTimerMode,
+    /// When the timer fires, for `Minutes`.
+    deadline: Option<Instant>,
+    /// Track changes left before the timer fires, for the other modes.
+    tracks_left: u32,
+    cancel: Arc<AtomicBool>,
+}
+
+impl SleepTimer {
+    fn new(mode: SleepTimerMode, deadline: Option<Instant>, cancel: Arc<AtomicBool>) -> Self {
+        let tracks_left = match mode {
+            SleepTimerMode::Minutes { .. } => 0,
+            SleepTimerMode::EndOfTrack => 1,
+            SleepTimerMode::Tracks { count } => count,
+        };
+        SleepTimer {
+            mode,
+            deadline,
+            tracks_left,
+            cancel,
+        }
+    }
+
+    /// Whether the timer stops after tracks rather than after minutes.
+    fn counts_tracks(&self) -> bool {
+        self.tracks_left > 0
+    }
+
+    /// Whether the current track is the last one the timer lets play.
+    fn stops_after_current(&self) -> bool {
+        self.tracks_left == 1
+    }
+
+    /// Count a track starting on its own. Return whether it is one too many, which
+    /// happens when the timer was set after that track had been handed to the deck.
+    fn track_changed(&mut self) -> bool {
+        match self.tracks_left {
+            0 => false,
+            1 => true,
+            _ => {
+                self.tracks_left -= 1;
+                false
+            }
+        }
+    }
+}
+
+/// The timer set, if any.
+pub struct SleepTimerState(pub Mutex<Option<SleepTimer>>);
+
+#[derive(Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct SleepTimerStatus {
+    #[serde(flatten)]
+    mode: SleepTimerMode,
+    /// Seconds left, for `Minutes`.
+    remaining: Option<f64>,
+    tracks_left: Option<u32>,
+}
+
+/// Pause the playback and let the system and the frontend know.
+fn fire(app: &AppHandle, mode: SleepTimerMode) {
+    song_player::pause_playback(&app.state::<AudioState>());
+    report_fired(app, mode);
+}
+
+/// Show the playback as paused in the media controls and tell the frontend.
+fn report_fired(app: &AppHandle, mode: SleepTimerMode) {
+    let progress = song_player::current_position(&app.state::<AudioState>());
+    if let Err(e) = system::set_system_status(&app.state::<PlayerControls>(), true, progress) {
+        if cfg!(debug_assertions) {
+            println!("[DEBUG] Failed to update the media controls: {}", e);
+        }
+    }
+    let _ = app.emit("sleep-timer-fired", mode);
+}
+
+/// Wait for `deadline` on a thread of its own, so the timer does not depend on the
+/// webview, which may be throttled in the background.
+fn spawn_countdown(app: AppHandle, deadline: Instant, fade: Duration, cancel: Arc<AtomicBool>) {
+    std::thread::spawn(move || {
+        let fade_start = deadline.checked_sub(fade).unwrap_or(deadline);
+        loop {
+            if cancel.load(Ordering::Relaxed) {
+                return;
+            }
+            let now = Instant::now();
+            if now >= fade_start {
+                break;
+            }
+            std::thread::sleep(TICK.min(fade_start - now));
+        }
+
+        let state = app.state::<AudioState>();
+        let Ok(volume) = state.engine.volume() else {
+            return;
+        };
+        loop {
+            if cancel.load(Ordering::Relaxed) {
+                let _ = state.engine.set_volume(volume);
+                return;
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            // Quadratic curve, the decrease sounds more even than a linear one.
+            let left = (deadline - now).as_secs_f32() / fade.as_secs_f32();
+            let _ = state.engine.set_volume(volume * left * left);
+            std::thread::sleep(FADE_STEP);
+        }
+
+        let timer_state = app.state::<SleepTimerState>();
+        let mut timer = timer_state.0.lock().unwrap();
+        // Cancelled or replaced while fading.
+        if cancel.load(Ordering::Relaxed) {
+            let _ = state.engine.set_volume(volume);
+            return;
+        }
+        let Some(timer) = timer.take() else {
+            return;
+        };
+        fire(&app, timer.mode);
+        // The next play starts at the usual volume.
+        let _ = state.engine.set_volume(volume);
+    });
+}
+
+/// Whether the current track is the last one before the timer fires, in which case
+/// no track is handed to the deck after it and the playback ends with it.
+pub(crate) fn stops_after_current(app: &AppHandle) -> bool {
+    let state = app.state::<SleepTimerState>();
+    let slot = state.0.lock().unwrap();
+    slot.as_ref().is_some_and(SleepTimer::stops_after_current)
+}
+
+/// Count a track change for the timers stopping after tracks. Called by the deck
+/// listener when the next track starts on its own, before the queue prepares the one after.
+pub(crate) fn on_track_changed(app: &AppHandle) {
+    let state = app.state::<SleepTimerState>();
+    let one_too_many = state
+        .0
+        .lock()
+        .unwrap()
+        .as_mut()
+        .is_some_and(SleepTimer::track_changed);
+    if !one_too_many {
+        return;
+    }
+
+    // End it right away, the timer fires once the playback is over.
+    let audio_state = app.state::<AudioState>();
+    song_player::clear_next(&audio_state);
+    song_player::finish_current(&audio_state);
+}
+
+/// The playback ended on its own, so a timer waiting for the end of a track is done.
+/// Return whether it was.
+pub(crate) fn on_playback_finished(app: &AppHandle) -> bool {
+    let state = app.state::<SleepTimerState>();
+    let timer = state
+        .0
+        .lock()
+        .unwrap()
+        .take_if(|timer| timer.counts_tracks());
+    if let Some(timer) = &timer {
+        report_fired(app, timer.mode);
+    }
+    timer.is_some()
+}
+
+/// Start a sleep timer, replacing the one set.
+#[tauri::command]
+pub fn set_sleep_timer(
+    mode: SleepTimerMode,
+    app_handle: AppHandle,
+    state: State<'_, SleepTimerState>,
+) -> Result<(), PlayerError> {
+    match mode {
+        SleepTimerMode::Minutes { minutes, fade } => {
+            if !(minutes > 0. && minutes <= MAX_MINUTES) {
+                return Err(PlayerError::invalid(format!(
+                    "Invalid sleep timer duration: {}",
+                    minutes
+                )));
+            }
+            if !(0. ..=MAX_FADE.min(minutes * 60.)).contains(&fade) {
+                return Err(PlayerError::invalid(format!(
+                    "Invalid fade duration: {}",
+                    fade
+                )));
+            }
+        }
+        SleepTimerMode::Tracks { count: 0 } => {
+            return Err(PlayerError::invalid("Invalid track count: 0"));
+        }
+        _ => {}
+    }
+
+    let was_last_track = stops_after_current(&app_handle);
+    let mut slot = state.0.lock().unwrap();
+    if let Some(timer) = slot.take() {
+        timer.cancel.store(true, Ordering::Relaxed);
+    }
+    let cancel = Arc::new(AtomicBool::new(false));
+    let deadline = match mode {
+        SleepTimerMode::Minutes { minutes, fade } => {
+            let deadline = Instant::now() + Duration::from_secs_f64(minutes * 60.);
+            spawn_countdown(
+                app_handle.clone(),
+                deadline,
+                Duration::from_secs_f64(fade),
+                cancel.clone(),
+            );
+            Some(deadline)
+        }
+        _ => None,
+    };
+    let timer = slot.insert(SleepTimer::new(mode, deadline, cancel));
+    let last_track = timer.stops_after_current();
+    drop(slot);
+
+    // Take back or hand over the track after the current one.
+    if was_last_track || last_track {
+        playback_queue::prepare_next_track(&app_handle);
+    }
+    Ok(())
+}
+
+#[tauri::command]
+pub fn cancel_sleep_timer(
+    app_handle: AppHandle,
+    state: State<'_, SleepTimerState>,
+) -> Result<(), PlayerError> {
+    let was_last_track = stops_after_current(&app_handle);
+    if let Some(timer) = state.0.lock().unwrap().take() {
+        timer.cancel.store(true, Ordering::Relaxed);
+    }
+    if was_last_track {
+        playback_queue::prepare_next_track(&app_handle);
+    }
+    Ok(())
+}
+
+#[tauri::command]
+pub fn fetch_sleep_timer(
+    state: State<'_, SleepTimerState>,
+) -> Result<Option<SleepTimerStatus>, PlayerError> {
+    Ok(state
+        .0
+        .lock()
+        .unwrap()
+        .as_ref()
+        .map(|timer| SleepTimerStatus {
+            mode: timer.mode,
+            remaining: timer.deadline.map(|deadline| {
+                deadline
+                    .saturating_duration_since(Instant::now())
+                    .as_secs_f64()
+            }),
+            tracks_left: timer.counts_tracks().then_some(timer.tracks_left),
+        }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SleepTimer, SleepTimerMode};
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Arc;
+
+    fn new_timer(mode: SleepTimerMode) -> SleepTimer {
+        SleepTimer::new(mode, None, Arc::new(AtomicBool::new(false)))
+    }
+
+    #[test]
+    fn end_of_track_stops_after_the_current_track() {
+        let mut timer = new_timer(SleepTimerMode::EndOfTrack);
+        assert!(timer.counts_tracks());
+        assert!(timer.stops_after_current());
+        // Set after the next track was handed to the deck: that one is ended at once.
+        assert!(timer.track_changed());
+        assert!(timer.track_changed());
+        assert!(timer.stops_after_current());
+    }
+
+    #[test]
+    fn tracks_counts_the_current_track() {
+        let mut timer = new_timer(SleepTimerMode::Tracks { count: 3 });
+        assert!(!timer.stops_after_current());
+        assert!(!timer.track_changed());
+        assert!(!timer.stops_after_current());
+        assert!(!timer.track_changed());
+        // The third track is the last one: no track is handed over after it.
+        assert!(timer.stops_after_current());
+        assert_eq!(timer.tracks_left, 1);
+        // Handed over before the count reached it.
+        assert!(timer.track_changed());
+        assert!(timer.counts_tracks());
+
+        let mut timer = new_timer(SleepTimerMode::Tracks { count: 1 });
+        assert!(timer.stops_after_current());
+        assert!(timer.track_changed());
+    }
+
+    #[test]
+    fn minutes_ignore_the_tracks() {
+        let mut timer = new_timer(SleepTimerMode::Minutes {
+            minutes: 10.,
+            fade: 0.,
+        });
+        assert!(!timer.counts_tracks());
+        assert!(!timer.stops_after_current());
+        assert!(!timer.track_changed());
+    }
+}
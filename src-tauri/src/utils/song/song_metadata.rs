@@ -1,17 +1,84 @@
+use crate::utils::song::cover_art;
+use crate::utils::song::player_error::PlayerError;
+use crate::utils::song::replay_gain::{self, ReplayGain};
+use crate::utils::song::stream_info::{self, StreamInfo};
+use crate::utils::song::track_duration::ResolvedDuration;
 use audiotags::{AudioTagEdit, Tag};
-use base64::engine::general_purpose;
-use base64::Engine;
+use id3::TagLike;
 use lazy_static::lazy_static;
+use rodio::SampleRate;
 use std::collections::HashMap;
+use std::path::Path;
 use std::sync::Mutex;
 
-#[derive(serde::Serialize, Clone)]
+/// `total_duration` keeps the snake_case name it was first sent with, the frontend reads it.
+#[derive(serde::Serialize, Clone, Default)]
+#[serde(rename_all = "camelCase")]
 pub struct AudioMetadata {
     pub title: Option<String>,
     pub artist: Option<String>,
     pub album: Option<String>,
-    pub cover: Option<String>, // Base64 encoded image
+    /// URL of the embedded cover, served by `cover_art`.
+    pub cover: Option<String>,
+    #[serde(rename = "total_duration")]
     pub total_duration: f64,
+    /// Set by `with_duration`, false while `total_duration` is estimated or unknown.
+    pub duration_exact: bool,
+    pub replay_gain: Option<ReplayGain>,
+    pub album_artist: Option<String>,
+    pub track_number: Option<u16>,
+    pub track_total: Option<u16>,
+    pub disc_number: Option<u16>,
+    pub disc_total: Option<u16>,
+    pub year: Option<i32>,
+    /// The full release date when the tag has one, e.g. `2019-05-03`.
+    pub date: Option<String>,
+    pub genre: Option<String>,
+    pub composer: Option<String>,
+    pub comment: Option<String>,
+    pub bpm: Option<f32>,
+    /// Part of a compilation, i.e. an album of several artists.
+    pub compilation: bool,
+    pub stream: Option<StreamInfo>,
+}
+
+/// The fields `audiotags` has no getter for: BPM and the compilation flag.
+fn read_bpm_and_compilation(path: &str) -> (Option<f32>, bool) {
+    let ext = Path::new(path)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.to_lowercase())
+        .unwrap_or_default();
+    let is_set = |value: &str| value.trim() == "1";
+
+    match ext.as_str() {
+        "mp3" => {
+            let Ok(tag) = id3::Tag::read_from_path(path) else {
+                return (None, false);
+            };
+            let text = |id| tag.get(id).and_then(|frame| frame.content().text());
+            (
+                text("TBPM").and_then(|bpm| bpm.trim().parse().ok()),
+                // TCMP is an iTunes frame, but most taggers write it.
+                text("TCMP").is_some_and(is_set),
+            )
+        }
+        "flac" => {
+            let Ok(tag) = metaflac::Tag::read_from_path(path) else {
+                return (None, false);
+            };
+            let first = |key| tag.get_vorbis(key).and_then(|mut values| values.next());
+            (
+                first("BPM").and_then(|bpm| bpm.trim().parse().ok()),
+                first("COMPILATION").is_some_and(is_set),
+            )
+        }
+        "m4a" | "mp4" => match mp4ameta::Tag::read_from_path(path) {
+            Ok(tag) => (tag.bpm().map(f32::from), tag.compilation()),
+            Err(_) => (None, false),
+        },
+        _ => (None, false),
+    }
 }
 
 lazy_static! {
@@ -33,28 +100,38 @@ pub fn get_metadata(path: &String, total_duration: f64) -> AudioMetadata {
     // 2. If not found, do the heavy lifting
     let metadata = match Tag::new().read_from_path(&path) {
         Ok(tag) => {
-            let cover_base64 = tag.album_cover().map(|cover| {
-                let b64 = general_purpose::STANDARD.encode(cover.data);
-                let mime = match cover.mime_type {
-                    audiotags::MimeType::Png => "image/png",
-                    _ => "image/jpeg",
-                };
-                format!("data:{};base64,{}", mime, b64)
-            });
+            let date = tag.date();
+            let (bpm, compilation) = read_bpm_and_compilation(path);
             AudioMetadata {
                 title: tag.title().map(|s| s.to_string()),
                 artist: tag.artist().map(|s| s.to_string()),
                 album: tag.album().map(|a| a.title.to_string()),
-                cover: cover_base64,
+                cover: tag
+                    .album_cover()
+                    .is_some()
+                    .then(|| cover_art::cover_url(path)),
                 total_duration,
+                duration_exact: false,
+                replay_gain: replay_gain::read_replay_gain(path),
+                album_artist: tag.album_artist().map(|s| s.to_string()),
+                track_number: tag.track_number(),
+                track_total: tag.total_tracks(),
+                disc_number: tag.disc_number(),
+                disc_total: tag.total_discs(),
+                year: tag.year().or(date.map(|date| date.year)),
+                date: date.map(|date| date.to_string()),
+                genre: tag.genre().map(|s| s.to_string()),
+                composer: tag.composer().map(|s| s.to_string()),
+                comment: tag.comment().map(|s| s.to_string()),
+                bpm,
+                compilation,
+                stream: stream_info::read_stream_info(path),
             }
         }
         Err(_) => AudioMetadata {
-            title: None,
-            artist: None,
-            album: None,
-            cover: None,
             total_duration,
+            stream: stream_info::read_stream_info(path),
+            ..Default::default()
         },
     };
 
@@ -65,8 +142,63 @@ pub fn get_metadata(path: &String, total_duration: f64) -> AudioMetadata {
     metadata
 }
 
+impl AudioMetadata {
+    pub fn with_duration(mut self, duration: ResolvedDuration) -> Self {
+        self.total_duration = duration.seconds();
+        self.duration_exact = duration.exact;
+        self
+    }
+
+    /// Tell how the track reaches the output, for the one being played.
+    pub fn with_output(mut self, decks: SampleRate, output: SampleRate) -> Self {
+        if let Some(stream) = &mut self.stream {
+            stream.set_output(decks, output);
+        }
+        self
+    }
+}
+
+/// Drop the cached metadata of a file whose tags changed.
+pub fn forget_metadata(path: &str) {
+    METADATA_CACHE.lock().unwrap().remove(path);
+}
+
 /// Get the metadata of some files.
 #[tauri::command]
-pub fn fetch_metadata(paths: Vec<String>) -> Result<Vec<AudioMetadata>, String> {
+pub fn fetch_metadata(paths: Vec<String>) -> Result<Vec<AudioMetadata>, PlayerError> {
     Ok(paths.iter().map(|path| get_metadata(path, 0.)).collect())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::get_metadata;
+    use id3::{Frame, Tag, TagLike, Version};
+
+    #[test]
+    fn reads_the_extended_fields() {
+        let path = std::env::temp_dir().join("youngl_song_metadata.mp3");
+        std::fs::write(&path, b"\xff\xfb\x90\x44\x00\x00\x00\x00").unwrap();
+        let mut tag = Tag::new();
+        tag.set_track(4);
+        tag.set_total_tracks(12);
+        tag.set_disc(2);
+        tag.set_genre("Jazz");
+        tag.set_album_artist("Various Artists");
+        tag.set_text("TDRC", "2019-05-03");
+        tag.add_frame(Frame::text("TBPM", "128"));
+        tag.add_frame(Frame::text("TCMP", "1"));
+        tag.write_to_path(&path, Version::Id3v24).unwrap();
+
+        let metadata = get_metadata(&path.to_str().unwrap().to_string(), 0.);
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(metadata.track_number, Some(4));
+        assert_eq!(metadata.track_total, Some(12));
+        assert_eq!(metadata.disc_number, Some(2));
+        assert_eq!(metadata.year, Some(2019));
+        assert_eq!(metadata.date.as_deref(), Some("2019-05-03"));
+        assert_eq!(metadata.genre.as_deref(), Some("Jazz"));
+        assert_eq!(metadata.album_artist.as_deref(), Some("Various Artists"));
+        assert_eq!(metadata.bpm, Some(128.));
+        assert!(metadata.compilation);
+    }
+}
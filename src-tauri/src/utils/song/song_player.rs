@@ -1,53 +1,579 @@
+use crate::utils::song::audio_engine::{AudioEngine, OutputBackend, OutputInfo};
+use crate::utils::song::equalizer::{EqControl, Equalizer};
+use crate::utils::song::gain_ramp;
+use crate::utils::song::gapless::{self, GaplessTrim};
+use crate::utils::song::playback_clock::{PlaybackClock, PlaybackProgress};
+use crate::utils::song::playback_deck::{self, DeckEvent, DeckHandle, DeckTrack, LoopRegion};
+use crate::utils::song::playback_queue;
+use crate::utils::song::player_error::PlayerError;
+use crate::utils::song::replay_gain::ReplayGainSettings;
+use crate::utils::song::seek_fallback;
+use crate::utils::song::sleep_timer;
 use crate::utils::song::song_metadata;
 use crate::utils::song::song_metadata::AudioMetadata;
+use crate::utils::song::time_stretch::{
+    RateControl, TimeStretch, MAX_PLAYBACK_RATE, MIN_PLAYBACK_RATE,
+};
+use crate::utils::song::track_duration::{self, ResolvedDuration};
+use crate::utils::song::track_generations::TrackGenerations;
 use lazy_static::lazy_static;
-use rodio::{Decoder, OutputStream, OutputStreamBuilder, Sink, Source};
+use rodio::{ChannelCount, Decoder, SampleRate, Source};
 use std::collections::HashMap;
 use std::fs::File;
-use std::sync::atomic::AtomicBool;
+use std::io::BufReader;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
-use tauri::{AppHandle, Emitter, State};
+use tauri::{AppHandle, Emitter, Manager, State};
+
+/// The loop set by `set_loop_region`, and the rate to go back to once it ends.
+struct LoopState {
+    region: LoopRegion,
+    base_rate: f32,
+}
+
+#[derive(serde::Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct LoopIteration {
+    track_id: u64,
+    path: String,
+    iteration: u32,
+    playback_rate: f32,
+}
+
+#[derive(serde::Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct TrackStarted {
+    track_id: u64,
+    path: String,
+    duration: f64,
+    duration_exact: bool,
+}
+
+#[derive(serde::Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct DurationUpdated {
+    track_id: u64,
+    path: String,
+    duration: f64,
+    exact: bool,
+}
+
+#[derive(serde::Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct PlaybackFinished {
+    track_id: u64,
+    /// The sleep timer stopped the playback here: nothing should be loaded after it.
+    sleep_timer: bool,
+}
+
+#[derive(serde::Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct PlaybackError {
+    track_id: u64,
+    path: String,
+    error: PlayerError,
+}
+
+#[derive(serde::Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct DeviceUnavailable {
+    error: PlayerError,
+}
+
+/// Where `set_position` ended up.
+#[derive(serde::Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct SeekResult {
+    /// In seconds, short of the position asked for when it is past the end.
+    position: f64,
+    /// The seek went past the end, so the track is over.
+    finished: bool,
+}
+
+/// The track the deck reported last.
+#[derive(Clone, Default)]
+struct NowPlaying {
+    id: u64,
+    path: String,
+    duration: f64,
+    /// Whether `duration` is exact rather than estimated.
+    exact: bool,
+}
 
 pub struct AudioState {
-    pub(crate) sink: Arc<Mutex<Sink>>,
-    is_paused: Arc<Mutex<AtomicBool>>,
-}
-
-pub fn get_audio_state() -> (OutputStream, AudioState) {
-    let output_stream =
-        OutputStreamBuilder::open_default_stream().expect("Failed to open default output stream.");
-    let sink = Arc::new(Mutex::new(Sink::connect_new(&output_stream.mixer())));
-    sink.lock().unwrap().set_volume(0.8);
-    (
-        output_stream,
-        AudioState {
-            sink,
-            is_paused: Arc::new(Mutex::new(AtomicBool::new(false))),
+    pub(crate) engine: AudioEngine,
+    deck: Arc<Mutex<Option<DeckHandle>>>,
+    crossfade: Arc<Mutex<Duration>>,
+    replay_gain: Arc<Mutex<ReplayGainSettings>>,
+    pub(crate) equalizer: Arc<EqControl>,
+    playback_rate: Arc<RateControl>,
+    looping: Arc<Mutex<Option<LoopState>>>,
+    now_playing: Arc<Mutex<NowPlaying>>,
+    generations: Arc<Mutex<TrackGenerations>>,
+    /// Given to every deck, so that one thread hears them all.
+    events: Sender<DeckEvent>,
+    /// Taken by the supervisor when it starts.
+    pending_events: Mutex<Option<Receiver<DeckEvent>>>,
+    pub(crate) clock: Arc<PlaybackClock>,
+    /// Name of the output device, `None` for the null output.
+    pub(crate) output_device: Mutex<Option<String>>,
+    /// Why no device could be opened, when playing into the null output.
+    device_error: Option<PlayerError>,
+    /// Format of the decks, the one of the first output. Switching outputs
+    /// does not change it, the sink converts if needed.
+    channels: ChannelCount,
+    sample_rate: SampleRate,
+    /// Rate of the output playing now.
+    pub(crate) output_sample_rate: Mutex<SampleRate>,
+}
+
+/// The backend asked for by `YOUNGL_AUDIO_OUTPUT`: `null` plays into nothing in real time,
+/// `null:<speed>` faster (e.g. `null:8`, or `null:inf` as fast as possible).
+pub(crate) fn requested_backend() -> OutputBackend {
+    let Ok(value) = std::env::var("YOUNGL_AUDIO_OUTPUT") else {
+        return OutputBackend::Default;
+    };
+    match value.split_once(':') {
+        None if value == "null" => OutputBackend::Null { speed: 1. },
+        Some(("null", speed)) => match speed.parse::<f32>() {
+            Ok(speed) if speed > 0. => OutputBackend::Null { speed },
+            _ => OutputBackend::Null { speed: 1. },
         },
-    )
+        _ => OutputBackend::Default,
+    }
+}
+
+/// Open the output. Without a usable device, the playback goes to the null output
+/// so that the app still starts; the reason is kept for `playback-device-unavailable`.
+pub fn get_audio_state() -> AudioState {
+    let (engine, output, device_error) = match AudioEngine::spawn(requested_backend()) {
+        Ok((engine, output)) => (engine, output, None),
+        Err(e) => {
+            if cfg!(debug_assertions) {
+                println!("[DEBUG] {}, falling back to the null output", e);
+            }
+            let (engine, output) = AudioEngine::spawn(OutputBackend::Null { speed: 1. })
+                .expect("Failed to start the audio thread.");
+            (engine, output, Some(e))
+        }
+    };
+    audio_state(engine, output, device_error)
+}
+
+fn audio_state(
+    engine: AudioEngine,
+    output: OutputInfo,
+    device_error: Option<PlayerError>,
+) -> AudioState {
+    let (events, pending_events) = mpsc::channel();
+    AudioState {
+        engine,
+        deck: Arc::new(Mutex::new(None)),
+        crossfade: Arc::new(Mutex::new(Duration::ZERO)),
+        replay_gain: Arc::new(Mutex::new(ReplayGainSettings::default())),
+        equalizer: Arc::new(EqControl::default()),
+        playback_rate: Arc::new(RateControl::default()),
+        looping: Arc::new(Mutex::new(None)),
+        now_playing: Arc::new(Mutex::new(NowPlaying::default())),
+        generations: Arc::new(Mutex::new(TrackGenerations::default())),
+        events,
+        pending_events: Mutex::new(Some(pending_events)),
+        clock: Arc::new(PlaybackClock::default()),
+        output_device: Mutex::new(output.device),
+        device_error,
+        channels: output.channels,
+        sample_rate: output.sample_rate,
+        output_sample_rate: Mutex::new(output.sample_rate),
+    }
+}
+
+/// How often the supervisor checks whether the sink ran dry.
+const SUPERVISOR_POLL: Duration = Duration::from_millis(250);
+
+/// The single thread following the playback for the whole life of the app.
+/// It forwards what the decks report and tells when the playback is over,
+/// ignoring whatever comes from a deck replaced since.
+pub fn spawn_playback_supervisor(app_handle: AppHandle) {
+    let state = app_handle.state::<AudioState>();
+    let Some(events) = state.pending_events.lock().unwrap().take() else {
+        return;
+    };
+    std::thread::spawn(move || loop {
+        match events.recv_timeout(SUPERVISOR_POLL) {
+            Ok(event) => on_deck_event(&app_handle, event),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+        check_finished(&app_handle);
+    });
+}
+
+fn on_deck_event(app_handle: &AppHandle, event: DeckEvent) {
+    let state = app_handle.state::<AudioState>();
+    match event {
+        DeckEvent::TrackStarted {
+            id,
+            path,
+            total_duration,
+        } => {
+            let Some((loaded, duration)) = track_started(&state, id, &path, total_duration) else {
+                return;
+            };
+            let _ = app_handle.emit(
+                "track-started",
+                TrackStarted {
+                    track_id: id,
+                    path: path.clone(),
+                    duration: duration.seconds(),
+                    duration_exact: duration.exact,
+                },
+            );
+            // Only the tracks following on their own count, not the one loaded.
+            // Counted first, so the queue knows whether one may follow.
+            if !loaded {
+                sleep_timer::on_track_changed(app_handle);
+            }
+            playback_queue::on_track_started(app_handle, path, duration);
+        }
+        DeckEvent::TrackFinished { id, path } => {
+            if cfg!(debug_assertions) {
+                println!("[DEBUG] Finished #{}: {}", id, path);
+            }
+        }
+        DeckEvent::LoopIteration {
+            id,
+            path,
+            iteration,
+        } => {
+            if state.generations.lock().unwrap().is_stale(id) {
+                return;
+            }
+            let Some(rate) = state.looping.lock().unwrap().as_ref().map(|looping| {
+                (looping.base_rate - looping.region.slowdown * iteration as f32)
+                    .clamp(MIN_PLAYBACK_RATE, MAX_PLAYBACK_RATE)
+            }) else {
+                return;
+            };
+            state.playback_rate.set_rate(rate);
+            state.clock.notify();
+            let _ = app_handle.emit(
+                "loop-iteration",
+                LoopIteration {
+                    track_id: id,
+                    path,
+                    iteration,
+                    playback_rate: rate,
+                },
+            );
+        }
+    }
+}
+
+/// Make `id` the track being played, unless the event is stale. Return whether it is
+/// the track loaded rather than one following it, and its duration.
+fn track_started(
+    state: &AudioState,
+    id: u64,
+    path: &str,
+    total_duration: Option<Duration>,
+) -> Option<(bool, ResolvedDuration)> {
+    let loaded = {
+        let mut generations = state.generations.lock().unwrap();
+        if !generations.started(id) {
+            return None;
+        }
+        generations.is_load(id)
+    };
+    // The loop belonged to the previous track.
+    end_loop(state);
+    let duration =
+        track_duration::resolve(path, total_duration).unwrap_or(ResolvedDuration::UNKNOWN);
+    *state.now_playing.lock().unwrap() = NowPlaying {
+        id,
+        path: path.to_string(),
+        duration: duration.seconds(),
+        exact: duration.exact,
+    };
+    state.clock.notify();
+    Some((loaded, duration))
+}
+
+/// Emit `playback-finished` once the sink has played everything, once per track.
+fn check_finished(app_handle: &AppHandle) {
+    let state = app_handle.state::<AudioState>();
+    // A stopped playback did not finish.
+    let playing = state.deck.lock().unwrap().is_some();
+    let Ok(status) = state.engine.status() else {
+        return;
+    };
+    let sink_empty = playing && status.empty;
+    let Some(id) = state.generations.lock().unwrap().finished(sink_empty) else {
+        return;
+    };
+    let sleep_timer = sleep_timer::on_playback_finished(app_handle);
+    let _ = app_handle.emit(
+        "playback-finished",
+        PlaybackFinished {
+            track_id: id,
+            sleep_timer,
+        },
+    );
+}
+
+/// Why no device could be opened at startup, while the app still plays into the null output.
+/// Asked by the frontend once it is ready, as an event sent then would be lost.
+#[tauri::command]
+pub fn fetch_device_error(state: State<'_, AudioState>) -> Option<PlayerError> {
+    let on_null_output = state.output_device.lock().unwrap().is_none();
+    state.device_error.clone().filter(|_| on_null_output)
+}
+
+pub(crate) fn report_device_unavailable(app_handle: &AppHandle, error: PlayerError) {
+    let _ = app_handle.emit("playback-device-unavailable", DeviceUnavailable { error });
 }
 
-/// Emit an event when the playback stops
-pub fn spawn_playback_watcher(app_handle: AppHandle, state: &State<'_, AudioState>) {
-    let sink_mutex = state.sink.clone();
+/// Tell the frontend that a track could not be played.
+fn report_error(app_handle: &AppHandle, id: u64, path: &str, error: &PlayerError) {
+    let _ = app_handle.emit(
+        "playback-error",
+        PlaybackError {
+            track_id: id,
+            path: path.to_string(),
+            error: error.clone(),
+        },
+    );
+}
 
+/// Replace an estimated duration by the one found by scanning the whole file,
+/// in the background, and send it with `duration-updated`.
+fn refine_duration(app_handle: &AppHandle, id: u64, path: &str, duration: ResolvedDuration) {
+    if duration.exact {
+        return;
+    }
+    let app_handle = app_handle.clone();
+    let path = path.to_string();
     std::thread::spawn(move || {
-        loop {
-            // Sleep to avoid high CPU usage
-            std::thread::sleep(Duration::from_millis(500));
-
-            let sink = sink_mutex.lock().unwrap();
-            // Check if the sink has finished all audio
-            if sink.empty() {
-                // Emit the event to the frontend
-                app_handle.emit("playback-finished", {}).unwrap();
-                break; // Exit the loop/thread once finished
+        let scanned = match track_duration::scan(&path) {
+            Ok(scanned) => scanned,
+            Err(e) => {
+                if cfg!(debug_assertions) {
+                    println!("[DEBUG] {}", e);
+                }
+                return;
             }
+        };
+        let state = app_handle.state::<AudioState>();
+        if state.generations.lock().unwrap().is_stale(id) {
+            return;
         }
+        {
+            let mut now_playing = state.now_playing.lock().unwrap();
+            if now_playing.id == id {
+                now_playing.duration = scanned.seconds();
+                now_playing.exact = scanned.exact;
+            }
+        }
+        state.clock.notify();
+        let _ = app_handle.emit(
+            "duration-updated",
+            DurationUpdated {
+                track_id: id,
+                path,
+                duration: scanned.seconds(),
+                exact: scanned.exact,
+            },
+        );
     });
 }
 
+/// Decode a file, without the encoder delay and padding.
+pub(crate) fn open_decoder(path: &str) -> Result<Box<dyn Source + Send>, PlayerError> {
+    let file = File::open(path).map_err(|e| PlayerError::io(path, &e))?;
+    let byte_len = file
+        .metadata()
+        .map_err(|e| PlayerError::io(path, &e))?
+        .len();
+    // With gapless enabled, symphonia trims the LAME/Xing delay and padding of MP3s.
+    let decoder = Decoder::builder()
+        .with_data(BufReader::new(file))
+        .with_byte_len(byte_len)
+        .with_seekable(true)
+        .with_gapless(true)
+        .build()
+        .map_err(|e| PlayerError::decoder(path, &e))?;
+
+    Ok(match gapless::read_gapless_info(path) {
+        Some(info) => Box::new(GaplessTrim::new(decoder, info)),
+        None => Box::new(decoder),
+    })
+}
+
+/// Decode a file for the deck.
+/// `album_sequence` tells if it is played among tracks of its album, for ReplayGain.
+fn open_track(
+    id: u64,
+    path: &str,
+    state: &AudioState,
+    album_sequence: bool,
+) -> Result<DeckTrack, PlayerError> {
+    let source = open_decoder(path)?;
+    let replay_gain = song_metadata::get_metadata(&path.to_string(), 0.).replay_gain;
+    Ok(DeckTrack::new(
+        id,
+        path.to_string(),
+        source,
+        state.channels,
+        state.sample_rate,
+    )
+    .with_replay_gain(replay_gain, album_sequence))
+}
+
+/// A track started by `load_file`.
+struct LoadedTrack {
+    id: u64,
+    duration: ResolvedDuration,
+    metadata: AudioMetadata,
+}
+
+/// Start playing a file, replacing whatever is playing now.
+pub(crate) fn play_file(
+    path: &str,
+    state: &AudioState,
+    app_handle: &AppHandle,
+) -> Result<AudioMetadata, PlayerError> {
+    let album_sequence = playback_queue::in_album_sequence(app_handle, path);
+    match load_file(path, state, album_sequence) {
+        Ok(loaded) => {
+            refine_duration(app_handle, loaded.id, path, loaded.duration);
+            Ok(loaded.metadata)
+        }
+        Err((id, e)) => {
+            // Not worth telling once another track was loaded since.
+            if !state.generations.lock().unwrap().is_stale(id) {
+                report_error(app_handle, id, path, &e);
+            }
+            Err(e)
+        }
+    }
+}
+
+/// Open `path` and play it, or crossfade into it. On error, also return the id
+/// the track was given.
+fn load_file(
+    path: &str,
+    state: &AudioState,
+    album_sequence: bool,
+) -> Result<LoadedTrack, (u64, PlayerError)> {
+    // Held until the end so that concurrent loads play in the order of their ids.
+    let mut deck_slot = state.deck.lock().unwrap();
+    let id = state.generations.lock().unwrap().next_id();
+    let with_id = |e| (id, e);
+    let track = open_track(id, path, state, album_sequence).map_err(with_id)?;
+
+    // Without a duration in the header, estimate one until the file is scanned.
+    let duration =
+        track_duration::resolve(path, track.total_duration).unwrap_or(ResolvedDuration::UNKNOWN);
+    let metadata = with_output_format(
+        state,
+        song_metadata::get_metadata(&path.to_string(), 0.).with_duration(duration),
+    );
+    // Also when crossfading: the track was chosen, it did not follow on its own.
+    state.generations.lock().unwrap().loaded(id);
+
+    // Fade into the new track instead of cutting the one playing.
+    let crossfade = *state.crossfade.lock().unwrap();
+    let status = state.engine.status().map_err(with_id)?;
+    if !crossfade.is_zero() && !status.empty && !status.paused {
+        if let Some(deck) = deck_slot.as_ref() {
+            deck.crossfade_to(track.with_crossfade(crossfade));
+            return Ok(LoadedTrack {
+                id,
+                duration,
+                metadata,
+            });
+        }
+    }
+
+    let (deck, handle) = playback_deck::new_deck(
+        track,
+        state.channels,
+        state.sample_rate,
+        *state.replay_gain.lock().unwrap(),
+        state.events.clone(),
+    );
+
+    let deck = TimeStretch::new(deck, state.playback_rate.clone());
+    state
+        .engine
+        .play(Box::new(Equalizer::new(deck, state.equalizer.clone())))
+        .map_err(with_id)?;
+    *deck_slot = Some(handle);
+    drop(deck_slot);
+
+    if cfg!(debug_assertions) {
+        println!("[DEBUG] Succeeded to load the song...");
+    }
+
+    Ok(LoadedTrack {
+        id,
+        duration,
+        metadata,
+    })
+}
+
+/// Add to the metadata of the track being played how it is converted for the output.
+pub(crate) fn with_output_format(state: &AudioState, metadata: AudioMetadata) -> AudioMetadata {
+    metadata.with_output(state.sample_rate, *state.output_sample_rate.lock().unwrap())
+}
+
+/// How long the transition from `from` to `to` should overlap.
+/// Consecutive tracks of the same album are never crossfaded.
+fn crossfade_between(from: Option<&str>, to: &str, state: &AudioState) -> Duration {
+    let crossfade = *state.crossfade.lock().unwrap();
+    if crossfade.is_zero() {
+        return crossfade;
+    }
+
+    let album_of = |path: &str| song_metadata::get_metadata(&path.to_string(), 0.).album;
+    match (from.and_then(album_of), album_of(to)) {
+        (Some(a), Some(b)) if a == b => Duration::ZERO,
+        _ => crossfade,
+    }
+}
+
+/// Decode `path` and hand it to the deck, so it starts right when the current track
+/// (`current`, if known) ends.
+pub(crate) fn enqueue_next(
+    path: &str,
+    current: Option<&str>,
+    album_sequence: bool,
+    app_handle: &AppHandle,
+) -> Result<(), PlayerError> {
+    let state = app_handle.state::<AudioState>();
+    if state.deck.lock().unwrap().is_none() {
+        return Ok(());
+    }
+    let id = state.generations.lock().unwrap().next_id();
+    let track = open_track(id, path, &state, album_sequence)
+        .inspect_err(|e| report_error(app_handle, id, path, e))?
+        .with_crossfade(crossfade_between(current, path, &state));
+    if let Ok(duration) = track_duration::resolve(path, track.total_duration) {
+        refine_duration(app_handle, id, path, duration);
+    }
+    if let Some(deck) = state.deck.lock().unwrap().as_ref() {
+        deck.enqueue(track);
+    }
+    Ok(())
+}
+
+/// Cancel the track handed over by `enqueue_next`.
+pub(crate) fn clear_next(state: &AudioState) {
+    if let Some(deck) = state.deck.lock().unwrap().as_ref() {
+        deck.clear_next();
+    }
+}
+
 /// Load the song with the path of the song file.
 /// This function plays the song.
 /// Return the total duration.
@@ -56,82 +582,373 @@ pub fn load_song(
     path: String,
     state: State<'_, AudioState>,
     app_handle: AppHandle,
-) -> Result<AudioMetadata, String> {
-    let sink = state.sink.lock().unwrap();
-    let file = File::open(&path).map_err(|e| format!("[ERROR] Failed to open the file: {}", e))?;
-    let source = Decoder::try_from(file).map_err(|e| format!("[ERROR] Failed to decode: {}", e))?;
+) -> Result<AudioMetadata, PlayerError> {
+    play_file(&path, &state, &app_handle)
+}
 
-    let total_duration = source.total_duration().unwrap().as_secs_f64();
+/// Set how long tracks overlap when switching, from 0 (gapless) to 12 seconds.
+#[tauri::command]
+pub fn set_crossfade(
+    seconds: f64,
+    state: State<'_, AudioState>,
+    app_handle: AppHandle,
+) -> Result<(), PlayerError> {
+    if !(0. ..=playback_deck::MAX_CROSSFADE.as_secs_f64()).contains(&seconds) {
+        return Err(PlayerError::invalid(format!(
+            "Invalid crossfade duration: {}",
+            seconds
+        )));
+    }
+    *state.crossfade.lock().unwrap() = Duration::from_secs_f64(seconds);
 
-    sink.stop();
-    sink.clear();
-    sink.append(source);
-    sink.play();
+    // The track already handed to the deck still has the old duration.
+    playback_queue::prepare_next_track(&app_handle);
+    Ok(())
+}
 
-    *state.is_paused.lock().unwrap().get_mut() = false;
+/// Set how long pausing, resuming and stopping fade, and seeking crossfades,
+/// from 5 to 50 milliseconds.
+#[tauri::command]
+pub fn set_declick_ramp(
+    milliseconds: f64,
+    state: State<'_, AudioState>,
+) -> Result<(), PlayerError> {
+    let range = gain_ramp::MIN_RAMP.as_secs_f64()..=gain_ramp::MAX_RAMP.as_secs_f64();
+    if !range.contains(&(milliseconds / 1000.)) {
+        return Err(PlayerError::invalid(format!(
+            "Invalid ramp duration: {} ms",
+            milliseconds
+        )));
+    }
+    state
+        .engine
+        .set_ramp_length(Duration::from_secs_f64(milliseconds / 1000.));
+    Ok(())
+}
 
-    spawn_playback_watcher(app_handle, &state);
+/// Change how ReplayGain is applied. Takes effect on the current track too.
+#[tauri::command]
+pub fn set_replay_gain(
+    settings: ReplayGainSettings,
+    state: State<'_, AudioState>,
+) -> Result<(), PlayerError> {
+    if !(-15. ..=15.).contains(&settings.preamp) {
+        return Err(PlayerError::invalid(format!(
+            "Invalid pre-amp: {} dB",
+            settings.preamp
+        )));
+    }
+    *state.replay_gain.lock().unwrap() = settings;
+    if let Some(deck) = state.deck.lock().unwrap().as_ref() {
+        deck.set_replay_gain(settings);
+    }
+    Ok(())
+}
 
-    if cfg!(debug_assertions) {
-        println!("[DEBUG] Succeeded to load the song...");
+/// Change the playback speed, from 0.5x to 3x. The pitch is kept, unless `vinyl`
+/// is set. Positions stay in media time.
+#[tauri::command]
+pub fn set_playback_rate(
+    rate: f32,
+    vinyl: Option<bool>,
+    state: State<'_, AudioState>,
+) -> Result<(), PlayerError> {
+    if !(MIN_PLAYBACK_RATE..=MAX_PLAYBACK_RATE).contains(&rate) {
+        return Err(PlayerError::invalid(format!(
+            "Invalid playback rate: {}",
+            rate
+        )));
+    }
+    let mut looping = state.looping.lock().unwrap();
+    if let Some(looping) = looping.as_mut() {
+        looping.base_rate = rate;
+    }
+    state.playback_rate.set(rate, vinyl.unwrap_or(false));
+    Ok(())
+}
+
+/// Restore the rate and pitch changed by the loop.
+fn end_loop(state: &AudioState) {
+    if let Some(looping) = state.looping.lock().unwrap().take() {
+        state.playback_rate.set_rate(looping.base_rate);
+        state.playback_rate.set_semitones(0.);
+    }
+}
+
+/// Loop a region of the current track. Every repetition sends a `loop-iteration` event
+/// and may slow the playback down. Changing track ends the loop.
+#[tauri::command]
+pub fn set_loop_region(
+    region: LoopRegion,
+    state: State<'_, AudioState>,
+) -> Result<(), PlayerError> {
+    if !(region.start >= 0. && region.end - region.start >= 0.05) {
+        return Err(PlayerError::invalid(format!(
+            "Invalid loop region: {} - {}",
+            region.start, region.end
+        )));
+    }
+    if !(0. ..=10.).contains(&region.count_in)
+        || !(-0.5..=0.5).contains(&region.slowdown)
+        || !(-12. ..=12.).contains(&region.semitones)
+    {
+        return Err(PlayerError::invalid(format!(
+            "Invalid loop settings: {:?}",
+            region
+        )));
     }
-    println!("TOT: {}", total_duration);
 
-    Ok(song_metadata::get_metadata(&path, total_duration))
+    let deck = state.deck.lock().unwrap();
+    let Some(deck) = deck.as_ref() else {
+        return Err(PlayerError::NothingPlaying);
+    };
+    let mut looping = state.looping.lock().unwrap();
+    // A new region starts over from the rate set by the user.
+    let base_rate = looping
+        .as_ref()
+        .map_or_else(|| state.playback_rate.rate(), |looping| looping.base_rate);
+    state.playback_rate.set_rate(base_rate);
+    state.playback_rate.set_semitones(region.semitones);
+    *looping = Some(LoopState { region, base_rate });
+    deck.set_loop(Some(region));
+    Ok(())
+}
+
+#[tauri::command]
+pub fn clear_loop_region(state: State<'_, AudioState>) -> Result<(), PlayerError> {
+    end_loop(&state);
+    if let Some(deck) = state.deck.lock().unwrap().as_ref() {
+        deck.set_loop(None);
+    }
+    Ok(())
 }
 
 /// Clear the playback.
 #[tauri::command]
-pub fn stop_song(state: State<'_, AudioState>) -> Result<(), String> {
-    let sink = state.sink.lock().unwrap();
-    sink.stop();
-    sink.clear();
+pub fn stop_song(state: State<'_, AudioState>) -> Result<(), PlayerError> {
+    state.engine.stop()?;
+    *state.deck.lock().unwrap() = None;
+    state.clock.notify();
     Ok(())
 }
 
+/// Pause without toggling, e.g. when a timer fires.
+pub(crate) fn pause_playback(state: &AudioState) {
+    let _ = state.engine.pause();
+    state.clock.notify();
+}
+
+/// End the current track now, as if it had played to the end.
+pub(crate) fn finish_current(state: &AudioState) {
+    let id = state.now_playing.lock().unwrap().id;
+    if let Some(deck) = state.deck.lock().unwrap().as_ref() {
+        deck.finish(id);
+    }
+    state.clock.notify();
+}
+
+/// Resume after `pause_playback`.
+pub(crate) fn resume_playback(state: &AudioState) {
+    let _ = state.engine.resume();
+    state.clock.notify();
+}
+
 /// Toggle playback.
 /// Return a boolean meaning that if the playback is paused.
 #[tauri::command]
-pub fn toggle_playback(state: State<'_, AudioState>) -> Result<bool, String> {
-    let sink = state.sink.lock().unwrap();
-
-    let mut binding = state.is_paused.lock().unwrap();
-    let origin_state = binding.get_mut();
-    if *origin_state && !sink.empty() {
-        sink.play();
-    } else {
-        sink.pause();
-    }
-    let new_state = sink.is_paused();
+pub fn toggle_playback(state: State<'_, AudioState>) -> Result<bool, PlayerError> {
+    let new_state = state.engine.toggle()?;
 
     if cfg!(debug_assertions) {
-        println!(
-            "Toggling playback state(is playing?): {} -> {}",
-            origin_state, new_state
-        );
+        println!("Toggling playback state(is paused?): {}", new_state);
     }
 
-    *origin_state = new_state;
+    state.clock.notify();
 
     Ok(new_state)
 }
 
 /// Fetch the song playback progress.
+/// This is media time, whatever the playback rate.
 #[tauri::command]
-pub fn fetch_progress(state: State<'_, AudioState>) -> Result<f64, String> {
-    let sink = state.sink.lock().unwrap();
-    let current_time = sink.get_pos().as_secs_f64();
-    Ok(current_time)
+pub fn fetch_progress(state: State<'_, AudioState>) -> Result<f64, PlayerError> {
+    Ok(current_position(&state))
+}
+
+/// Position in the current track, in seconds.
+pub(crate) fn current_position(state: &AudioState) -> f64 {
+    if let Some(deck) = state.deck.lock().unwrap().as_ref() {
+        return deck.position().as_secs_f64();
+    }
+    state
+        .engine
+        .status()
+        .map_or(0., |status| status.position.as_secs_f64())
+}
+
+/// What `playback-progress` reports, `None` when nothing is loaded.
+pub(crate) fn playback_progress(state: &AudioState) -> Option<PlaybackProgress> {
+    let position = state
+        .deck
+        .lock()
+        .unwrap()
+        .as_ref()?
+        .position()
+        .as_secs_f64();
+    let status = state.engine.status().ok()?;
+    // Reported as paused once the playback is over.
+    let paused = status.paused || status.empty;
+    let now_playing = state.now_playing.lock().unwrap().clone();
+    Some(PlaybackProgress {
+        track_id: now_playing.id,
+        position,
+        duration: now_playing.duration,
+        paused,
+    })
 }
 
-/// Set the position
+/// Set the position. When the decoder cannot seek, the file is opened again and
+/// decoded up to `time`. Past the end, the track finishes as if played to the end.
 #[tauri::command]
-pub fn set_position(time: f64, state: State<'_, AudioState>) -> Result<(), String> {
-    let sink = state.sink.lock().unwrap();
+pub fn set_position(time: f64, state: State<'_, AudioState>) -> Result<SeekResult, PlayerError> {
+    if !time.is_finite() || time < 0. {
+        return Err(PlayerError::invalid(format!("Invalid position: {}", time)));
+    }
+    // After a stop, `now_playing` still describes the last track.
+    if state.deck.lock().unwrap().is_none() {
+        return Err(PlayerError::NothingPlaying);
+    }
+    let now_playing = state.now_playing.lock().unwrap().clone();
+    if now_playing.exact && time >= now_playing.duration {
+        if let Some(deck) = state.deck.lock().unwrap().as_ref() {
+            deck.finish(now_playing.id);
+        }
+        state.clock.notify();
+        return Ok(SeekResult {
+            position: now_playing.duration,
+            finished: true,
+        });
+    }
 
-    let seek_time = Duration::from_secs_f64(time);
-    sink.try_seek(seek_time)
-        .map_err(|e| format!("Failed to seek: {}", e))?;
+    let target = Duration::from_secs_f64(time);
+    let reached = match state.engine.seek(target) {
+        Ok(()) => target,
+        Err(PlayerError::SeekUnsupported { reason, .. }) => {
+            if cfg!(debug_assertions) {
+                println!(
+                    "[DEBUG] Seek failed ({}), reopening {}",
+                    reason, now_playing.path
+                );
+            }
+            let (source, reached) = seek_fallback::reopen_at(&now_playing.path, target)?;
+            // Ignored by the deck if the track changed meanwhile.
+            if let Some(deck) = state.deck.lock().unwrap().as_ref() {
+                deck.reopen(now_playing.id, source, reached);
+            }
+            reached
+        }
+        Err(e) => return Err(e),
+    };
+    state.clock.notify();
 
-    Ok(())
+    Ok(SeekResult {
+        position: reached.as_secs_f64(),
+        // The file ended before `target`; the deck moves on once the source runs out.
+        finished: reached < target,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{audio_state, load_file, playback_progress, track_started, AudioState};
+    use crate::utils::song::audio_engine::{AudioEngine, OutputBackend};
+    use crate::utils::song::playback_deck::DeckEvent;
+    use crate::utils::song::test_audio;
+    use std::sync::{Arc, Barrier};
+    use std::time::Duration;
+
+    fn null_state() -> AudioState {
+        let (engine, output) = AudioEngine::spawn(OutputBackend::Null { speed: 1. }).unwrap();
+        audio_state(engine, output, None)
+    }
+
+    /// Go through the deck events sent so far, like the supervisor does.
+    /// Return the tracks that started, and whether each was the one loaded.
+    fn started_tracks(state: &AudioState) -> Vec<(u64, bool)> {
+        let events = state.pending_events.lock().unwrap();
+        let events = events.as_ref().unwrap();
+        let mut started = Vec::new();
+        while let Ok(event) = events.recv_timeout(Duration::from_millis(200)) {
+            if let DeckEvent::TrackStarted {
+                id,
+                path,
+                total_duration,
+            } = event
+            {
+                if let Some((loaded, _)) = track_started(state, id, &path, total_duration) {
+                    started.push((id, loaded));
+                }
+            }
+        }
+        started
+    }
+
+    #[test]
+    fn only_the_last_of_quick_loads_plays() {
+        let state = Arc::new(null_state());
+        let paths: Vec<String> = (0..4)
+            .map(|i| test_audio::write_wav(&format!("youngl_quick_load_{}.wav", i), 8000, 2, 2))
+            .collect();
+        let barrier = Arc::new(Barrier::new(paths.len()));
+        let threads: Vec<_> = paths
+            .iter()
+            .map(|path| {
+                let (state, barrier, path) = (state.clone(), barrier.clone(), path.clone());
+                std::thread::spawn(move || {
+                    barrier.wait();
+                    load_file(&path, &state, false).map(|loaded| loaded.id)
+                })
+            })
+            .collect();
+        let ids: Vec<u64> = threads
+            .into_iter()
+            .map(|thread| thread.join().unwrap().unwrap())
+            .collect();
+        let last = *ids.iter().max().unwrap();
+
+        // The events of the replaced decks arrive, but are dropped.
+        assert_eq!(started_tracks(&state), vec![(last, true)]);
+        let progress = playback_progress(&state).unwrap();
+        assert_eq!(progress.track_id, last);
+        assert!(!progress.paused);
+
+        // The error of a load is only reported until another track is loaded.
+        let (failed, _) = load_file("/nonexistent/youngl.wav", &state, false)
+            .err()
+            .unwrap();
+        assert!(!state.generations.lock().unwrap().is_stale(failed));
+        load_file(&paths[0], &state, false).ok().unwrap();
+        assert!(state.generations.lock().unwrap().is_stale(failed));
+
+        for path in paths {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+
+    #[test]
+    fn a_crossfaded_load_is_a_load() {
+        let state = null_state();
+        *state.crossfade.lock().unwrap() = Duration::from_secs(1);
+        let first = test_audio::write_wav("youngl_crossfade_first.wav", 8000, 2, 3);
+        let second = test_audio::write_wav("youngl_crossfade_second.wav", 8000, 2, 3);
+
+        load_file(&first, &state, false).ok().unwrap();
+        let second_id = load_file(&second, &state, false).ok().unwrap().id;
+        // Not one following on its own, which the sleep timer would count.
+        assert_eq!(started_tracks(&state), vec![(second_id, true)]);
+        assert_eq!(playback_progress(&state).unwrap().track_id, second_id);
+
+        let _ = std::fs::remove_file(first);
+        let _ = std::fs::remove_file(second);
+    }
 }
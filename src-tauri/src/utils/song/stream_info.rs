@@ -0,0 +1,211 @@
+use crate::utils::song::track_duration;
+use rodio::SampleRate;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+use symphonia::core::audio::Channels;
+use symphonia::core::codecs::{self, CodecType};
+use symphonia::core::io::ReadBytes;
+
+/// Packets whose sizes tell a constant bitrate from a variable one.
+const SIZE_PACKETS: usize = 64;
+
+#[derive(serde::Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum BitrateMode {
+    Constant,
+    Variable,
+}
+
+/// What the file holds, as the decoder sees it, and how it reaches the output.
+#[derive(serde::Serialize, Clone, Debug, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct StreamInfo {
+    pub container: Option<String>,
+    pub codec: Option<String>,
+    pub sample_rate: Option<u32>,
+    /// Only lossless codecs have one.
+    pub bit_depth: Option<u32>,
+    pub channels: Option<usize>,
+    /// `Mono`, `Stereo`, or speakers and LFE like `5.1`.
+    pub channel_layout: Option<String>,
+    /// Average over the whole file, in bits per second.
+    pub bitrate: Option<u32>,
+    /// Told from the sizes of the first packets; `None` for lossless codecs.
+    pub bitrate_mode: Option<BitrateMode>,
+    pub lossless: bool,
+    /// Only known for the track being played.
+    pub output_sample_rate: Option<u32>,
+    pub resampled: Option<bool>,
+}
+
+impl StreamInfo {
+    /// The tracks are converted to the rate of the decks, which the sink converts
+    /// to the one of the output if the device changed since.
+    pub fn set_output(&mut self, decks: SampleRate, output: SampleRate) {
+        self.output_sample_rate = Some(output);
+        self.resampled = self
+            .sample_rate
+            .map(|rate| rate != decks || decks != output);
+    }
+}
+
+/// Plain PCM, not A-law or μ-law which are companded.
+fn is_pcm(codec: CodecType) -> bool {
+    codec != codecs::CODEC_TYPE_PCM_ALAW
+        && codec != codecs::CODEC_TYPE_PCM_MULAW
+        && symphonia::default::get_codecs()
+            .get_codec(codec)
+            .is_some_and(|descriptor| descriptor.short_name.starts_with("pcm_"))
+}
+
+fn is_lossless(codec: CodecType) -> bool {
+    matches!(
+        codec,
+        codecs::CODEC_TYPE_FLAC
+            | codecs::CODEC_TYPE_ALAC
+            | codecs::CODEC_TYPE_WAVPACK
+            | codecs::CODEC_TYPE_MONKEYS_AUDIO
+            | codecs::CODEC_TYPE_TTA
+    ) || is_pcm(codec)
+}
+
+fn codec_name(codec: CodecType) -> Option<String> {
+    let name = match codec {
+        codecs::CODEC_TYPE_MP1 => "MP1",
+        codecs::CODEC_TYPE_MP2 => "MP2",
+        codecs::CODEC_TYPE_MP3 => "MP3",
+        codecs::CODEC_TYPE_AAC => "AAC",
+        codecs::CODEC_TYPE_VORBIS => "Vorbis",
+        codecs::CODEC_TYPE_OPUS => "Opus",
+        codecs::CODEC_TYPE_FLAC => "FLAC",
+        codecs::CODEC_TYPE_ALAC => "ALAC",
+        codec if is_pcm(codec) => "PCM",
+        codecs::CODEC_TYPE_PCM_ALAW => "A-law",
+        codecs::CODEC_TYPE_PCM_MULAW => "μ-law",
+        codec => {
+            return symphonia::default::get_codecs()
+                .get_codec(codec)
+                .map(|descriptor| descriptor.short_name.to_uppercase())
+        }
+    };
+    Some(name.to_string())
+}
+
+fn layout_name(channels: Channels) -> String {
+    let lfe = channels.contains(Channels::LFE1) as usize;
+    match (channels.count(), lfe) {
+        (1, _) => "Mono".to_string(),
+        (2, 0) => "Stereo".to_string(),
+        (count, lfe) => format!("{}.{}", count - lfe, lfe),
+    }
+}
+
+/// The container, from the bytes after the ID3v2 tag. Bare streams
+/// like MP3 or ADTS have none and are named after their extension.
+fn container_name(path: &str) -> Option<String> {
+    let mut file = File::open(path).ok()?;
+    let start = track_duration::id3v2_size(&mut file);
+    file.seek(SeekFrom::Start(start)).ok()?;
+    let mut header = [0; 12];
+    file.read_exact(&mut header).ok()?;
+
+    let name = match (&header[..4], &header[4..8], &header[8..]) {
+        (b"fLaC", _, _) => "FLAC",
+        (b"OggS", _, _) => "Ogg",
+        (b"RIFF", _, b"WAVE") => "WAV",
+        (b"FORM", _, b"AIFF") => "AIFF",
+        (_, b"ftyp", _) => "MP4",
+        _ => {
+            return Path::new(path)
+                .extension()
+                .and_then(|ext| ext.to_str())
+                .map(|ext| ext.to_uppercase())
+        }
+    };
+    Some(name.to_string())
+}
+
+/// Read the stream parameters of the file from its headers and first packets.
+/// `None` if symphonia cannot open it.
+pub fn read_stream_info(path: &str) -> Option<StreamInfo> {
+    let (mut format, track_id, time_base) = track_duration::open_format(path).ok()?;
+    let params = format
+        .tracks()
+        .iter()
+        .find(|track| track.id == track_id)?
+        .codec_params
+        .clone();
+    let lossless = is_lossless(params.codec);
+
+    let mut sizes = Vec::with_capacity(SIZE_PACKETS);
+    while sizes.len() < SIZE_PACKETS {
+        match format.next_packet() {
+            Ok(packet) if packet.track_id() == track_id => sizes.push(packet.data.len() as u64),
+            Ok(_) => {}
+            Err(_) => break,
+        }
+    }
+    // Frame padding makes constant bitrate packets differ by a byte or so.
+    let bitrate_mode = match (sizes.iter().min(), sizes.iter().max()) {
+        (Some(min), Some(max)) if !lossless => Some(if max - min <= max / 64 + 1 {
+            BitrateMode::Constant
+        } else {
+            BitrateMode::Variable
+        }),
+        _ => None,
+    };
+
+    // Whatever comes before the first packet is headers, tags and covers.
+    let audio_start = format.into_inner().pos().saturating_sub(sizes.iter().sum());
+    let length = std::fs::metadata(path).ok()?.len();
+    let duration = match params.n_frames {
+        Some(frames) => Some(track_duration::to_duration(time_base, frames)),
+        None => track_duration::resolve(path, None)
+            .ok()
+            .map(|resolved| resolved.duration),
+    };
+    let bitrate = duration
+        .map(|duration| duration.as_secs_f64())
+        .filter(|seconds| *seconds > 0.)
+        .map(|seconds| (length.saturating_sub(audio_start) as f64 * 8. / seconds) as u32);
+
+    Some(StreamInfo {
+        container: container_name(path),
+        codec: codec_name(params.codec),
+        sample_rate: params.sample_rate,
+        bit_depth: params.bits_per_sample.filter(|_| lossless),
+        channels: params.channels.map(|channels| channels.count()),
+        channel_layout: params.channels.map(layout_name),
+        bitrate,
+        bitrate_mode,
+        lossless,
+        output_sample_rate: None,
+        resampled: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_stream_info, BitrateMode};
+    use crate::utils::song::test_audio;
+
+    #[test]
+    fn pcm_wav() {
+        let path = test_audio::write_wav("youngl_stream_info.wav", 44100, 2, 1);
+        let mut info = read_stream_info(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+
+        assert_eq!(info.container.as_deref(), Some("WAV"));
+        assert_eq!(info.codec.as_deref(), Some("PCM"));
+        assert_eq!(info.sample_rate, Some(44100));
+        assert_eq!(info.bit_depth, Some(16));
+        assert_eq!(info.channel_layout.as_deref(), Some("Stereo"));
+        assert!(info.lossless);
+        assert_eq!(info.bitrate_mode, None::<BitrateMode>);
+        assert_eq!(info.bitrate, Some(1_411_200));
+
+        info.set_output(44100, 48000);
+        assert_eq!(info.resampled, Some(true));
+    }
+}
@@ -0,0 +1,260 @@
+use crate::utils::song::player_error::PlayerError;
+use crate::utils::song::replay_gain::tag_error;
+use crate::utils::song::song_metadata;
+use audiotags::{AudioTagEdit, FlacTag, Id3v2Tag, MimeType, Mp4Tag, Picture};
+use base64::engine::general_purpose;
+use base64::Engine;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// The fields to change. Those left out are kept; an empty string,
+/// or 0 for the numbers, removes the field.
+#[derive(serde::Deserialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct TagChanges {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub track_number: Option<u16>,
+    pub total_tracks: Option<u16>,
+    /// A PNG or JPEG image, as a data URL or plain base64.
+    pub cover: Option<String>,
+}
+
+/// One file of `update_tags_batch`.
+#[derive(serde::Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct TagEdit {
+    pub path: String,
+    pub changes: TagChanges,
+}
+
+#[derive(serde::Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct TagEditResult {
+    pub path: String,
+    /// `None` when the tags were written.
+    pub error: Option<PlayerError>,
+}
+
+/// The cover to write, `None` to remove it.
+type Cover = Option<(MimeType, Vec<u8>)>;
+
+fn decode_cover(cover: &str) -> Result<Cover, PlayerError> {
+    if cover.is_empty() {
+        return Ok(None);
+    }
+    let data = cover.split_once(',').map_or(cover, |(_, data)| data);
+    let bytes = general_purpose::STANDARD
+        .decode(data.trim())
+        .map_err(|e| PlayerError::invalid(format!("Invalid cover: {}", e)))?;
+    let mime_type = if bytes.starts_with(b"\x89PNG") {
+        MimeType::Png
+    } else if bytes.starts_with(b"\xff\xd8") {
+        MimeType::Jpeg
+    } else {
+        return Err(PlayerError::invalid(
+            "The cover must be a PNG or JPEG image",
+        ));
+    };
+    Ok(Some((mime_type, bytes)))
+}
+
+/// Change the common fields, the same way whatever the format.
+fn apply(tag: &mut dyn AudioTagEdit, changes: &TagChanges, cover: Option<&Cover>) {
+    if let Some(title) = &changes.title {
+        match title.as_str() {
+            "" => tag.remove_title(),
+            title => tag.set_title(title),
+        }
+    }
+    if let Some(artist) = &changes.artist {
+        match artist.as_str() {
+            "" => tag.remove_artist(),
+            artist => tag.set_artist(artist),
+        }
+    }
+    if let Some(album) = &changes.album {
+        match album.as_str() {
+            "" => tag.remove_album_title(),
+            album => tag.set_album_title(album),
+        }
+    }
+    match changes.track_number {
+        Some(0) => tag.remove_track_number(),
+        Some(number) => tag.set_track_number(number),
+        None => {}
+    }
+    match changes.total_tracks {
+        Some(0) => tag.remove_total_tracks(),
+        Some(total) => tag.set_total_tracks(total),
+        None => {}
+    }
+    match cover {
+        Some(Some((mime_type, data))) => tag.set_album_cover(Picture {
+            mime_type: *mime_type,
+            data,
+        }),
+        Some(None) => tag.remove_album_cover(),
+        None => {}
+    }
+}
+
+/// Read the tags of `target` with the library of its format, change them, and write them
+/// back. The whole tag is read, so the frames and fields not changed are kept as they are.
+fn write_tags(
+    path: &str,
+    target: &Path,
+    changes: &TagChanges,
+    cover: Option<&Cover>,
+) -> Result<(), PlayerError> {
+    let ext = Path::new(path)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.to_lowercase())
+        .unwrap_or_default();
+
+    match ext.as_str() {
+        "mp3" => {
+            let (tag, version) = match id3::Tag::read_from_path(target) {
+                Ok(tag) => {
+                    let version = tag.version();
+                    (tag, version)
+                }
+                Err(e) if matches!(e.kind, id3::ErrorKind::NoTag) => {
+                    (id3::Tag::new(), id3::Version::Id3v24)
+                }
+                Err(e) => return Err(tag_error(path, "Failed to read the tags", e)),
+            };
+            let mut tag = Id3v2Tag::from(tag);
+            apply(&mut tag, changes, cover);
+            // Written in the version it was read in, not always in 2.4 like audiotags does.
+            id3::Tag::from(tag)
+                .write_to_path(target, version)
+                .map_err(|e| tag_error(path, "Failed to write the tags", e))
+        }
+        "flac" => {
+            let tag = metaflac::Tag::read_from_path(target)
+                .map_err(|e| tag_error(path, "Failed to read the tags", e))?;
+            let mut tag = FlacTag::from(tag);
+            apply(&mut tag, changes, cover);
+            metaflac::Tag::from(tag)
+                .write_to_path(target)
+                .map_err(|e| tag_error(path, "Failed to write the tags", e))
+        }
+        "m4a" | "mp4" => {
+            let tag = mp4ameta::Tag::read_from_path(target)
+                .map_err(|e| tag_error(path, "Failed to read the tags", e))?;
+            let mut tag = Mp4Tag::from(tag);
+            apply(&mut tag, changes, cover);
+            mp4ameta::Tag::from(tag)
+                .write_to_path(target)
+                .map_err(|e| tag_error(path, "Failed to write the tags", e))
+        }
+        _ => Err(PlayerError::TagWriteFailed {
+            path: path.to_string(),
+            reason: "Unsupported format for tag writing".to_string(),
+        }),
+    }
+}
+
+/// Hidden file next to `path`, on the same file system so that it can be renamed over it.
+fn temp_path(path: &Path) -> PathBuf {
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy())
+        .unwrap_or_default();
+    path.with_file_name(format!(".{}.tags-tmp", name))
+}
+
+/// Let `write` change the tags of a copy of the file, then put the copy in its place,
+/// so that a failure or a crash halfway never leaves a broken file.
+pub(crate) fn update_atomically(
+    path: &str,
+    write: impl FnOnce(&Path) -> Result<(), PlayerError>,
+) -> Result<(), PlayerError> {
+    let temp = temp_path(Path::new(path));
+    fs::copy(path, &temp).map_err(|e| PlayerError::io(path, &e))?;
+    let result =
+        write(&temp).and_then(|()| fs::rename(&temp, path).map_err(|e| PlayerError::io(path, &e)));
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    song_metadata::forget_metadata(path);
+    result
+}
+
+fn update_file(path: &str, changes: &TagChanges, cover: Option<&Cover>) -> Result<(), PlayerError> {
+    update_atomically(path, |target| write_tags(path, target, changes, cover))
+}
+
+/// Write title, artist, album, track number and cover to a file.
+#[tauri::command]
+pub fn update_tags(path: String, changes: TagChanges) -> Result<(), PlayerError> {
+    let cover = changes.cover.as_deref().map(decode_cover).transpose()?;
+    update_file(&path, &changes, cover.as_ref())
+}
+
+/// `update_tags` on several files, e.g. a whole album. A file failing does not stop
+/// the others; the result tells how each one went.
+#[tauri::command]
+pub fn update_tags_batch(edits: Vec<TagEdit>) -> Vec<TagEditResult> {
+    edits
+        .into_iter()
+        .map(|edit| {
+            let result = edit
+                .changes
+                .cover
+                .as_deref()
+                .map(decode_cover)
+                .transpose()
+                .and_then(|cover| update_file(&edit.path, &edit.changes, cover.as_ref()));
+            TagEditResult {
+                path: edit.path,
+                error: result.err(),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{update_tags, TagChanges};
+    use id3::frame::ExtendedText;
+    use id3::{Tag, TagLike, Version};
+
+    #[test]
+    fn keeps_the_version_and_other_frames() {
+        let dir = std::env::temp_dir().join("youngl_tag_editor");
+        let _ = std::fs::create_dir_all(&dir);
+        let path = dir.join("song.mp3");
+        std::fs::write(&path, b"\xff\xfb\x90\x44\x00\x00\x00\x00").unwrap();
+        let mut tag = Tag::new();
+        tag.set_title("Tpyo");
+        tag.set_album("Album");
+        tag.add_frame(ExtendedText {
+            description: "REPLAYGAIN_TRACK_GAIN".to_string(),
+            value: "-6.00 dB".to_string(),
+        });
+        tag.write_to_path(&path, Version::Id3v23).unwrap();
+        let path = path.to_str().unwrap().to_string();
+
+        let changes = TagChanges {
+            title: Some("Typo".to_string()),
+            album: Some(String::new()),
+            track_number: Some(3),
+            ..Default::default()
+        };
+        update_tags(path.clone(), changes).unwrap();
+
+        let tag = Tag::read_from_path(&path).unwrap();
+        assert_eq!(tag.version(), Version::Id3v23);
+        assert_eq!(tag.title(), Some("Typo"));
+        assert_eq!(tag.album(), None);
+        assert_eq!(tag.track(), Some(3));
+        assert_eq!(tag.extended_texts().count(), 1);
+        // Only the file itself is left.
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,46 @@
+//! Audio and audio files made up by the tests.
+
+/// Interleaved samples of a sine at `dbfs` (peak), the same in every channel.
+pub fn sine(
+    frequency: f64,
+    dbfs: f64,
+    channels: usize,
+    sample_rate: u32,
+    seconds: f64,
+) -> Vec<f32> {
+    let amplitude = 10f64.powf(dbfs / 20.);
+    let frames = (sample_rate as f64 * seconds) as usize;
+    (0..frames)
+        .flat_map(|i| {
+            let phase = 2. * std::f64::consts::PI * frequency * i as f64 / sample_rate as f64;
+            std::iter::repeat_n((amplitude * phase.sin()) as f32, channels)
+        })
+        .collect()
+}
+
+/// A 16-bit PCM WAV file of `seconds` of silence.
+pub fn wav(sample_rate: u32, channels: u16, seconds: u32) -> Vec<u8> {
+    let block_align = channels as u32 * 2;
+    let data = sample_rate * block_align * seconds;
+    let mut content = b"RIFF".to_vec();
+    content.extend((36 + data).to_le_bytes());
+    content.extend(b"WAVEfmt ");
+    content.extend(16u32.to_le_bytes());
+    content.extend(1u16.to_le_bytes());
+    content.extend(channels.to_le_bytes());
+    content.extend(sample_rate.to_le_bytes());
+    content.extend((sample_rate * block_align).to_le_bytes());
+    content.extend((block_align as u16).to_le_bytes());
+    content.extend(16u16.to_le_bytes());
+    content.extend(b"data");
+    content.extend(data.to_le_bytes());
+    content.resize(content.len() + data as usize, 0);
+    content
+}
+
+/// Write `wav(...)` to `name` in the temporary directory and return its path.
+pub fn write_wav(name: &str, sample_rate: u32, channels: u16, seconds: u32) -> String {
+    let path = std::env::temp_dir().join(name);
+    std::fs::write(&path, wav(sample_rate, channels, seconds)).unwrap();
+    path.to_str().unwrap().to_string()
+}
@@ -0,0 +1,500 @@
+use rodio::source::SeekError;
+use rodio::{ChannelCount, Sample, SampleRate, Source};
+use std::collections::VecDeque;
+use std::f32::consts::PI;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+pub const MIN_PLAYBACK_RATE: f32 = 0.5;
+pub const MAX_PLAYBACK_RATE: f32 = 3.0;
+
+/// How many frames are played between two checks of the rate.
+const UPDATE_FRAMES: usize = 256;
+/// Length of the WSOLA segments. Segments overlap by half.
+const SEGMENT_LENGTH: Duration = Duration::from_millis(40);
+/// How far from its nominal position a segment may be taken to line up with the previous one.
+const SEEK_TOLERANCE: Duration = Duration::from_millis(10);
+/// Only one frame out of this many is used to compare the segments.
+const CORRELATION_STEP: usize = 4;
+
+/// Playback rate and pitch shared between the commands and the audio thread.
+pub struct RateControl {
+    /// Bits of the `f32` rate.
+    rate: AtomicU32,
+    /// Resample instead of stretching, changing the pitch like a turntable.
+    vinyl: AtomicBool,
+    /// Bits of the `f32` pitch shift, in semitones.
+    semitones: AtomicU32,
+}
+
+impl Default for RateControl {
+    fn default() -> Self {
+        RateControl {
+            rate: AtomicU32::new(1f32.to_bits()),
+            vinyl: AtomicBool::new(false),
+            semitones: AtomicU32::new(0f32.to_bits()),
+        }
+    }
+}
+
+impl RateControl {
+    pub fn set(&self, rate: f32, vinyl: bool) {
+        self.set_rate(rate);
+        self.vinyl.store(vinyl, Ordering::Relaxed);
+    }
+
+    pub fn set_rate(&self, rate: f32) {
+        self.rate.store(rate.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn set_semitones(&self, semitones: f32) {
+        self.semitones.store(semitones.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn rate(&self) -> f32 {
+        f32::from_bits(self.rate.load(Ordering::Relaxed))
+    }
+
+    pub fn vinyl(&self) -> bool {
+        self.vinyl.load(Ordering::Relaxed)
+    }
+
+    pub fn semitones(&self) -> f32 {
+        f32::from_bits(self.semitones.load(Ordering::Relaxed))
+    }
+}
+
+/// Waveform-similarity overlap-add: segments of the input are taken at the playback
+/// rate and overlapped, each one shifted a little so that its waveform lines up with
+/// the previous one. The tempo changes, the pitch does not.
+struct Wsola {
+    channels: usize,
+    window: Vec<f32>,
+    segment: usize,
+    tolerance: usize,
+    /// Interleaved input not consumed yet.
+    buffer: Vec<Sample>,
+    /// Frames of `buffer` coming from the input, the rest is padding.
+    buffered_frames: usize,
+    exhausted: bool,
+    /// Nominal start of the next segment, in frames of `buffer`.
+    analysis: f64,
+    /// Where the last segment would naturally continue, in frames of `buffer`.
+    continuation: Option<usize>,
+    /// Second half of the last segment, waiting for the next one to overlap.
+    overlap: Vec<Sample>,
+}
+
+impl Wsola {
+    fn new(channels: usize, sample_rate: SampleRate) -> Self {
+        let sample_rate = sample_rate as f64;
+        let segment = ((SEGMENT_LENGTH.as_secs_f64() * sample_rate) as usize / 2 * 2).max(2);
+        // Periodic Hann window: two halves overlapping by half sum to one.
+        let window = (0..segment)
+            .map(|i| 0.5 - 0.5 * (2. * PI * i as f32 / segment as f32).cos())
+            .collect();
+
+        Wsola {
+            channels,
+            window,
+            segment,
+            tolerance: (SEEK_TOLERANCE.as_secs_f64() * sample_rate) as usize,
+            buffer: Vec::new(),
+            buffered_frames: 0,
+            exhausted: false,
+            analysis: 0.,
+            continuation: None,
+            overlap: vec![0.; segment / 2 * channels],
+        }
+    }
+
+    fn reset(&mut self) {
+        self.buffer.clear();
+        self.buffered_frames = 0;
+        self.exhausted = false;
+        self.analysis = 0.;
+        self.continuation = None;
+        self.overlap.fill(0.);
+    }
+
+    /// Hand the input not played yet over to `output`, going on unwindowed from
+    /// where the last segment stopped, and start afresh.
+    fn drain(&mut self, output: &mut VecDeque<Sample>) {
+        let start = self
+            .continuation
+            .unwrap_or(self.analysis.round() as usize)
+            .min(self.buffered_frames);
+        output.extend(
+            self.buffer
+                .drain(start * self.channels..self.buffered_frames * self.channels),
+        );
+        self.reset();
+    }
+
+    /// Read until `buffer` holds `frames` frames, padding with silence at the end.
+    fn fill(&mut self, input: &mut impl Iterator<Item = Sample>, frames: usize) {
+        while self.buffer.len() < frames * self.channels {
+            let sample = if self.exhausted { None } else { input.next() };
+            match sample {
+                Some(sample) => {
+                    self.buffer.push(sample);
+                    self.buffered_frames = self.buffer.len() / self.channels;
+                }
+                None => {
+                    self.exhausted = true;
+                    self.buffer.push(0.);
+                }
+            }
+        }
+    }
+
+    /// Sum of the channels of a frame of `buffer`.
+    fn mono(&self, frame: usize) -> f32 {
+        self.buffer[frame * self.channels..(frame + 1) * self.channels]
+            .iter()
+            .sum()
+    }
+
+    /// The start near `nominal` whose beginning resembles the most `continuation`.
+    fn best_start(&self, nominal: usize, continuation: usize) -> usize {
+        let overlap = self.segment / 2;
+        let mut best = (nominal, f32::MIN);
+        for start in nominal.saturating_sub(self.tolerance)..=nominal + self.tolerance {
+            let (mut correlation, mut energy) = (0., 0.);
+            for i in (0..overlap).step_by(CORRELATION_STEP) {
+                let candidate = self.mono(start + i);
+                correlation += candidate * self.mono(continuation + i);
+                energy += candidate * candidate;
+            }
+            let score = correlation / (energy + 1e-9).sqrt();
+            if score > best.1 {
+                best = (start, score);
+            }
+        }
+        best.0
+    }
+
+    /// Append the next half segment to `output`. Returns false once the input is over.
+    fn step(
+        &mut self,
+        input: &mut impl Iterator<Item = Sample>,
+        rate: f64,
+        output: &mut VecDeque<Sample>,
+    ) -> bool {
+        let hop = self.segment / 2;
+        let nominal = self.analysis.round() as usize;
+        if self.exhausted && nominal >= self.buffered_frames {
+            // Only the tail of the last segment is left.
+            if self.continuation.take().is_some() {
+                output.extend(self.overlap.iter().copied());
+                return true;
+            }
+            return false;
+        }
+
+        let needed = match self.continuation {
+            Some(continuation) => (nominal + self.tolerance).max(continuation) + self.segment,
+            None => nominal + self.segment,
+        };
+        self.fill(input, needed);
+
+        let start = match self.continuation {
+            Some(continuation) => self.best_start(nominal, continuation),
+            None => nominal,
+        };
+        for i in 0..self.segment {
+            // The very first half is played as is, nothing fades out under it.
+            let gain = if self.continuation.is_none() && i < hop {
+                1.
+            } else {
+                self.window[i]
+            };
+            for c in 0..self.channels {
+                let sample = self.buffer[(start + i) * self.channels + c] * gain;
+                if i < hop {
+                    output.push_back(self.overlap[i * self.channels + c] + sample);
+                } else {
+                    self.overlap[(i - hop) * self.channels + c] = sample;
+                }
+            }
+        }
+        let continuation = start + hop;
+        self.continuation = Some(continuation);
+        self.analysis += hop as f64 * rate;
+
+        // Drop the input that no segment can use anymore.
+        let unused = (self.analysis as usize)
+            .saturating_sub(self.tolerance)
+            .min(continuation);
+        if unused > self.segment {
+            self.buffer.drain(..unused * self.channels);
+            self.buffered_frames = self.buffered_frames.saturating_sub(unused);
+            self.analysis -= unused as f64;
+            self.continuation = Some(continuation - unused);
+        }
+        true
+    }
+}
+
+/// Changes the speed and the pitch of its input.
+///
+/// The input is first stretched by WSOLA, then resampled: resampling by a factor
+/// raises the pitch and the tempo alike, so the stretch makes up for the tempo.
+/// In vinyl mode the rate is done by resampling only, like a turntable.
+///
+/// The input keeps counting media time, so positions and seeks are unaffected.
+pub struct TimeStretch<S> {
+    input: S,
+    control: Arc<RateControl>,
+    channels: usize,
+    /// Tempo change done by WSOLA.
+    stretch: f64,
+    /// Resampling factor, i.e. the pitch change.
+    resample: f64,
+    countdown: usize,
+    wsola: Wsola,
+    /// Stretched samples waiting to be resampled.
+    stretched: VecDeque<Sample>,
+    /// The two frames the output is interpolated between.
+    frames: Vec<Sample>,
+    phase: f64,
+    /// Samples ready to be played.
+    output: VecDeque<Sample>,
+    ended: bool,
+}
+
+impl<S: Source> TimeStretch<S> {
+    pub fn new(input: S, control: Arc<RateControl>) -> Self {
+        let channels = input.channels().max(1) as usize;
+        let wsola = Wsola::new(channels, input.sample_rate());
+        TimeStretch {
+            input,
+            control,
+            channels,
+            stretch: 1.,
+            resample: 1.,
+            countdown: 0,
+            wsola,
+            stretched: VecDeque::new(),
+            frames: Vec::new(),
+            phase: 0.,
+            output: VecDeque::new(),
+            ended: false,
+        }
+    }
+
+    fn update(&mut self) {
+        let rate = self.control.rate() as f64;
+        let pitch = 2f64.powf(self.control.semitones() as f64 / 12.);
+        let resample = if self.control.vinyl() {
+            rate * pitch
+        } else {
+            pitch
+        };
+        let stretch = rate / resample;
+
+        // A stage no longer used hands the input it holds over to the next one.
+        if stretch == 1. && self.stretch != 1. {
+            self.wsola.drain(&mut self.stretched);
+        }
+        if resample == 1. && self.resample != 1. {
+            // The output was between the first two frames, it goes on from the second.
+            for sample in self
+                .frames
+                .drain(self.channels.min(self.frames.len())..)
+                .rev()
+            {
+                self.stretched.push_front(sample);
+            }
+            self.frames.clear();
+            self.phase = 0.;
+        }
+        self.stretch = stretch;
+        self.resample = resample;
+    }
+
+    /// Forget the buffered input, after a seek.
+    fn reset(&mut self) {
+        self.wsola.reset();
+        self.stretched.clear();
+        self.frames.clear();
+        self.phase = 0.;
+        self.output.clear();
+        self.ended = false;
+    }
+
+    /// Add stretched samples to `stretched`. Returns false once the input is over.
+    fn pull_stretched(&mut self) -> bool {
+        if self.stretch != 1. {
+            return self
+                .wsola
+                .step(&mut self.input, self.stretch, &mut self.stretched);
+        }
+        for _ in 0..self.channels {
+            match self.input.next() {
+                Some(sample) => self.stretched.push_back(sample),
+                None => return false,
+            }
+        }
+        true
+    }
+
+    /// Move the next stretched frame to `frames`.
+    fn pull_frame(&mut self) -> bool {
+        while self.stretched.len() < self.channels {
+            if !self.pull_stretched() {
+                return false;
+            }
+        }
+        self.frames.extend(self.stretched.drain(..self.channels));
+        true
+    }
+
+    /// Produce the next samples to play.
+    fn produce(&mut self) {
+        if self.resample == 1. {
+            if self.stretched.is_empty() && !self.pull_stretched() {
+                self.ended = true;
+            }
+            self.output.append(&mut self.stretched);
+            return;
+        }
+
+        // Linear interpolation, reading the stretched frames `resample` times as fast.
+        if self.frames.is_empty() {
+            self.phase = 0.;
+            if !(self.pull_frame() && self.pull_frame()) {
+                self.ended = true;
+                return;
+            }
+        }
+        while self.phase >= 1. {
+            self.frames.drain(..self.channels);
+            if !self.pull_frame() {
+                self.ended = true;
+                return;
+            }
+            self.phase -= 1.;
+        }
+
+        let phase = self.phase as f32;
+        for c in 0..self.channels {
+            let (a, b) = (self.frames[c], self.frames[self.channels + c]);
+            self.output.push_back(a + (b - a) * phase);
+        }
+        self.phase += self.resample;
+    }
+}
+
+impl<S: Source> Iterator for TimeStretch<S> {
+    type Item = Sample;
+
+    fn next(&mut self) -> Option<Sample> {
+        if self.countdown == 0 {
+            self.update();
+            self.countdown = UPDATE_FRAMES * self.channels;
+        }
+        self.countdown -= 1;
+
+        if self.output.is_empty() {
+            if self.stretch == 1. && self.resample == 1. && self.stretched.is_empty() {
+                return self.input.next();
+            }
+            if !self.ended {
+                self.produce();
+            }
+        }
+        self.output.pop_front()
+    }
+}
+
+impl<S: Source> Source for TimeStretch<S> {
+    fn current_span_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> ChannelCount {
+        self.input.channels()
+    }
+
+    fn sample_rate(&self) -> SampleRate {
+        self.input.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+
+    fn try_seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+        self.input.try_seek(pos)?;
+        self.reset();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RateControl, TimeStretch};
+    use rodio::buffer::SamplesBuffer;
+    use rodio::Sample;
+    use std::sync::Arc;
+
+    const RATE: u32 = 44100;
+
+    fn sine(seconds: usize) -> Vec<Sample> {
+        (0..RATE as usize * seconds)
+            .map(|i| (i as f32 * 440. * std::f32::consts::TAU / RATE as f32).sin() * 0.5)
+            .collect()
+    }
+
+    fn stretch(samples: Vec<Sample>, rate: f32, vinyl: bool) -> Vec<Sample> {
+        let control = Arc::new(RateControl::default());
+        control.set(rate, vinyl);
+        TimeStretch::new(SamplesBuffer::new(1, RATE, samples), control).collect()
+    }
+
+    #[test]
+    fn rate_one_passes_through() {
+        let input = sine(1);
+        assert_eq!(stretch(input.clone(), 1., false), input);
+    }
+
+    #[test]
+    fn length_follows_the_rate() {
+        let input = sine(2);
+        for (rate, vinyl) in [(2., false), (0.5, false), (1.5, true), (0.75, true)] {
+            let expected = input.len() as f32 / rate;
+            let length = stretch(input.clone(), rate, vinyl).len() as f32;
+            assert!(
+                (length / expected - 1.).abs() < 0.02,
+                "rate {} vinyl {}: {} samples instead of {}",
+                rate,
+                vinyl,
+                length,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn back_to_rate_one_keeps_the_buffered_input() {
+        // Each sample holds its index, the pass-through part is easy to spot.
+        let input: Vec<Sample> = (0..RATE * 2).map(|i| i as f32).collect();
+        let control = Arc::new(RateControl::default());
+        control.set_rate(2.);
+        let mut stretched = TimeStretch::new(SamplesBuffer::new(1, RATE, input), control.clone());
+        let mut output: Vec<Sample> = stretched.by_ref().take(10000).collect();
+        control.set_rate(1.);
+        output.extend(stretched);
+
+        assert_eq!(*output.last().unwrap(), (RATE * 2 - 1) as f32);
+        let mut start = output.len() - 1;
+        while start > 0 && output[start - 1] == output[start] - 1. {
+            start -= 1;
+        }
+        assert!(start > 10000);
+        // The last stretched sample is followed by the next input sample.
+        assert!((output[start] - 1. - output[start - 1]).abs() < 2.);
+    }
+}
@@ -0,0 +1,237 @@
+use crate::utils::song::player_error::PlayerError;
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
+use symphonia::core::errors::Error as SymphoniaError;
+use symphonia::core::formats::{FormatOptions, FormatReader};
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::MetadataOptions;
+use symphonia::core::probe::Hint;
+use symphonia::core::units::TimeBase;
+
+/// Packets read to measure the bitrate.
+const BITRATE_PACKETS: usize = 64;
+
+/// A track duration, exact or estimated from the bitrate.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ResolvedDuration {
+    pub duration: Duration,
+    pub exact: bool,
+}
+
+impl ResolvedDuration {
+    /// Neither known nor estimated.
+    pub const UNKNOWN: ResolvedDuration = ResolvedDuration {
+        duration: Duration::ZERO,
+        exact: false,
+    };
+
+    pub fn seconds(&self) -> f64 {
+        self.duration.as_secs_f64()
+    }
+}
+
+lazy_static! {
+    /// Durations of the files without one in their header, estimated or scanned.
+    static ref DURATIONS: Mutex<HashMap<String, ResolvedDuration>> = Mutex::new(HashMap::new());
+}
+
+/// The best duration known without reading the whole file: the one of the container,
+/// else the one found earlier, else an estimate from the bitrate of the first packets.
+pub fn resolve(path: &str, container: Option<Duration>) -> Result<ResolvedDuration, PlayerError> {
+    if let Some(duration) = container {
+        return Ok(ResolvedDuration {
+            duration,
+            exact: true,
+        });
+    }
+    if let Some(known) = DURATIONS.lock().unwrap().get(path) {
+        return Ok(*known);
+    }
+
+    let estimated = ResolvedDuration {
+        duration: estimate_from_bitrate(path)?,
+        exact: false,
+    };
+    DURATIONS
+        .lock()
+        .unwrap()
+        .insert(path.to_string(), estimated);
+    Ok(estimated)
+}
+
+/// Read every packet without decoding it and add their durations up.
+/// Fast enough for a background thread, even on long files.
+pub fn scan(path: &str) -> Result<ResolvedDuration, PlayerError> {
+    let (mut format, track_id, time_base) = open_format(path)?;
+    let mut ticks = 0;
+    loop {
+        match format.next_packet() {
+            Ok(packet) if packet.track_id() == track_id => ticks += packet.dur,
+            Ok(_) => {}
+            Err(SymphoniaError::IoError(e)) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(decode_error(path, e)),
+        }
+    }
+
+    let scanned = ResolvedDuration {
+        duration: to_duration(time_base, ticks),
+        exact: true,
+    };
+    DURATIONS.lock().unwrap().insert(path.to_string(), scanned);
+    Ok(scanned)
+}
+
+fn decode_error(path: &str, error: SymphoniaError) -> PlayerError {
+    match error {
+        SymphoniaError::IoError(e) => PlayerError::io(path, &e),
+        SymphoniaError::Unsupported(reason) => PlayerError::UnsupportedFormat {
+            path: path.to_string(),
+            reason: reason.to_string(),
+        },
+        e => PlayerError::DecodeFailed {
+            path: path.to_string(),
+            reason: e.to_string(),
+        },
+    }
+}
+
+/// Open the demuxer of `path` and pick its audio track.
+pub(crate) fn open_format(
+    path: &str,
+) -> Result<(Box<dyn FormatReader>, u32, TimeBase), PlayerError> {
+    let file = File::open(path).map_err(|e| PlayerError::io(path, &e))?;
+    let stream = MediaSourceStream::new(Box::new(file), Default::default());
+    let mut hint = Hint::new();
+    if let Some(extension) = Path::new(path).extension().and_then(|ext| ext.to_str()) {
+        hint.with_extension(extension);
+    }
+    let format = symphonia::default::get_probe()
+        .format(
+            &hint,
+            stream,
+            &FormatOptions {
+                enable_gapless: true,
+                ..Default::default()
+            },
+            &MetadataOptions::default(),
+        )
+        .map_err(|e| decode_error(path, e))?
+        .format;
+
+    let track = format
+        .default_track()
+        .ok_or_else(|| PlayerError::UnsupportedFormat {
+            path: path.to_string(),
+            reason: "No audio track".to_string(),
+        })?;
+    let time_base = track
+        .codec_params
+        .time_base
+        .or_else(|| {
+            track
+                .codec_params
+                .sample_rate
+                .map(|rate| TimeBase::new(1, rate))
+        })
+        .ok_or_else(|| PlayerError::DecodeFailed {
+            path: path.to_string(),
+            reason: "Unknown time base".to_string(),
+        })?;
+    let track_id = track.id;
+    Ok((format, track_id, time_base))
+}
+
+pub(crate) fn to_duration(time_base: TimeBase, ticks: u64) -> Duration {
+    let time = time_base.calc_time(ticks);
+    Duration::from_secs(time.seconds) + Duration::from_secs_f64(time.frac)
+}
+
+/// Size of the ID3v2 tag at the start of the file, large when it holds a cover.
+pub(crate) fn id3v2_size(file: &mut File) -> u64 {
+    let mut header = [0; 10];
+    if file.read_exact(&mut header).is_err() || &header[..3] != b"ID3" {
+        return 0;
+    }
+    // Syncsafe integer: 7 bits per byte.
+    let size = header[6..10]
+        .iter()
+        .fold(0u64, |size, &byte| (size << 7) | (byte & 0x7f) as u64);
+    let footer = if header[5] & 0x10 != 0 { 10 } else { 0 };
+    10 + size + footer
+}
+
+/// Size of the audio data divided by the bitrate of the first packets.
+fn estimate_from_bitrate(path: &str) -> Result<Duration, PlayerError> {
+    let (mut format, track_id, time_base) = open_format(path)?;
+    let (mut bytes, mut ticks) = (0u64, 0u64);
+    let mut packets = 0;
+    while packets < BITRATE_PACKETS {
+        match format.next_packet() {
+            Ok(packet) if packet.track_id() == track_id => {
+                bytes += packet.data.len() as u64;
+                ticks += packet.dur;
+                packets += 1;
+            }
+            Ok(_) => {}
+            Err(_) => break,
+        }
+    }
+    let seconds = to_duration(time_base, ticks).as_secs_f64();
+    if bytes == 0 || seconds == 0. {
+        return Err(PlayerError::DecodeFailed {
+            path: path.to_string(),
+            reason: "Unknown duration".to_string(),
+        });
+    }
+
+    let mut file = File::open(path).map_err(|e| PlayerError::io(path, &e))?;
+    let length = file
+        .metadata()
+        .map_err(|e| PlayerError::io(path, &e))?
+        .len();
+    let audio_bytes = length.saturating_sub(id3v2_size(&mut file));
+    Ok(Duration::from_secs_f64(
+        audio_bytes as f64 * seconds / bytes as f64,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{estimate_from_bitrate, id3v2_size, scan};
+    use crate::utils::song::test_audio;
+    use std::fs::File;
+    use std::io::Write;
+    use std::time::Duration;
+
+    #[test]
+    fn scan_and_estimate_agree() {
+        let path = test_audio::write_wav("youngl_duration_scan.wav", 8000, 1, 3);
+
+        let scanned = scan(&path).unwrap();
+        assert!(scanned.exact);
+        assert_eq!(scanned.duration, Duration::from_secs(3));
+        let estimated = estimate_from_bitrate(&path).unwrap();
+        assert!((estimated.as_secs_f64() - 3.).abs() < 0.1);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn id3v2_tag_is_skipped() {
+        let path = std::env::temp_dir().join("youngl_id3v2_size.mp3");
+        // 257 bytes of frames: 0x02 0x01 as a syncsafe integer.
+        let mut content = b"ID3\x04\x00\x00\x00\x00\x02\x01".to_vec();
+        content.extend([0; 257]);
+        content.extend(b"\xff\xfb");
+        File::create(&path).unwrap().write_all(&content).unwrap();
+
+        assert_eq!(id3v2_size(&mut File::open(&path).unwrap()), 10 + 257);
+        std::fs::write(&path, b"\xff\xfb\x90\x00").unwrap();
+        assert_eq!(id3v2_size(&mut File::open(&path).unwrap()), 0);
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -0,0 +1,137 @@
+/// Hands out track ids and tells which deck events are still relevant.
+///
+/// Every decoded source gets a new id, in increasing order. Loading a song replaces
+/// the deck, and the events of the older decks, still in flight, become stale.
+#[derive(Debug, Default)]
+pub struct TrackGenerations {
+    last_id: u64,
+    /// First track of the deck playing now.
+    latest_load: u64,
+    /// Track being played, as reported by the deck.
+    current: Option<u64>,
+    /// Track whose end was reported.
+    finished: Option<u64>,
+}
+
+impl TrackGenerations {
+    /// Id of a source about to be decoded.
+    pub fn next_id(&mut self) -> u64 {
+        self.last_id += 1;
+        self.last_id
+    }
+
+    /// `id` was loaded, on a new deck or crossfaded into the one playing:
+    /// whatever the older tracks send from now on is stale.
+    pub fn loaded(&mut self, id: u64) {
+        self.latest_load = self.latest_load.max(id);
+    }
+
+    pub fn is_stale(&self, id: u64) -> bool {
+        id < self.latest_load
+    }
+
+    /// Whether `id` is the track a deck was created with, rather than one following it.
+    pub fn is_load(&self, id: u64) -> bool {
+        id == self.latest_load
+    }
+
+    /// The deck started playing `id`. Return false if the event is stale.
+    pub fn started(&mut self, id: u64) -> bool {
+        if self.is_stale(id) || self.current.is_some_and(|current| current > id) {
+            return false;
+        }
+        self.current = Some(id);
+        true
+    }
+
+    /// Called with the state of the sink. Return the track to report as finished,
+    /// only once per track and never while a newly loaded one is about to start.
+    pub fn finished(&mut self, sink_empty: bool) -> Option<u64> {
+        let current = self.current?;
+        if !sink_empty || current < self.latest_load || self.finished == Some(current) {
+            return None;
+        }
+        self.finished = Some(current);
+        Some(current)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TrackGenerations;
+
+    /// Load a song, the way `play_file` does.
+    fn load(generations: &mut TrackGenerations) -> u64 {
+        let id = generations.next_id();
+        generations.loaded(id);
+        id
+    }
+
+    #[test]
+    fn ids_increase() {
+        let mut generations = TrackGenerations::default();
+        let ids: Vec<u64> = (0..5).map(|_| generations.next_id()).collect();
+        assert!(ids.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    fn quick_loads_ignore_older_decks() {
+        let mut generations = TrackGenerations::default();
+        let first = load(&mut generations);
+        let second = load(&mut generations);
+        let third = load(&mut generations);
+
+        // The events of the first two decks arrive late.
+        assert!(!generations.started(first));
+        assert!(!generations.started(second));
+        assert_eq!(generations.finished(true), None);
+
+        assert!(generations.started(third));
+        assert_eq!(generations.finished(true), Some(third));
+    }
+
+    #[test]
+    fn finished_is_reported_once() {
+        let mut generations = TrackGenerations::default();
+        let id = load(&mut generations);
+        assert!(generations.started(id));
+        assert_eq!(generations.finished(false), None);
+        assert_eq!(generations.finished(true), Some(id));
+        assert_eq!(generations.finished(true), None);
+        assert_eq!(generations.finished(true), None);
+    }
+
+    #[test]
+    fn no_finish_while_a_load_is_pending() {
+        let mut generations = TrackGenerations::default();
+        let first = load(&mut generations);
+        assert!(generations.started(first));
+
+        // The sink is cleared while the new deck has not started yet.
+        let second = load(&mut generations);
+        assert_eq!(generations.finished(true), None);
+        assert!(generations.started(second));
+        assert_eq!(generations.finished(false), None);
+        assert_eq!(generations.finished(true), Some(second));
+    }
+
+    #[test]
+    fn queued_tracks_follow_the_load() {
+        let mut generations = TrackGenerations::default();
+        let loaded = load(&mut generations);
+        let queued = generations.next_id();
+        assert!(generations.is_load(loaded));
+        assert!(!generations.is_load(queued));
+
+        assert!(generations.started(loaded));
+        assert!(generations.started(queued));
+        assert_eq!(generations.finished(true), Some(queued));
+
+        // A track queued on a deck that was replaced since.
+        let stale = generations.next_id();
+        let reloaded = load(&mut generations);
+        assert!(!generations.started(stale));
+        assert!(generations.started(reloaded));
+        assert!(!generations.started(loaded));
+    }
+}
@@ -1,6 +1,3 @@
-use base64::engine::general_purpose;
-use base64::Engine;
-use std::fs;
 use std::sync::Mutex;
 use std::time::Duration;
 use tauri::{AppHandle, Emitter, Manager, State, Url};
@@ -11,14 +8,14 @@ use souvlaki::{
     MediaControlEvent, MediaControls, MediaMetadata, MediaPlayback, MediaPosition, PlatformConfig,
 };
 
-use crate::utils::song::AudioState;
+use crate::utils::song::{
+    cover_file, play_next_in_queue, play_previous_in_queue, AudioState, PlayerError, QueueState,
+};
 
 /// Set the volume (这个是通用的，不用改)
 #[tauri::command]
-pub fn set_volume(volume: f32, state: State<'_, AudioState>) -> Result<(), String> {
-    let sink = state.sink.lock().unwrap();
-    sink.set_volume(volume / 100.);
-    Ok(())
+pub fn set_volume(volume: f32, state: State<'_, AudioState>) -> Result<(), PlayerError> {
+    state.engine.set_volume(volume / 100.)
 }
 
 // --- 状态结构体：在移动端内部存储 None ---
@@ -28,6 +25,13 @@ pub struct PlayerControls(pub Mutex<Option<MediaControls>>);
 #[cfg(any(target_os = "android", target_os = "ios"))]
 pub struct PlayerControls(pub Mutex<Option<String>>); // 移动端只是个占位符
 
+#[cfg(not(any(target_os = "android", target_os = "ios")))]
+fn media_error(error: impl std::fmt::Debug) -> PlayerError {
+    PlayerError::MediaControls {
+        reason: format!("{:?}", error),
+    }
+}
+
 #[tauri::command]
 pub async fn update_system_metadata(
     app: AppHandle,
@@ -36,7 +40,7 @@ pub async fn update_system_metadata(
     artist: String,
     album: String,
     cover: Option<String>,
-) -> Result<(), String> {
+) -> Result<(), PlayerError> {
     // 如果是 Android/iOS，直接返回 Ok，不做任何 souvlaki 操作
     #[cfg(any(target_os = "android", target_os = "ios"))]
     {
@@ -52,12 +56,14 @@ pub async fn update_system_metadata(
 
         // 1. 初始化
         if control_slot.is_none() {
-            let window = app
-                .get_webview_window("main")
-                .ok_or_else(|| "Main window not found".to_string())?;
+            let window =
+                app.get_webview_window("main")
+                    .ok_or_else(|| PlayerError::MediaControls {
+                        reason: "Main window not found".to_string(),
+                    })?;
 
             #[cfg(target_os = "windows")]
-            let hwnd = Some(window.hwnd().map_err(|e| e.to_string())?.0 as *mut std::ffi::c_void);
+            let hwnd = Some(window.hwnd().map_err(media_error)?.0 as *mut std::ffi::c_void);
             #[cfg(not(target_os = "windows"))]
             let hwnd = None;
 
@@ -67,39 +73,42 @@ pub async fn update_system_metadata(
                 hwnd,
             };
 
-            let mut c = MediaControls::new(config).map_err(|e| format!("{:?}", e))?;
+            let mut c = MediaControls::new(config).map_err(media_error)?;
             c.set_playback(MediaPlayback::Playing { progress: None })
                 .ok();
 
             let handle = app.clone();
             c.attach(move |event| {
+                // 队列由后端管理时，直接在 Rust 中切歌，不依赖前端
+                let queue_in_use = !handle.state::<QueueState>().0.lock().unwrap().is_empty();
                 let signal = match event {
                     MediaControlEvent::Toggle
                     | MediaControlEvent::Play
                     | MediaControlEvent::Pause => "Toggle",
+                    MediaControlEvent::Next if queue_in_use => {
+                        let _ = play_next_in_queue(&handle);
+                        return;
+                    }
+                    MediaControlEvent::Previous if queue_in_use => {
+                        let _ = play_previous_in_queue(&handle);
+                        return;
+                    }
                     MediaControlEvent::Next => "Next",
                     MediaControlEvent::Previous => "Previous",
                     _ => "Unknown",
                 };
                 let _ = handle.emit("system-media-event", signal);
             })
-            .map_err(|e| format!("{:?}", e))?;
+            .map_err(media_error)?;
 
             *control_slot = Some(c);
         }
 
-        // 2. 转换 Base64
-        let mut local_cover_url = None;
-        if let Some(base64_str) = cover {
-            let base64_data = base64_str.split(',').last().unwrap_or(&base64_str);
-            if let Ok(bytes) = general_purpose::STANDARD.decode(base64_data) {
-                let temp_path = std::env::temp_dir().join("yt_music_cover.jpg");
-                let _ = fs::remove_file(&temp_path);
-                if fs::write(&temp_path, bytes).is_ok() {
-                    local_cover_url = Some(temp_path.to_str().unwrap().to_string());
-                }
-            }
-        }
+        // 2. 封面：使用 cover:// 缓存在磁盘上的同一个文件
+        let local_cover_url = cover
+            .and_then(|url| cover_file(&url))
+            .and_then(|file| Url::from_file_path(file).ok())
+            .map(|url| url.to_string());
 
         // 3. 提交元数据
         if let Some(ref mut controls) = *control_slot {
@@ -111,7 +120,7 @@ pub async fn update_system_metadata(
                     cover_url: local_cover_url.as_deref(),
                     duration: None,
                 })
-                .map_err(|e| format!("{:?}", e))?;
+                .map_err(media_error)?;
         }
         Ok(())
     }
@@ -122,7 +131,16 @@ pub async fn update_system_status(
     control_state: State<'_, PlayerControls>,
     is_paused: bool,
     progress: f64,
-) -> Result<(), String> {
+) -> Result<(), PlayerError> {
+    set_system_status(&control_state, is_paused, progress)
+}
+
+/// Report the playback status to the system media controls, if they are set up.
+pub(crate) fn set_system_status(
+    control_state: &PlayerControls,
+    is_paused: bool,
+    progress: f64,
+) -> Result<(), PlayerError> {
     // Android 直接跳过
     #[cfg(any(target_os = "android", target_os = "ios"))]
     return Ok(());
@@ -143,9 +161,7 @@ pub async fn update_system_status(
                     progress: progress_pos,
                 }
             };
-            controls
-                .set_playback(status)
-                .map_err(|e| format!("{:?}", e))?;
+            controls.set_playback(status).map_err(media_error)?;
         }
         Ok(())
     }
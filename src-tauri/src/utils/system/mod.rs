@@ -1,2 +1,4 @@
 pub mod hardware_controller;
+pub mod sleep_inhibitor;
 pub use hardware_controller::*;
+pub use sleep_inhibitor::*;
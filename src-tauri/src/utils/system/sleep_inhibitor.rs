@@ -0,0 +1,407 @@
+use crate::utils::config::{read_config, write_config};
+use crate::utils::song::PlayerError;
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use tauri::{AppHandle, Manager, State};
+
+#[cfg(target_os = "linux")]
+use dbus::arg::OwnedFd;
+#[cfg(target_os = "linux")]
+use dbus::blocking::Connection;
+#[cfg(target_os = "linux")]
+use dbus::channel::{BusType, Channel};
+#[cfg(target_os = "linux")]
+use std::time::Duration;
+
+const SETTINGS_FILE: &str = "sleep_inhibit.json";
+
+#[cfg(target_os = "linux")]
+const APP_NAME: &str = "YoungL Music";
+#[cfg(target_os = "linux")]
+const REASON: &str = "Playing music";
+#[cfg(target_os = "linux")]
+const LOGIN1: &str = "org.freedesktop.login1";
+#[cfg(target_os = "linux")]
+const LOGIN1_MANAGER: &str = "org.freedesktop.login1.Manager";
+#[cfg(target_os = "linux")]
+const SCREEN_SAVER: &str = "org.freedesktop.ScreenSaver";
+/// How long the services have to answer.
+#[cfg(target_os = "linux")]
+const DBUS_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// What to keep from happening while music is playing, saved in `sleep_inhibit.json`.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct InhibitSettings {
+    /// Suspending, automatic or not.
+    pub prevent_suspend: bool,
+    /// Blanking the screen and locking the session when idle.
+    pub prevent_screen_blank: bool,
+}
+
+impl Default for InhibitSettings {
+    fn default() -> Self {
+        InhibitSettings {
+            prevent_suspend: true,
+            prevent_screen_blank: false,
+        }
+    }
+}
+
+fn inhibit_error(error: impl std::fmt::Display) -> PlayerError {
+    PlayerError::InhibitFailed {
+        reason: error.to_string(),
+    }
+}
+
+/// Where to reach a service. The tests run their own bus.
+#[cfg(target_os = "linux")]
+#[derive(Clone, Debug)]
+pub enum Bus {
+    System,
+    Session,
+    Address(String),
+}
+
+#[cfg(target_os = "linux")]
+fn connect(bus: &Bus) -> Result<Connection, PlayerError> {
+    let channel = match bus {
+        Bus::System => Channel::get_private(BusType::System),
+        Bus::Session => Channel::get_private(BusType::Session),
+        Bus::Address(address) => Channel::open_private(address).and_then(|mut channel| {
+            channel.register()?;
+            Ok(channel)
+        }),
+    };
+    Ok(Connection::from(channel.map_err(inhibit_error)?))
+}
+
+/// The locks of logind (suspend) and of the screen saver (screen blank).
+#[cfg(target_os = "linux")]
+struct Locks {
+    login1_bus: Bus,
+    screen_saver_bus: Bus,
+    /// logind releases the lock once this descriptor is closed.
+    suspend: Option<OwnedFd>,
+    /// The screen saver releases the lock on `UnInhibit`, or when the connection closes.
+    screen: Option<(Connection, u32)>,
+}
+
+#[cfg(target_os = "linux")]
+impl Default for Locks {
+    fn default() -> Self {
+        Locks {
+            login1_bus: Bus::System,
+            screen_saver_bus: Bus::Session,
+            suspend: None,
+            screen: None,
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Locks {
+    /// Take or release each lock. One failing does not keep the other from being taken.
+    fn update(&mut self, suspend: bool, screen: bool) -> Result<(), PlayerError> {
+        if !suspend {
+            self.suspend = None;
+        }
+        if let Some((connection, cookie)) = self.screen.take_if(|_| !screen) {
+            let _: Result<(), _> = connection
+                .with_proxy(SCREEN_SAVER, "/org/freedesktop/ScreenSaver", DBUS_TIMEOUT)
+                .method_call(SCREEN_SAVER, "UnInhibit", (cookie,));
+        }
+
+        let suspended = match self.suspend {
+            Some(_) => Ok(()),
+            None if suspend => self.inhibit_suspend(),
+            None => Ok(()),
+        };
+        let blanked = match self.screen {
+            Some(_) => Ok(()),
+            None if screen => self.inhibit_screen_blank(),
+            None => Ok(()),
+        };
+        suspended.and(blanked)
+    }
+
+    fn inhibit_suspend(&mut self) -> Result<(), PlayerError> {
+        let connection = connect(&self.login1_bus)?;
+        let (lock,): (OwnedFd,) = connection
+            .with_proxy(LOGIN1, "/org/freedesktop/login1", DBUS_TIMEOUT)
+            .method_call(
+                LOGIN1_MANAGER,
+                "Inhibit",
+                ("sleep:idle", APP_NAME, REASON, "block"),
+            )
+            .map_err(inhibit_error)?;
+        self.suspend = Some(lock);
+        Ok(())
+    }
+
+    fn inhibit_screen_blank(&mut self) -> Result<(), PlayerError> {
+        let connection = connect(&self.screen_saver_bus)?;
+        let (cookie,): (u32,) = connection
+            .with_proxy(SCREEN_SAVER, "/org/freedesktop/ScreenSaver", DBUS_TIMEOUT)
+            .method_call(SCREEN_SAVER, "Inhibit", (APP_NAME, REASON))
+            .map_err(inhibit_error)?;
+        self.screen = Some((connection, cookie));
+        Ok(())
+    }
+}
+
+/// Elsewhere, nothing to lock yet.
+#[cfg(not(target_os = "linux"))]
+#[derive(Default)]
+struct Locks;
+
+#[cfg(not(target_os = "linux"))]
+impl Locks {
+    fn update(&mut self, _suspend: bool, _screen: bool) -> Result<(), PlayerError> {
+        Ok(())
+    }
+}
+
+/// Keeps the system awake while music is playing, as set by the user.
+pub struct SleepInhibitor {
+    settings: InhibitSettings,
+    playing: bool,
+    locks: Locks,
+}
+
+/// `YOUNGL_INHIBIT_BUS=<address>` sends the requests of both services to that bus,
+/// e.g. one started with `dbus-daemon --session --print-address` to watch them.
+impl Default for SleepInhibitor {
+    fn default() -> Self {
+        #[cfg(target_os = "linux")]
+        if let Ok(address) = std::env::var("YOUNGL_INHIBIT_BUS") {
+            let bus = Bus::Address(address);
+            return SleepInhibitor::with_buses(bus.clone(), bus);
+        }
+        SleepInhibitor {
+            settings: InhibitSettings::default(),
+            playing: false,
+            locks: Locks::default(),
+        }
+    }
+}
+
+impl SleepInhibitor {
+    /// Reach logind and the screen saver on the given buses instead of the usual ones.
+    #[cfg(target_os = "linux")]
+    pub fn with_buses(login1_bus: Bus, screen_saver_bus: Bus) -> Self {
+        SleepInhibitor {
+            settings: InhibitSettings::default(),
+            playing: false,
+            locks: Locks {
+                login1_bus,
+                screen_saver_bus,
+                suspend: None,
+                screen: None,
+            },
+        }
+    }
+
+    /// Take the locks when the playback starts, release them when it stops.
+    pub fn set_playing(&mut self, playing: bool) -> Result<(), PlayerError> {
+        if playing == self.playing {
+            return Ok(());
+        }
+        self.playing = playing;
+        self.apply()
+    }
+
+    pub fn set_settings(&mut self, settings: InhibitSettings) -> Result<(), PlayerError> {
+        self.settings = settings;
+        self.apply()
+    }
+
+    fn apply(&mut self) -> Result<(), PlayerError> {
+        self.locks.update(
+            self.playing && self.settings.prevent_suspend,
+            self.playing && self.settings.prevent_screen_blank,
+        )
+    }
+}
+
+pub struct SleepInhibitState {
+    inhibitor: Arc<Mutex<SleepInhibitor>>,
+    /// Whether music is playing, for the worker making the D-Bus calls,
+    /// so the playback clock never waits for the services.
+    playing: Sender<bool>,
+}
+
+impl SleepInhibitState {
+    pub fn new(inhibitor: SleepInhibitor) -> Self {
+        let inhibitor = Arc::new(Mutex::new(inhibitor));
+        let (playing, changes) = mpsc::channel();
+        let worker = inhibitor.clone();
+        std::thread::spawn(move || {
+            while let Ok(mut playing) = changes.recv() {
+                // Only the latest state matters.
+                while let Ok(latest) = changes.try_recv() {
+                    playing = latest;
+                }
+                let result = worker.lock().unwrap().set_playing(playing);
+                if let Err(e) = result {
+                    if cfg!(debug_assertions) {
+                        println!("[DEBUG] {}", e);
+                    }
+                }
+            }
+        });
+        SleepInhibitState { inhibitor, playing }
+    }
+}
+
+/// Load the settings saved by `set_sleep_inhibit`.
+pub fn load_sleep_inhibit_settings(app: &AppHandle) {
+    match read_config::<InhibitSettings>(app, SETTINGS_FILE) {
+        Ok(settings) => {
+            let state = app.state::<SleepInhibitState>();
+            let _ = state.inhibitor.lock().unwrap().set_settings(settings);
+        }
+        Err(e) => {
+            if cfg!(debug_assertions) {
+                println!("[DEBUG] {}", e);
+            }
+        }
+    }
+}
+
+/// Called by the playback clock on every tick. The locks are taken on the worker.
+pub(crate) fn update_sleep_inhibitor(app: &AppHandle, playing: bool) {
+    if let Some(state) = app.try_state::<SleepInhibitState>() {
+        let _ = state.playing.send(playing);
+    }
+}
+
+#[tauri::command]
+pub fn fetch_sleep_inhibit(state: State<'_, SleepInhibitState>) -> InhibitSettings {
+    state.inhibitor.lock().unwrap().settings
+}
+
+/// Choose what to prevent while playing. Takes effect right away.
+#[tauri::command]
+pub fn set_sleep_inhibit(
+    settings: InhibitSettings,
+    app_handle: AppHandle,
+    state: State<'_, SleepInhibitState>,
+) -> Result<(), PlayerError> {
+    write_config(&app_handle, SETTINGS_FILE, &settings)?;
+    state.inhibitor.lock().unwrap().set_settings(settings)
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::{connect, Bus, InhibitSettings, SleepInhibitor, LOGIN1, SCREEN_SAVER};
+    use dbus::arg::OwnedFd;
+    use dbus::channel::MatchingReceiver;
+    use dbus::message::MatchRule;
+    use std::io::{BufRead, BufReader, PipeReader, Read};
+    use std::os::fd::{FromRawFd, IntoRawFd};
+    use std::process::{Command, Stdio};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{mpsc, Arc, Mutex};
+    use std::time::Duration;
+
+    /// Stands for logind and the screen saver. Hands out the write end of a pipe
+    /// as the suspend lock, and sends the read end to the test.
+    fn fake_services(
+        address: String,
+        calls: Arc<Mutex<Vec<String>>>,
+        stop: Arc<AtomicBool>,
+    ) -> mpsc::Receiver<PipeReader> {
+        let (ready_tx, ready_rx) = mpsc::channel();
+        let (pipe_tx, pipe_rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            let connection = connect(&Bus::Address(address)).unwrap();
+            connection
+                .request_name(LOGIN1, false, false, false)
+                .unwrap();
+            connection
+                .request_name(SCREEN_SAVER, false, false, false)
+                .unwrap();
+            connection.start_receive(
+                MatchRule::new_method_call(),
+                Box::new(move |message, connection| {
+                    let call = format!(
+                        "{}.{}",
+                        message.interface().unwrap(),
+                        message.member().unwrap()
+                    );
+                    let reply = match call.as_str() {
+                        "org.freedesktop.login1.Manager.Inhibit" => {
+                            let (reader, writer) = std::io::pipe().unwrap();
+                            let _ = pipe_tx.send(reader);
+                            // SAFETY: the descriptor was just taken from the writer.
+                            let lock = unsafe { OwnedFd::from_raw_fd(writer.into_raw_fd()) };
+                            message.method_return().append1(lock)
+                        }
+                        "org.freedesktop.ScreenSaver.Inhibit" => {
+                            message.method_return().append1(7u32)
+                        }
+                        _ => message.method_return(),
+                    };
+                    calls.lock().unwrap().push(call);
+                    let _ = dbus::channel::Sender::send(connection, reply);
+                    true
+                }),
+            );
+            let _ = ready_tx.send(());
+            while !stop.load(Ordering::Relaxed) {
+                connection.process(Duration::from_millis(20)).unwrap();
+            }
+        });
+        ready_rx.recv().unwrap();
+        pipe_rx
+    }
+
+    #[test]
+    #[ignore = "needs dbus-daemon"]
+    fn locks_follow_the_playback() {
+        let mut daemon = Command::new("dbus-daemon")
+            .args(["--session", "--nofork", "--print-address"])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("dbus-daemon not found");
+        let mut address = String::new();
+        BufReader::new(daemon.stdout.take().unwrap())
+            .read_line(&mut address)
+            .unwrap();
+        let address = address.trim().to_string();
+
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let stop = Arc::new(AtomicBool::new(false));
+        let pipes = fake_services(address.clone(), calls.clone(), stop.clone());
+        let bus = Bus::Address(address);
+        let mut inhibitor = SleepInhibitor::with_buses(bus.clone(), bus);
+        inhibitor
+            .set_settings(InhibitSettings {
+                prevent_suspend: true,
+                prevent_screen_blank: true,
+            })
+            .unwrap();
+        assert!(calls.lock().unwrap().is_empty());
+
+        inhibitor.set_playing(true).unwrap();
+        let mut lock = pipes.recv().unwrap();
+        inhibitor.set_playing(false).unwrap();
+        // Every write end is closed once the lock is released.
+        assert_eq!(lock.read(&mut [0]).unwrap(), 0);
+
+        assert_eq!(
+            *calls.lock().unwrap(),
+            [
+                "org.freedesktop.login1.Manager.Inhibit",
+                "org.freedesktop.ScreenSaver.Inhibit",
+                "org.freedesktop.ScreenSaver.UnInhibit",
+            ]
+        );
+
+        stop.store(true, Ordering::Relaxed);
+        let _ = daemon.kill();
+        let _ = daemon.wait();
+    }
+}
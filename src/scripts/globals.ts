@@ -1,5 +1,5 @@
 import {ref} from 'vue'
-import {PlaybackMode, SongMetadata} from "../types.ts";
+import {PlaybackMode, PlayerError, SongMetadata} from "../types.ts";
 
 // Constants
 export const callWait = 50; // ms
@@ -27,6 +27,8 @@ export const playlist = ref<string[]>([]); // The new global playlist
 export const currentIndex = ref(-1);
 
 export const openedDialog = ref(false);
+/// Why the playback goes nowhere, for want of an output device.
+export const deviceError = ref<PlayerError | null>(null);
 
 /// Data
 export const currentMetadata = ref<SongMetadata>({
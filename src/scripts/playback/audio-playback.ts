@@ -16,6 +16,7 @@ import {getValidLastFileFromHistory} from "../files/playback-history.ts";
 import {getNextValidAudio, getPrevValidAudio, removeMultipleFromPlaylist} from "../files/playlist.ts";
 import {syncPlaybackStatus, syncSystemMetadata} from "../utils/system-api.ts";
 import {fetchLyricsFromSong} from "./lyrics-handler.ts";
+import {isUnplayable} from "../../types.ts";
 
 /// Load the audio after choosing a file.
 export const loadAudio = async (path: unknown) => {
@@ -55,15 +56,18 @@ export const loadAudio = async (path: unknown) => {
             await emit("close-menu");
 
             return true;
-        } catch {
-            removeMultipleFromPlaylist([playlist.value.findIndex((item) => item === path)]);
+        } catch (e) {
+            console.error(e);
+            // Only drop the files that cannot be played, not the ones hit by an output failure.
+            if (isUnplayable(e))
+                removeMultipleFromPlaylist([playlist.value.findIndex((item) => item === path)]);
             return false;
         }
     }
 };
 
-/// Reset all the state to no-audio state
-export const resetStates = async () => {
+/// Reset all the state to no-audio state, then load the next track of the playlist unless `loadNext` is false.
+export const resetStates = async (loadNext = true) => {
     stopProgressCollection()
     noAudio.value = true;
     isPaused.value = true;
@@ -74,7 +78,8 @@ export const resetStates = async () => {
         cover: null as string | null,
         totalDuration: 0,
     };
-    await checkAudioAvailability('playlist', true);
+    if (loadNext)
+        await checkAudioAvailability('playlist', true);
     await syncSystemMetadata();
     await syncPlaybackStatus();
 }
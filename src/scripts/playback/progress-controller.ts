@@ -1,33 +1,29 @@
 import {invoke} from "@tauri-apps/api/core"
+import {listen, UnlistenFn} from "@tauri-apps/api/event";
 import {currentMetadata, currentTime} from "../globals.ts";
-import {syncPlaybackStatus} from "../utils/system-api.ts";
+import {PlaybackProgress, SeekResult} from "../../types.ts";
 
-export let progressController: number | null = null;
-
-export const updateProgress = async () => {
-    try {
-        currentTime.value = await invoke<number>('fetch_progress');
-        await syncPlaybackStatus();
-    } catch (e) {
-        console.log("Failed to fetch progress: ", e);
-    }
-}
+/// Whether the pushed positions move the current time; off while paused or dragging.
+let followingProgress = false;
+let progressListener: Promise<UnlistenFn> | null = null;
 
 export const stopProgressCollection = () => {
-    if (progressController) {
-        clearInterval(progressController);
-        progressController = null;
-    }
+    followingProgress = false;
 }
 
+/// Follow the `playback-progress` events of the backend, which also keeps the system controls up to date.
 export const startProgressCollection = () => {
-    stopProgressCollection();
-    progressController = setInterval(updateProgress, 250);
+    followingProgress = true;
+    progressListener ??= listen<PlaybackProgress>('playback-progress', (event) => {
+        if (followingProgress)
+            currentTime.value = event.payload.position;
+    });
 }
 
 export const setPosition = async (time: number) => {
     try {
-        await invoke('set_position', { time: time });
+        const result = await invoke<SeekResult>('set_position', { time: time });
+        currentTime.value = result.position;
     } catch (e) {
         console.log("Failed to set position: ", e);
     }
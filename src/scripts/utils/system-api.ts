@@ -1,6 +1,7 @@
 import { invoke } from "@tauri-apps/api/core";
 import {emit, listen} from "@tauri-apps/api/event";
-import {currentIndex, currentMetadata, currentTime, isPaused, playlist, playlistCloseTimer} from "../globals";
+import {currentIndex, currentMetadata, currentTime, deviceError, isPaused, playlist, playlistCloseTimer} from "../globals";
+import {PlayerError} from "../../types.ts";
 import {toggleAudioPlayback, skipEnd, skipStart, resetStates} from "../playback/audio-playback";
 import {addToPlayList} from "../files/playlist.ts";
 
@@ -46,6 +47,24 @@ export const initSystemListeners = async () => {
         const filePaths = event.payload;
         handleMusicFiles(filePaths);
     });
+    await listen<{ error: PlayerError }>('playback-device-unavailable', (event) => {
+        deviceError.value = event.payload.error;
+        console.error("No output device:", event.payload.error);
+    });
+    await listen<{ device: string | null }>('output-device-changed', (event) => {
+        if (event.payload.device !== null)
+            deviceError.value = null;
+    });
+    // Startup failures happened before anything listened.
+    try {
+        const error = await invoke<PlayerError | null>("fetch_device_error");
+        if (error) {
+            deviceError.value = error;
+            console.error("No output device:", error);
+        }
+    } catch (err) {
+        console.error("Failed to fetch the output device status:", err);
+    }
 }
 
 /**
@@ -11,7 +11,7 @@ export interface SongMetadata {
     title: string;
     artist: string;
     album?: string;
-    cover: string | null;
+    cover: string | null;   // cover:// URL, `?size=` for a thumbnail
     totalDuration: number;
     lyrics?: LyricLine[];
 }
@@ -20,4 +20,48 @@ export enum PlaybackMode {
     Shuffle = 0,
     RepeatAll = 1,
     RepeatOne = 2
-}
\ No newline at end of file
+}
+/** Error returned by the backend commands, see `PlayerError` in Rust. */
+export interface PlayerError {
+    code: 'FileNotFound' | 'PermissionDenied' | 'UnsupportedFormat' | 'DecodeFailed'
+        | 'SeekUnsupported' | 'NoOutputDevice' | 'TagWriteFailed' | 'InvalidArgument'
+        | 'NothingPlaying' | 'PresetNotFound' | 'ConfigFailed' | 'Io' | 'Busy'
+        | 'Cancelled' | 'AudioEngine' | 'MediaControls' | 'InhibitFailed';
+    path?: string;
+    device?: string | null;
+    reason?: string;
+}
+
+/** Returned by `set_position`: where the playback went, short of the end of the track. */
+export interface SeekResult {
+    position: number;
+    finished: boolean;
+}
+
+/** Pushed as `playback-progress` by the backend while a track is loaded. */
+export interface PlaybackProgress {
+    trackId: number;
+    position: number;
+    duration: number;
+    paused: boolean;
+}
+
+/** Technical details of a track, `stream` in the metadata of `load_song` and `fetch_metadata`. */
+export interface StreamInfo {
+    container: string | null;
+    codec: string | null;
+    sampleRate: number | null;
+    bitDepth: number | null;
+    channels: number | null;
+    channelLayout: string | null;
+    bitrate: number | null;   // Bits per second
+    bitrateMode: 'constant' | 'variable' | null;
+    lossless: boolean;
+    outputSampleRate: number | null;
+    resampled: boolean | null;
+}
+
+/** Whether the file itself cannot be played, rather than the player failing. */
+export const isUnplayable = (error: unknown) =>
+    ['FileNotFound', 'PermissionDenied', 'UnsupportedFormat', 'DecodeFailed']
+        .includes((error as PlayerError | undefined)?.code ?? '');